@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{install, CommandRunner, Invocation};
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+/// Role of a file in an exported kernel archive
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactKind {
+    Kernel,
+    Initramfs,
+    SystemMap,
+    Config,
+    Module,
+}
+
+/// File of the archive with its checksum, paths are relative to the archive root
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub kind: ArtifactKind,
+    pub sha256: String,
+}
+
+/// Describes the content of an exported kernel archive
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub version: String,
+    /// Date of the export as `YYYY-MM-DD`
+    pub date: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(dir.join(MANIFEST_FILE))?;
+        toml::from_str(&content).map_err(io::Error::other)
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let content = toml::to_string(self).map_err(io::Error::other)?;
+        std::fs::write(dir.join(MANIFEST_FILE), content)
+    }
+
+    /// Checks that the archive cannot write outside of the places it is installed to: the
+    /// version has to be a plain file name, every path relative without `..` and modules have
+    /// to be below `lib/modules/<version>`.
+    pub fn check_paths(&self) -> io::Result<()> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        if self.version.is_empty()
+            || self.version.contains('/')
+            || self.version.contains("..")
+            || self.version.starts_with('.')
+        {
+            return Err(invalid(format!("invalid version `{}`", self.version)));
+        }
+
+        let modules = Path::new("lib/modules").join(&self.version);
+        for file in &self.files {
+            let plain = file
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if !plain || (file.kind == ArtifactKind::Module && !file.path.starts_with(&modules)) {
+                return Err(invalid(format!("invalid path {}", file.path.display())));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Files whose checksum does not match the manifest, relative to the unpacked archive in `dir`
+    pub fn verify(&self, runner: &dyn CommandRunner, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let paths: Vec<PathBuf> = self.files.iter().map(|file| file.path.clone()).collect();
+        let sums = install::sha256_all(runner, dir, &paths)?;
+
+        Ok(self
+            .files
+            .iter()
+            .zip(sums)
+            .filter(|(file, sum)| file.sha256 != *sum)
+            .map(|(file, _)| file.path.clone())
+            .collect())
+    }
+}
+
+/// Regular files below `dir`, relative to `root`
+pub fn files_below(root: &Path, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            files.extend(files_below(root, &path)?);
+        } else if kind.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Copies a directory tree preserving symlinks and permissions
+pub fn copy_tree(runner: &dyn CommandRunner, src: &Path, dst: &Path) -> io::Result<()> {
+    runner
+        .run(&Invocation::new("cp").arg("-a").arg(src).arg(dst))
+        .map(drop)
+}
+
+/// Packs the content of `dir` into a zstd compressed tarball
+pub fn pack(runner: &dyn CommandRunner, dir: &Path, archive: &Path) -> io::Result<()> {
+    runner
+        .run(
+            &Invocation::new("tar")
+                .arg("--zstd")
+                .arg("-cf")
+                .arg(archive)
+                .arg("-C")
+                .arg(dir)
+                .arg("."),
+        )
+        .map(drop)
+}
+
+/// Unpacks a zstd compressed tarball into `dir`
+pub fn unpack(runner: &dyn CommandRunner, archive: &Path, dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    runner
+        .run(
+            &Invocation::new("tar")
+                .arg("--zstd")
+                .arg("-xf")
+                .arg(archive)
+                .arg("-C")
+                .arg(dir),
+        )
+        .map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(version: &str, path: &str, kind: ArtifactKind) -> Manifest {
+        Manifest {
+            version: version.to_string(),
+            date: "2024-01-01".to_string(),
+            files: vec![ManifestEntry {
+                path: PathBuf::from(path),
+                kind,
+                sha256: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn check_paths() {
+        let module = ArtifactKind::Module;
+        assert!(manifest("6.12.1", "lib/modules/6.12.1/kernel/a.ko", module)
+            .check_paths()
+            .is_ok());
+        assert!(
+            manifest("6.12.1", "boot/vmlinuz-6.12.1", ArtifactKind::Kernel)
+                .check_paths()
+                .is_ok()
+        );
+        assert!(manifest("../../etc", "boot/vmlinuz", ArtifactKind::Kernel)
+            .check_paths()
+            .is_err());
+        assert!(manifest("6.12.1", "/etc/shadow", ArtifactKind::Config)
+            .check_paths()
+            .is_err());
+        assert!(
+            manifest("6.12.1", "lib/modules/6.12.1/../../../etc/x", module)
+                .check_paths()
+                .is_err()
+        );
+        assert!(manifest("6.12.1", "etc/modprobe.d/evil.conf", module)
+            .check_paths()
+            .is_err());
+    }
+}
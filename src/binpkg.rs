@@ -0,0 +1,179 @@
+use crate::{CommandRunner, Invocation};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Portage binary package settings for `binpkg`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Binpkg {
+    #[serde(default = "default_category")]
+    pub category: String,
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// `PKGDIR` of Portage the package is written to
+    #[serde(default = "default_pkgdir")]
+    pub pkgdir: PathBuf,
+}
+
+impl Default for Binpkg {
+    fn default() -> Self {
+        Self {
+            category: default_category(),
+            name: default_name(),
+            pkgdir: default_pkgdir(),
+        }
+    }
+}
+
+fn default_category() -> String {
+    "sys-kernel".to_string()
+}
+
+fn default_name() -> String {
+    "kernel-builder-kernel".to_string()
+}
+
+fn default_pkgdir() -> PathBuf {
+    PathBuf::from("/var/cache/binpkgs")
+}
+
+/// Portage version of a kernel release, e.g. `6.12.8` for `6.12.8-gentoo` and `6.13_rc1` for
+/// `6.13.0-rc1`
+pub fn package_version(kver: &str) -> String {
+    let mut parts = kver.split('-');
+    let mut version = parts.next().unwrap_or(kver).to_string();
+    if let Some(rc) = parts.find(|part| part.starts_with("rc")) {
+        version = version.strip_suffix(".0").unwrap_or(&version).to_string();
+        version.push('_');
+        version.push_str(rc);
+    }
+
+    version
+}
+
+fn portage_var(runner: &dyn CommandRunner, name: &str, fallback: &str) -> String {
+    runner
+        .run(&Invocation::new("portageq").args(["envvar", name]))
+        .map(|value| value.trim().to_string())
+        .ok()
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+/// Ebuild environment of the package in `metadata`, its `pkg_postinst` hands the kernel to
+/// `installkernel` like the dist-kernel packages do.
+fn write_environment(runner: &dyn CommandRunner, metadata: &Path, kver: &str) -> io::Result<()> {
+    let dir = format!("/lib/modules/{kver}");
+    let path = metadata.join("environment");
+    std::fs::write(
+        &path,
+        format!("pkg_postinst() {{\n    installkernel {kver} {dir}/vmlinuz {dir}/System.map\n}}\n"),
+    )?;
+
+    // replaces the file with environment.bz2
+    runner
+        .run(&Invocation::new("bzip2").arg("--force").arg(&path))
+        .map(drop)
+}
+
+impl Binpkg {
+    /// Path of the package in `PKGDIR` using the `binpkg-multi-instance` layout
+    pub fn path(&self, kver: &str) -> PathBuf {
+        self.pkgdir
+            .join(&self.category)
+            .join(&self.name)
+            .join(format!(
+                "{}-{}-1.gpkg.tar",
+                self.name,
+                package_version(kver)
+            ))
+    }
+
+    /// Writes a GPKG from the `image` directory below `staging`, which holds the files as they
+    /// are installed to the root filesystem.
+    pub fn create(
+        &self,
+        runner: &dyn CommandRunner,
+        kver: &str,
+        staging: &Path,
+    ) -> io::Result<PathBuf> {
+        let pf = format!("{}-{}", self.name, package_version(kver));
+        let metadata = staging.join("metadata");
+        std::fs::create_dir_all(&metadata)?;
+        let build_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        let size = crate::install::dir_size(&staging.join("image"));
+        let chost = portage_var(runner, "CHOST", "x86_64-pc-linux-gnu");
+        for (name, value) in [
+            ("CATEGORY", self.category.as_str()),
+            ("PF", pf.as_str()),
+            ("SLOT", kver),
+            ("EAPI", "8"),
+            ("BUILD_ID", "1"),
+            ("BUILD_TIME", &build_time.to_string()),
+            ("SIZE", &size.to_string()),
+            ("CHOST", &chost),
+            ("CBUILD", &chost),
+            ("DEFINED_PHASES", "postinst"),
+            ("DESCRIPTION", "Kernel built with kernel-builder"),
+            ("LICENSE", "GPL-2"),
+            ("KEYWORDS", ""),
+            ("IUSE", ""),
+            ("USE", ""),
+            ("repository", "kernel-builder"),
+        ] {
+            std::fs::write(metadata.join(name), format!("{value}\n"))?;
+        }
+        write_environment(runner, &metadata, kver)?;
+
+        let package = staging.join("package");
+        let container = package.join(format!("{pf}-1"));
+        std::fs::create_dir_all(&container)?;
+        std::fs::write(container.join("gpkg-1"), "")?;
+        for part in ["metadata", "image"] {
+            runner.run(
+                &Invocation::new("tar")
+                    .args(["--zstd", "--numeric-owner", "-cf"])
+                    .arg(container.join(format!("{part}.tar.zst")))
+                    .arg("-C")
+                    .arg(staging)
+                    .arg(part),
+            )?;
+        }
+
+        let path = self.path(kver);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        runner.run(
+            &Invocation::new("tar")
+                .arg("-cf")
+                .arg(&path)
+                .arg("-C")
+                .arg(&package)
+                .arg(format!("{pf}-1")),
+        )?;
+
+        // let Portage pick up the new package in the Packages index
+        runner.run(&Invocation::new("emaint").args(["binhost", "--fix"]))?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_version_of_releases() {
+        assert_eq!(package_version("6.12.8-gentoo"), "6.12.8");
+        assert_eq!(package_version("6.12.8-gentoo-dist"), "6.12.8");
+        assert_eq!(package_version("6.13.0-rc1"), "6.13_rc1");
+        assert_eq!(package_version("6.13.0-rc3-gentoo"), "6.13_rc3");
+        assert_eq!(package_version("6.6.30"), "6.6.30");
+    }
+}
@@ -0,0 +1,1152 @@
+use crate::{
+    discover::VersionEntry, efi, install, mounts, snapshot, tmp, BuilderErr, Invocation,
+    KernelBuilder, UkiGenerator,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Boot loader that is updated after the kernel has been installed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bootloader {
+    /// Regenerate the GRUB configuration with `grub-mkconfig`
+    Grub,
+    /// Write Boot Loader Specification entries for systemd-boot
+    SystemdBoot,
+    /// Create an EFI boot entry per kernel with `efibootmgr`
+    Efibootmgr,
+    /// Write a `refind_linux.conf` next to the kernel image
+    Refind,
+}
+
+impl Bootloader {
+    /// Value of the `bootloader` config option
+    pub fn config_name(self) -> &'static str {
+        match self {
+            Self::Grub => "grub",
+            Self::SystemdBoot => "systemd-boot",
+            Self::Efibootmgr => "efibootmgr",
+            Self::Refind => "refind",
+        }
+    }
+}
+
+/// Which kernel GRUB boots after installation
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GrubDefault {
+    /// Leave the default entry alone
+    #[default]
+    Keep,
+    /// Make the new kernel the default with `grub-set-default`
+    Set,
+    /// Boot the new kernel only once with `grub-reboot`, a failed boot falls back to the old
+    /// default on the next reset
+    Once,
+}
+
+/// Default location of the generated GRUB configuration
+pub fn default_grub_config() -> PathBuf {
+    PathBuf::from("/boot/grub/grub.cfg")
+}
+
+/// Finds the id of the menu entry booting `kernel` in a generated GRUB configuration. Entries
+/// in a submenu are returned as `<submenu>><entry>` as expected by `grub-set-default`.
+pub fn grub_entry_id(grub_config: &Path, kernel: &Path) -> Option<String> {
+    let kernel_name = kernel.file_name()?;
+    let config = std::fs::read_to_string(grub_config).ok()?;
+    // `linux /vmlinuz-6.12.8-gentoo root=...`, the path is relative to the partition holding it
+    let boots_kernel = |line: &str| {
+        let mut tokens = line.split_whitespace();
+        tokens
+            .next()
+            .is_some_and(|command| command.starts_with("linux"))
+            && tokens
+                .next()
+                .is_some_and(|path| Path::new(path).file_name() == Some(kernel_name))
+    };
+    let id_of = |line: &str| {
+        let (_, id) = line.split_once("$menuentry_id_option")?;
+        let id = id.trim().trim_end_matches('{').trim();
+        Some(id.trim_matches(|c| c == '\'' || c == '"').to_string())
+    };
+
+    let mut submenu = None;
+    let mut entry = None;
+    for line in config.lines() {
+        let trimmed = line.trim_start();
+        if line.starts_with("submenu") {
+            submenu = id_of(line);
+        } else if line.starts_with("menuentry") {
+            submenu = None;
+            entry = id_of(line);
+        } else if trimmed.starts_with("menuentry") {
+            entry = id_of(trimmed);
+        } else if boots_kernel(trimmed) {
+            let entry = entry.clone()?;
+            return Some(match &submenu {
+                Some(submenu) => format!("{submenu}>{entry}"),
+                None => entry,
+            });
+        }
+    }
+
+    None
+}
+
+/// Default mount point of the ESP or XBOOTLDR partition holding the boot loader entries
+pub fn default_loader_root() -> PathBuf {
+    PathBuf::from("/boot")
+}
+
+/// Boot Loader Specification entry as read by systemd-boot
+#[derive(Debug, Clone)]
+pub struct LoaderEntry {
+    pub title: String,
+    pub version: String,
+    /// Kernel image relative to the loader root
+    pub linux: PathBuf,
+    /// Initramfs relative to the loader root
+    pub initrd: Option<PathBuf>,
+    pub options: Option<String>,
+}
+
+impl LoaderEntry {
+    fn render(&self) -> String {
+        let mut entry = format!(
+            "title {}\nversion {}\nlinux {}\n",
+            self.title,
+            self.version,
+            loader_path(&self.linux)
+        );
+        if let Some(initrd) = &self.initrd {
+            entry.push_str(&format!("initrd {}\n", loader_path(initrd)));
+        }
+        if let Some(options) = &self.options {
+            entry.push_str(&format!("options {options}\n"));
+        }
+        entry
+    }
+}
+
+/// Paths in loader entries are absolute to the root of the partition
+fn loader_path(path: &Path) -> String {
+    format!("/{}", path.to_string_lossy().trim_start_matches('/'))
+}
+
+/// Reads `/etc/machine-id`, which prefixes the names of the loader entries
+pub fn machine_id() -> Option<String> {
+    std::fs::read_to_string("/etc/machine-id")
+        .ok()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+}
+
+/// Name of the distribution for entry titles, taken from `/etc/os-release`
+pub fn os_name() -> String {
+    std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|release| {
+            release.lines().find_map(|line| {
+                line.strip_prefix("PRETTY_NAME=")
+                    .map(|name| name.trim_matches('"').to_string())
+            })
+        })
+        .unwrap_or_else(|| "Gentoo Linux".to_string())
+}
+
+fn entries_dir(loader_root: &Path) -> PathBuf {
+    loader_root.join("loader").join("entries")
+}
+
+/// Writes `loader/entries/<name>.conf` below the loader root, the name is made of the machine id
+/// and the kernel release by convention. With boot counting the entry is named
+/// `<name>+<tries>.conf`, so systemd-boot falls back to another entry once all tries failed.
+pub fn write_loader_entry(
+    loader_root: &Path,
+    name: &str,
+    entry: &LoaderEntry,
+    tries: Option<u32>,
+) -> std::io::Result<PathBuf> {
+    let dir = entries_dir(loader_root);
+    std::fs::create_dir_all(&dir)?;
+    remove_loader_entry(loader_root, name)?;
+    let path = match tries {
+        Some(tries) => dir.join(format!("{name}+{tries}.conf")),
+        None => dir.join(format!("{name}.conf")),
+    };
+    std::fs::write(&path, entry.render())?;
+    Ok(path)
+}
+
+/// Boot counting state systemd-boot encodes in the name of a loader entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCount {
+    /// No counter, the entry booted successfully or counting is not used
+    Good,
+    /// Boot attempts left before the entry is considered bad
+    Pending(u32),
+    /// All boot attempts failed
+    Bad,
+}
+
+/// Loader entries with the given name, with or without a boot counter like `+2-1`
+fn find_loader_entries(loader_root: &Path, name: &str) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(entries_dir(loader_root)) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|file| file.to_str())
+                .and_then(|file| file.strip_suffix(".conf"))
+                .is_some_and(|stem| {
+                    stem == name
+                        || stem
+                            .strip_prefix(name)
+                            .is_some_and(|counter| counter.starts_with('+'))
+                })
+        })
+        .collect()
+}
+
+/// Boot counting state of the loader entry of a kernel release
+pub fn boot_count(loader_root: &Path, name: &str) -> Option<BootCount> {
+    let entry = find_loader_entries(loader_root, name).into_iter().next()?;
+    let stem = entry.file_stem()?.to_string_lossy().to_string();
+    let Some((_, counter)) = stem.rsplit_once('+') else {
+        return Some(BootCount::Good);
+    };
+    let left: u32 = counter.split('-').next()?.parse().ok()?;
+
+    Some(if left == 0 {
+        BootCount::Bad
+    } else {
+        BootCount::Pending(left)
+    })
+}
+
+/// Strips the boot counter from the name of a loader entry like `systemd-bless-boot` does, so
+/// systemd-boot considers it good. Returns whether an entry had a counter.
+pub fn bless_loader_entry(loader_root: &Path, name: &str) -> std::io::Result<bool> {
+    let mut blessed = false;
+    for entry in find_loader_entries(loader_root, name) {
+        let counted = entry
+            .file_stem()
+            .is_some_and(|stem| stem.to_string_lossy() != name);
+        if counted {
+            std::fs::rename(&entry, entry.with_file_name(format!("{name}.conf")))?;
+            blessed = true;
+        }
+    }
+
+    Ok(blessed)
+}
+
+/// Removes the loader entries with the given name including their boot counter variants
+pub fn remove_loader_entry(loader_root: &Path, name: &str) -> std::io::Result<Vec<PathBuf>> {
+    let entries = find_loader_entries(loader_root, name);
+    for entry in &entries {
+        std::fs::remove_file(entry)?;
+    }
+
+    Ok(entries)
+}
+
+/// Removes the loader entries of this machine whose kernel image does not exist anymore.
+pub fn remove_stale_loader_entries(
+    loader_root: &Path,
+    machine_id: &str,
+) -> std::io::Result<Vec<PathBuf>> {
+    let dir = entries_dir(loader_root);
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let prefix = format!("{machine_id}-");
+    let mut removed = vec![];
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !name.starts_with(&prefix) || !name.ends_with(".conf") {
+            continue;
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let linux = content.lines().find_map(|line| {
+            line.strip_prefix("linux")
+                .filter(|rest| rest.starts_with(char::is_whitespace))
+                .map(|rest| rest.trim().trim_start_matches('/'))
+        });
+        if let Some(linux) = linux {
+            if !loader_root.join(linux).exists() {
+                std::fs::remove_file(&path)?;
+                removed.push(path);
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Additional boot option line offered by rEFInd
+#[derive(Debug, Deserialize, Clone)]
+pub struct RefindVariant {
+    pub title: String,
+    /// Parameters appended to the kernel command line
+    pub options: String,
+}
+
+/// Writes `refind_linux.conf` into the directory of the kernel image. rEFInd uses it for all
+/// kernels in that directory and finds the matching initramfs on its own.
+pub fn write_refind_conf(
+    kernel: &Path,
+    cmdline: Option<&str>,
+    variants: &[RefindVariant],
+) -> std::io::Result<PathBuf> {
+    let dir = kernel.parent().unwrap_or(Path::new("/"));
+    let path = dir.join("refind_linux.conf");
+    let cmdline = cmdline.unwrap_or_default();
+    let with = |options: &str| format!("{cmdline} {options}").trim().to_string();
+
+    let mut conf = format!("\"Boot with standard options\" \"{cmdline}\"\n");
+    conf.push_str(&format!(
+        "\"Boot to single-user mode\" \"{}\"\n",
+        with("single")
+    ));
+    for variant in variants {
+        conf.push_str(&format!(
+            "\"{}\" \"{}\"\n",
+            variant.title,
+            with(&variant.options)
+        ));
+    }
+
+    std::fs::write(&path, conf)?;
+    Ok(path)
+}
+
+/// Boot artifacts a boot loader is set up for, either the main install or a destination
+struct BootTarget<'a> {
+    kernel: PathBuf,
+    initramfs: Option<PathBuf>,
+    uki: Option<PathBuf>,
+    loader_root: PathBuf,
+    grub_config: &'a Path,
+    efi_label: String,
+}
+
+impl KernelBuilder {
+    /// Command line of the entry of `kver`, booting its boot environment if there is one.
+    fn entry_cmdline(&self, kver: &str) -> Result<Option<String>, BuilderErr> {
+        let cmdline = self.kernel_cmdline()?;
+        let Some(dataset) = self
+            .boot_environment(kver)
+            .filter(|dataset| snapshot::zfs_exists(self, dataset))
+        else {
+            return Ok(cmdline);
+        };
+
+        let mut params: Vec<String> = cmdline
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter(|param| !param.starts_with("root="))
+            .map(String::from)
+            .collect();
+        params.push(format!("root=ZFS={dataset}"));
+
+        Ok(Some(params.join(" ")))
+    }
+
+    /// Updates the configured boot loader and the boot loaders of destinations so they pick up
+    /// the installed kernel.
+    pub(crate) fn update_bootloaders(&self, kver: &str) -> Result<(), BuilderErr> {
+        if let Some(bootloader) = self.config.bootloader {
+            let target = self.main_target(kver);
+            self.update_bootloader(bootloader, kver, &target, self.config.keep_old)?;
+        }
+
+        for (bootloader, target) in self.destination_targets(kver)? {
+            self.update_bootloader(bootloader, kver, &target, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the entries of `kver` from the configured boot loader and the boot loaders of
+    /// destinations, after its artifacts were removed.
+    pub(crate) fn remove_bootloader_entries(&self, kver: &str) -> Result<(), BuilderErr> {
+        if let Some(bootloader) = self.config.bootloader {
+            self.remove_bootloader_entry(bootloader, kver, &self.main_target(kver))
+                .map_err(BuilderErr::BootloaderError)?;
+        }
+
+        for (bootloader, target) in self.destination_targets(kver)? {
+            self.remove_bootloader_entry(bootloader, kver, &target)
+                .map_err(BuilderErr::BootloaderError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Artifacts of `kver` booted by the configured boot loader
+    fn main_target(&self, kver: &str) -> BootTarget<'_> {
+        BootTarget {
+            kernel: self.kernel_path(kver),
+            initramfs: self.initramfs_path(kver).filter(|_| !self.initramfs_less()),
+            uki: self.uki_path(kver),
+            loader_root: self.config.loader_root.clone(),
+            grub_config: &self.config.grub_config,
+            efi_label: self.config.efi_label.clone(),
+        }
+    }
+
+    /// Boot loaders of the destinations with the artifacts of `kver` they boot
+    fn destination_targets(
+        &self,
+        kver: &str,
+    ) -> Result<Vec<(Bootloader, BootTarget<'_>)>, BuilderErr> {
+        let mut targets = vec![];
+        for (index, dest) in self.config.destinations.iter().enumerate() {
+            let Some(bootloader) = dest.bootloader else {
+                continue;
+            };
+            let kernel = self.render_path(&dest.kernel, kver);
+            let loader_root = match &dest.loader_root {
+                Some(loader_root) => loader_root.clone(),
+                None if bootloader == Bootloader::SystemdBoot => mounts::find(&kernel)
+                    .map(|mount| mount.mountpoint)
+                    .filter(|mountpoint| mountpoint != Path::new("/"))
+                    .unwrap_or_else(|| self.config.loader_root.clone()),
+                None => self.config.loader_root.clone(),
+            };
+            // entries are named after the machine and release only, the one of the main install
+            // would be overwritten
+            if bootloader == Bootloader::SystemdBoot
+                && self.config.bootloader == Some(Bootloader::SystemdBoot)
+                && loader_root == self.config.loader_root
+            {
+                return Err(BuilderErr::BootloaderError(format!(
+                    "destination {} uses systemd-boot and needs its own `loader-root`",
+                    index + 1
+                )));
+            }
+            let target = BootTarget {
+                kernel,
+                initramfs: dest
+                    .initramfs
+                    .as_ref()
+                    .map(|path| self.render_path(path, kver))
+                    .filter(|_| !self.initramfs_less()),
+                uki: None,
+                loader_root,
+                grub_config: dest
+                    .grub_config
+                    .as_ref()
+                    .unwrap_or(&self.config.grub_config),
+                efi_label: dest
+                    .efi_label
+                    .clone()
+                    .unwrap_or_else(|| format!("{}-{}", self.config.efi_label, index + 1)),
+            };
+            targets.push((bootloader, target));
+        }
+
+        Ok(targets)
+    }
+
+    /// Sets up one boot loader for the boot artifacts of `target`, with a fallback entry for the
+    /// previous kernel if `fallback` is set.
+    fn update_bootloader(
+        &self,
+        bootloader: Bootloader,
+        kver: &str,
+        target: &BootTarget,
+        fallback: bool,
+    ) -> Result<(), BuilderErr> {
+        self.progress.on_step_start("Updating boot loader");
+        let result = match bootloader {
+            Bootloader::Grub => {
+                self.progress.on_progress("Regenerating GRUB configuration");
+                // grub-set-default only manages the grubenv of the main installation
+                self.update_grub(target.grub_config, &target.kernel)
+                    .and_then(|()| {
+                        if target.grub_config == self.config.grub_config {
+                            self.set_grub_default(&target.kernel)
+                        } else {
+                            Ok(())
+                        }
+                    })
+            }
+            Bootloader::SystemdBoot => {
+                self.progress
+                    .on_progress("Writing systemd-boot loader entry");
+                self.write_loader_entry(kver, target)
+            }
+            Bootloader::Efibootmgr => {
+                self.progress.on_progress("Updating EFI boot entries");
+                self.update_efi_entry(kver, target)
+            }
+            Bootloader::Refind => {
+                self.progress.on_progress("Writing rEFInd boot options");
+                self.kernel_cmdline()
+                    .map_err(|e| e.to_string())
+                    .and_then(|cmdline| {
+                        write_refind_conf(
+                            &target.kernel,
+                            cmdline.as_deref(),
+                            &self.config.refind_variants,
+                        )
+                        .map_err(|e| format!("could not write refind_linux.conf: {e}"))
+                    })
+                    .map(|path| {
+                        self.progress
+                            .on_output_line(&format!("Wrote {}", path.display()))
+                    })
+            }
+        };
+
+        let result = result.and_then(|()| {
+            if fallback {
+                self.ensure_fallback_entry(bootloader, kver)
+            } else {
+                Ok(())
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                self.progress.on_step_end(true, "Updated boot loader");
+                Ok(())
+            }
+            Err(e) => {
+                self.progress
+                    .on_step_end(false, "Failed updating boot loader");
+                Err(BuilderErr::BootloaderError(e))
+            }
+        }
+    }
+
+    /// Makes sure the boot loader offers the previous kernel kept by `keep-old` as fallback, so a
+    /// broken upgrade is only one menu entry away from a working system.
+    fn ensure_fallback_entry(&self, bootloader: Bootloader, kver: &str) -> Result<(), String> {
+        let kernel_file_path = self.kernel_path(kver);
+        let old_kernel = self.old_path(&kernel_file_path);
+        let old_initramfs = self
+            .initramfs_path(kver)
+            .filter(|_| !self.initramfs_less())
+            .map(|initramfs| self.old_path(&initramfs))
+            .filter(|initramfs| initramfs.exists());
+
+        match bootloader {
+            // grub-mkconfig picks up `.old` kernels on its own, check that it did
+            Bootloader::Grub => {
+                if old_kernel.exists()
+                    && grub_entry_id(&self.config.grub_config, &old_kernel).is_none()
+                {
+                    self.warn(format!(
+                        "{} has no entry for the previous kernel {}",
+                        self.config.grub_config.display(),
+                        old_kernel.display()
+                    ));
+                }
+            }
+            Bootloader::SystemdBoot => {
+                if !old_kernel.exists() {
+                    return Ok(());
+                }
+                let root = &self.config.loader_root;
+                let relative = |path: &Path| {
+                    path.strip_prefix(root)
+                        .map(Path::to_path_buf)
+                        .map_err(|_| format!("{} is not below {}", path.display(), root.display()))
+                };
+                let machine_id =
+                    machine_id().ok_or_else(|| "could not read /etc/machine-id".to_string())?;
+                let entry = LoaderEntry {
+                    title: format!("{} (previous kernel)", os_name()),
+                    version: install::image_version(&old_kernel)
+                        .unwrap_or_else(|| "previous".to_string()),
+                    linux: relative(&old_kernel)?,
+                    initrd: old_initramfs.as_deref().map(relative).transpose()?,
+                    options: self.kernel_cmdline().map_err(|e| e.to_string())?,
+                };
+                let path =
+                    write_loader_entry(root, &format!("{machine_id}-previous"), &entry, None)
+                        .map_err(|e| format!("could not write loader entry: {e}"))?;
+                println!("Wrote fallback loader entry {}", path.display());
+            }
+            Bootloader::Efibootmgr => {
+                let (image, initramfs) = self.efi_boot_files(kver);
+                let old_image = self.old_path(&image);
+                if !old_image.exists() {
+                    return Ok(());
+                }
+                let location = efi::EspLocation::locate(&old_image).ok_or_else(|| {
+                    format!(
+                        "could not determine the partition of {}",
+                        old_image.display()
+                    )
+                })?;
+                let label = format!("{} previous", self.config.efi_label);
+                let mut present = false;
+                for entry in efi::entries(self).map_err(|e| e.to_string())? {
+                    if entry.label != label || !location.contains(&entry) {
+                        continue;
+                    }
+                    if !present
+                        && entry
+                            .loader
+                            .as_ref()
+                            .is_some_and(|loader| loader.eq_ignore_ascii_case(&location.loader))
+                    {
+                        present = true;
+                    } else {
+                        efi::delete_entry(self, &entry.number).map_err(|e| e.to_string())?;
+                    }
+                }
+                if !present {
+                    let old_initramfs = initramfs
+                        .map(|initramfs| self.old_path(&initramfs))
+                        .filter(|initramfs| initramfs.exists());
+                    self.create_efi_entry(&label, &old_image, old_initramfs.as_deref(), false)?;
+                }
+            }
+            // rEFInd lists every kernel it finds, including the `.old` one
+            Bootloader::Refind => {}
+        }
+
+        Ok(())
+    }
+
+    /// Regenerates the GRUB configuration and returns the log of `grub-mkconfig`
+    fn grub_mkconfig(&self, grub_config: &Path) -> Result<String, String> {
+        let output = self
+            .run_output(&Invocation::new("grub-mkconfig").arg("-o").arg(grub_config))
+            .map_err(|e| format!("could not run grub-mkconfig: {e}"))?;
+
+        // grub-mkconfig reports found images on stderr
+        if !output.success {
+            return Err(format!("grub-mkconfig failed: {}", output.stderr.trim()));
+        }
+
+        Ok(output.stderr)
+    }
+
+    /// Runs `grub-mkconfig` and checks that the installed kernel image is referenced in the
+    /// generated configuration.
+    fn update_grub(&self, grub_config: &Path, kernel: &Path) -> Result<(), String> {
+        let log = self.grub_mkconfig(grub_config)?;
+
+        let kernel_name = kernel
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let generated = std::fs::read_to_string(grub_config)
+            .map_err(|e| format!("could not read {}: {e}", grub_config.display()))?;
+        if !generated.contains(&kernel_name) {
+            return Err(format!(
+                "{kernel_name} is missing in {}, grub-mkconfig output:\n{}",
+                grub_config.display(),
+                log.trim()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Selects the GRUB entry booted next with `grub-set-default` or `grub-reboot`. Both need
+    /// `GRUB_DEFAULT=saved` in `/etc/default/grub`.
+    fn select_grub_entry(&self, mode: GrubDefault, entry: &str) -> Result<(), String> {
+        let tool = match mode {
+            GrubDefault::Keep => return Ok(()),
+            GrubDefault::Set => "grub-set-default",
+            GrubDefault::Once => "grub-reboot",
+        };
+
+        let saved = std::fs::read_to_string("/etc/default/grub").is_ok_and(|defaults| {
+            defaults
+                .lines()
+                .any(|line| line.trim().trim_matches('"') == "GRUB_DEFAULT=saved")
+        });
+        if !saved {
+            self.warn(format!(
+                "GRUB_DEFAULT=saved is not set in /etc/default/grub, {tool} has no effect"
+            ));
+        }
+
+        let output = self
+            .run_output(&Invocation::new(tool).arg(entry))
+            .map_err(|e| format!("could not run {tool}: {e}"))?;
+        if !output.success {
+            return Err(format!("{tool} failed: {}", output.stderr.trim()));
+        }
+
+        Ok(())
+    }
+
+    /// Makes GRUB boot the installed kernel next, once or permanently depending on the config.
+    fn set_grub_default(&self, kernel_file_path: &Path) -> Result<(), String> {
+        let mode = self.config.grub_default;
+        if mode == GrubDefault::Keep {
+            return Ok(());
+        }
+
+        let entry = grub_entry_id(&self.config.grub_config, kernel_file_path).ok_or_else(|| {
+            format!(
+                "no menu entry for {} in {}",
+                kernel_file_path.display(),
+                self.config.grub_config.display()
+            )
+        })?;
+        self.select_grub_entry(mode, &entry)?;
+        println!("GRUB boots `{entry}` next");
+
+        Ok(())
+    }
+
+    /// Removes the boot loader entry of a removed kernel, so the boot menu matches what is on disk.
+    /// rEFInd scans for kernels itself and needs no cleanup.
+    fn remove_bootloader_entry(
+        &self,
+        bootloader: Bootloader,
+        kver: &str,
+        target: &BootTarget,
+    ) -> Result<(), String> {
+        match bootloader {
+            Bootloader::Grub => {
+                self.grub_mkconfig(target.grub_config)?;
+                println!("Regenerated {}", target.grub_config.display());
+            }
+            Bootloader::SystemdBoot => {
+                let root = &target.loader_root;
+                let machine_id =
+                    machine_id().ok_or_else(|| "could not read /etc/machine-id".to_string())?;
+                let removed = remove_loader_entry(root, &format!("{machine_id}-{kver}"))
+                    .map_err(|e| format!("could not remove loader entry: {e}"))?
+                    .into_iter()
+                    .chain(
+                        remove_stale_loader_entries(root, &machine_id)
+                            .map_err(|e| format!("could not clean up loader entries: {e}"))?,
+                    );
+                for entry in removed {
+                    println!("Removed loader entry {}", entry.display());
+                }
+            }
+            Bootloader::Efibootmgr => {
+                let label = format!("{} {kver}", target.efi_label);
+                for entry in efi::entries(self).map_err(|e| e.to_string())? {
+                    if entry.label == label {
+                        efi::delete_entry(self, &entry.number).map_err(|e| e.to_string())?;
+                        println!("Removed EFI boot entry `{label}`");
+                    }
+                }
+            }
+            Bootloader::Refind => {}
+        }
+
+        Ok(())
+    }
+
+    /// Writes the systemd-boot entry of the installed kernel and drops entries whose kernel image
+    /// is gone.
+    fn write_loader_entry(&self, kver: &str, target: &BootTarget) -> Result<(), String> {
+        let root = &target.loader_root;
+        let relative = |path: &Path| {
+            path.strip_prefix(root).map(Path::to_path_buf).map_err(|_| {
+                format!(
+                    "{} is not below the loader root {}",
+                    path.display(),
+                    root.display()
+                )
+            })
+        };
+
+        let machine_id =
+            machine_id().ok_or_else(|| "could not read /etc/machine-id".to_string())?;
+        let initrd = target.initramfs.as_deref().map(relative).transpose()?;
+        let entry = LoaderEntry {
+            title: os_name(),
+            version: kver.to_string(),
+            linux: relative(&target.kernel)?,
+            initrd,
+            options: self.entry_cmdline(kver).map_err(|e| e.to_string())?,
+        };
+
+        let path = write_loader_entry(
+            root,
+            &format!("{machine_id}-{kver}"),
+            &entry,
+            self.config.boot_counting,
+        )
+        .map_err(|e| format!("could not write loader entry: {e}"))?;
+        println!("Wrote loader entry {}", path.display());
+        for stale in remove_stale_loader_entries(root, &machine_id)
+            .map_err(|e| format!("could not clean up loader entries: {e}"))?
+        {
+            println!("Removed stale loader entry {}", stale.display());
+        }
+
+        Ok(())
+    }
+
+    /// Creates the EFI boot entry `<efi-label> <kver>` for the installed image. Entries of this
+    /// label on the same ESP that point to the same image, a replaced kernel, or to an image which
+    /// does not exist anymore are removed.
+    fn update_efi_entry(&self, kver: &str, target: &BootTarget) -> Result<(), String> {
+        let (image, initramfs) = match &target.uki {
+            Some(uki) => (uki.clone(), None),
+            None => (target.kernel.clone(), target.initramfs.clone()),
+        };
+        let location = efi::EspLocation::locate(&image)
+            .ok_or_else(|| format!("could not determine the partition of {}", image.display()))?;
+        let esp = mounts::find(&image).map(|mount| mount.mountpoint);
+
+        let prefix = format!("{} ", target.efi_label);
+        let label = format!("{prefix}{kver}");
+        for entry in efi::entries(self).map_err(|e| e.to_string())? {
+            // entries of the same label on another ESP, e.g. of another installation, stay
+            if !entry.label.starts_with(&prefix) || !location.contains(&entry) {
+                continue;
+            }
+            let Some(loader) = &entry.loader else {
+                continue;
+            };
+            let missing = esp.as_ref().is_some_and(|esp| {
+                !esp.join(loader.trim_start_matches('\\').replace('\\', "/"))
+                    .exists()
+            });
+            if entry.label == label || loader.eq_ignore_ascii_case(&location.loader) || missing {
+                efi::delete_entry(self, &entry.number).map_err(|e| e.to_string())?;
+                println!("Removed stale EFI boot entry `{}`", entry.label);
+            }
+        }
+
+        self.create_efi_entry(
+            &label,
+            &image,
+            initramfs.as_deref(),
+            self.config.efi_boot_first,
+        )
+    }
+
+    /// Image booted by an EFI entry for a kernel release and the initramfs the EFI stub has to
+    /// load. A unified kernel image carries its own initramfs and command line.
+    pub(crate) fn efi_boot_files(&self, kver: &str) -> (PathBuf, Option<PathBuf>) {
+        match self.uki_path(kver) {
+            Some(uki) => (uki, None),
+            None => (
+                self.kernel_path(kver),
+                self.initramfs_path(kver).filter(|_| !self.initramfs_less()),
+            ),
+        }
+    }
+
+    /// Creates an EFI boot entry and puts it first or last in the boot order
+    fn create_efi_entry(
+        &self,
+        label: &str,
+        image: &Path,
+        initramfs: Option<&Path>,
+        first: bool,
+    ) -> Result<(), String> {
+        let location = efi::EspLocation::locate(image)
+            .ok_or_else(|| format!("could not determine the partition of {}", image.display()))?;
+
+        let cmdline = if self.config.uki_file_path.is_some() {
+            None
+        } else {
+            self.kernel_cmdline().map_err(|e| e.to_string())?
+        };
+        // the EFI stub loads the initramfs itself from the same partition
+        let initrd = initramfs
+            .and_then(efi::EspLocation::locate)
+            .map(|initramfs| format!("initrd={}", initramfs.loader));
+        let cmdline = match (cmdline, initrd) {
+            (Some(cmdline), Some(initrd)) => Some(format!("{initrd} {cmdline}")),
+            (cmdline, initrd) => cmdline.or(initrd),
+        };
+
+        let previous: Vec<String> = efi::entries(self)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|entry| entry.number)
+            .collect();
+        let mut order = efi::boot_order(self).map_err(|e| e.to_string())?;
+        efi::create_entry(self, &location, label, cmdline.as_deref()).map_err(|e| e.to_string())?;
+        let created = efi::entries(self)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .find(|entry| !previous.contains(&entry.number))
+            .map(|entry| entry.number);
+        println!("Created EFI boot entry `{label}` for {}", location.loader);
+
+        if let Some(number) = created {
+            order.retain(|n| n != &number);
+            if first {
+                order.insert(0, number);
+            } else {
+                order.push(number);
+            }
+            efi::set_boot_order(self, &order).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates an EFI boot entry for the installed kernel if there is none with the configured
+    /// label yet.
+    pub(crate) fn ensure_efi_stub_entry(&self, kver: &str) -> Result<(), BuilderErr> {
+        let label = &self.config.efi_label;
+        let entries = efi::entries(self).map_err(|e| BuilderErr::EfiStubError(e.to_string()))?;
+        if entries.iter().any(|entry| &entry.label == label) {
+            return Ok(());
+        }
+
+        let kernel_file_path = self.kernel_path(kver);
+        let location = efi::EspLocation::locate(&kernel_file_path).ok_or_else(|| {
+            BuilderErr::EfiStubError(format!(
+                "could not determine the partition of {}",
+                kernel_file_path.display()
+            ))
+        })?;
+        efi::create_entry(self, &location, label, None)
+            .map_err(|e| BuilderErr::EfiStubError(e.to_string()))?;
+        println!("Created EFI boot entry `{label}` for {}", location.loader);
+
+        Ok(())
+    }
+
+    pub(crate) fn generate_uki(
+        &self,
+        VersionEntry {
+            path,
+            version_string,
+        }: &VersionEntry,
+    ) -> Result<(), BuilderErr> {
+        let kver = self.kernel_release(path, version_string);
+        let kver = kver.as_str();
+        let uki_file_path = &self
+            .uki_path(kver)
+            .ok_or(BuilderErr::KernelConfigMissingOption("uki".into()))?;
+        let kernel_file_path = self.kernel_path(kver);
+        let cmdline = self.kernel_cmdline()?;
+        let staging = tmp::TempDir::new("uki").map_err(BuilderErr::UkiError)?;
+        let staged = staging.join(format!("uki-{kver}.efi"));
+
+        let cmd = match self.config.uki_generator {
+            #[cfg(feature = "dracut")]
+            UkiGenerator::Dracut => {
+                let mut cmd = Invocation::new("dracut")
+                    .args(["--uefi", "--hostonly", "--force", "--kver", kver])
+                    .arg("--kernel-image")
+                    .arg(&kernel_file_path);
+                if let Some(splash) = &self.config.uki_splash {
+                    cmd = cmd.arg("--uefi-splash-image").arg(splash);
+                }
+                if let Some(cmdline) = &cmdline {
+                    cmd = cmd.args(["--kernel-cmdline", cmdline]);
+                }
+                cmd.arg(&staged)
+            }
+            UkiGenerator::Ukify => {
+                let mut cmd = Invocation::new("ukify")
+                    .arg("build")
+                    .arg(format!("--uname={kver}"))
+                    .arg("--linux")
+                    .arg(&kernel_file_path);
+                if let Some(initramfs) =
+                    self.initramfs_path(kver).filter(|_| !self.initramfs_less())
+                {
+                    cmd = cmd.arg("--initrd").arg(initramfs);
+                }
+                if let Some(splash) = &self.config.uki_splash {
+                    cmd = cmd.arg("--splash").arg(splash);
+                }
+                if let Some(cmdline) = &cmdline {
+                    cmd = cmd.arg(format!("--cmdline={cmdline}"));
+                }
+                cmd.arg("--output").arg(&staged)
+            }
+        }
+        .current_dir(path);
+
+        let previous_size = std::fs::metadata(uki_file_path).map(|meta| meta.len()).ok();
+
+        self.backup_old(uki_file_path)?;
+
+        self.progress
+            .on_step_start("Generating unified kernel image");
+        let output = self.run_output(&cmd).map_err(BuilderErr::UkiError)?;
+
+        if !output.success {
+            self.progress
+                .on_step_end(false, "Failed generating unified kernel image");
+            return Err(BuilderErr::UkiError(std::io::Error::other(format!(
+                "generator failed: {}",
+                output.stderr.trim()
+            ))));
+        }
+        if self.signing_enabled() {
+            let unsigned = staged.with_extension("unsigned");
+            let signed = std::fs::rename(&staged, &unsigned)
+                .map_err(|e| BuilderErr::SigningError(e.to_string()))
+                .and_then(|()| self.sign(&unsigned, &staged));
+            if let Err(e) = signed {
+                self.progress
+                    .on_step_end(false, "Failed signing unified kernel image");
+                return Err(e);
+            }
+        }
+        install::atomic_copy(self, &staged, uki_file_path).map_err(BuilderErr::UkiError)?;
+        if self.signing_enabled() {
+            self.verify_signature(uki_file_path)?;
+        }
+        self.progress
+            .on_step_end(true, "Finished unified kernel image");
+        Self::report_size("Unified kernel image", uki_file_path, previous_size)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KBConfig, RecordingRunner};
+
+    #[test]
+    fn update_grub_runs_grub_mkconfig() {
+        let dir = tmp::TempDir::new("grub-test").unwrap();
+        let recorder = RecordingRunner::default();
+        let builder = KernelBuilder::builder(KBConfig::for_test(dir.path()))
+            .runner(Box::new(recorder.clone()))
+            .build()
+            .unwrap();
+        let grub_config = dir.join("grub.cfg");
+        std::fs::write(
+            &grub_config,
+            "linux /vmlinuz-6.12.8-gentoo root=/dev/sda2\n",
+        )
+        .unwrap();
+
+        builder
+            .update_grub(&grub_config, Path::new("/boot/vmlinuz-6.12.8-gentoo"))
+            .unwrap();
+        assert_eq!(
+            recorder.invocations(),
+            [Invocation::new("grub-mkconfig").arg("-o").arg(&grub_config)]
+        );
+
+        // the kernel is missing from the generated config
+        assert!(builder
+            .update_grub(&grub_config, Path::new("/boot/vmlinuz-6.13.0"))
+            .is_err());
+    }
+
+    #[test]
+    fn grub_entry_id_matches_the_exact_image() {
+        let dir = tmp::TempDir::new("grub-test").unwrap();
+        let grub_config = dir.join("grub.cfg");
+        std::fs::write(
+            &grub_config,
+            "menuentry 'Gentoo' $menuentry_id_option 'gnulinux-simple-abc' {\n\
+             \tlinux /vmlinuz-6.12.80-gentoo root=/dev/sda2\n\
+             }\n\
+             submenu 'Advanced' $menuentry_id_option 'gnulinux-advanced-abc' {\n\
+             \tmenuentry 'Gentoo 6.12.80' $menuentry_id_option 'gnulinux-6.12.80-gentoo-advanced-abc' {\n\
+             \t\tlinux /vmlinuz-6.12.80-gentoo root=/dev/sda2\n\
+             \t}\n\
+             \tmenuentry 'Gentoo 6.12.8' $menuentry_id_option 'gnulinux-6.12.8-gentoo-advanced-abc' {\n\
+             \t\tlinux /vmlinuz-6.12.8-gentoo root=/dev/sda2\n\
+             \t}\n\
+             }\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            grub_entry_id(&grub_config, Path::new("/boot/vmlinuz-6.12.8-gentoo")).as_deref(),
+            Some("gnulinux-advanced-abc>gnulinux-6.12.8-gentoo-advanced-abc")
+        );
+        assert_eq!(
+            grub_entry_id(&grub_config, Path::new("/boot/vmlinuz-6.12.80-gentoo")).as_deref(),
+            Some("gnulinux-simple-abc")
+        );
+        assert_eq!(
+            grub_entry_id(&grub_config, Path::new("/boot/vmlinuz-6.12")),
+            None
+        );
+    }
+
+    #[test]
+    fn blessing_strips_the_boot_counter() {
+        let dir = tmp::TempDir::new("loader-test").unwrap();
+        let entries = entries_dir(dir.path());
+        std::fs::create_dir_all(&entries).unwrap();
+        std::fs::write(entries.join("abc-6.12.8-gentoo+2-1.conf"), "").unwrap();
+        std::fs::write(entries.join("abc-6.12.80-gentoo+3.conf"), "").unwrap();
+
+        assert_eq!(
+            boot_count(dir.path(), "abc-6.12.8-gentoo"),
+            Some(BootCount::Pending(2))
+        );
+        assert!(bless_loader_entry(dir.path(), "abc-6.12.8-gentoo").unwrap());
+        assert!(entries.join("abc-6.12.8-gentoo.conf").exists());
+        assert_eq!(
+            boot_count(dir.path(), "abc-6.12.8-gentoo"),
+            Some(BootCount::Good)
+        );
+        assert!(!bless_loader_entry(dir.path(), "abc-6.12.8-gentoo").unwrap());
+        assert!(entries.join("abc-6.12.80-gentoo+3.conf").exists());
+    }
+
+    #[test]
+    fn removal_regenerates_the_grub_configs_of_destinations() {
+        let dir = tmp::TempDir::new("grub-test").unwrap();
+        let recorder = RecordingRunner::default();
+        let mut config = KBConfig::for_test(dir.path());
+        config.bootloader = Some(Bootloader::Grub);
+        config.grub_config = dir.join("grub.cfg");
+        config.destinations = vec![
+            crate::Destination {
+                kernel: dir.join("backup/vmlinuz-{version}"),
+                initramfs: None,
+                bootloader: Some(Bootloader::Grub),
+                loader_root: None,
+                grub_config: Some(dir.join("backup/grub.cfg")),
+                efi_label: None,
+            },
+            crate::Destination {
+                kernel: dir.join("plain/vmlinuz-{version}"),
+                initramfs: None,
+                bootloader: None,
+                loader_root: None,
+                grub_config: None,
+                efi_label: None,
+            },
+        ];
+        let builder = KernelBuilder::builder(config)
+            .runner(Box::new(recorder.clone()))
+            .build()
+            .unwrap();
+
+        builder.remove_bootloader_entries("6.12.8-gentoo").unwrap();
+        assert_eq!(
+            recorder.invocations(),
+            [
+                Invocation::new("grub-mkconfig")
+                    .arg("-o")
+                    .arg(dir.join("grub.cfg")),
+                Invocation::new("grub-mkconfig")
+                    .arg("-o")
+                    .arg(dir.join("backup/grub.cfg")),
+            ]
+        );
+    }
+}
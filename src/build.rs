@@ -0,0 +1,1300 @@
+use crate::{
+    compat, discover::VersionEntry, eselect, external, git, hooks, install, kconfig, modules,
+    patches, pipeline, portage, reboot, releases, rootfs, signing, snapshot, state, template, tmp,
+    version, Args, BuildOptions, BuildReport, BuilderErr, Invocation, KernelBuilder, KernelVersion,
+    PortageHook,
+};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::os::unix;
+use std::path::{Path, PathBuf};
+
+impl KernelBuilder {
+    const ZFS_KMOD: &'static str = "sys-fs/zfs-kmod";
+
+    /// Checksum of the `.config` the objects in a source tree were built with
+    pub(crate) const CONFIG_HASH_FILE: &'static str = ".kernel-builder-config.sha256";
+
+    /// Checks that a tree looks like kernel sources before anything is done with it, so a
+    /// leftover directory or broken tree gives a precise error instead of a failing make.
+    fn validate_source_tree(path: &Path) -> Result<(), BuilderErr> {
+        use std::os::unix::fs::MetadataExt;
+
+        let invalid = |reason: String| {
+            Err(BuilderErr::InvalidSourceTree(format!(
+                "{}: {reason}",
+                path.display()
+            )))
+        };
+        let Ok(mut entries) = std::fs::read_dir(path) else {
+            return invalid("cannot be read".into());
+        };
+        if entries.next().is_none() {
+            return invalid("is empty, probably left over from uninstalled sources".into());
+        }
+
+        let Ok(makefile) = std::fs::read_to_string(path.join("Makefile")) else {
+            return invalid("has no top-level Makefile".into());
+        };
+        let variable = |name: &str| {
+            makefile.lines().find_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                (key.trim() == name)
+                    .then(|| value.trim().parse::<u32>().ok())
+                    .flatten()
+            })
+        };
+        if variable("VERSION").is_none() || variable("PATCHLEVEL").is_none() {
+            return invalid("Makefile has no parsable VERSION and PATCHLEVEL".into());
+        }
+
+        // root can build anywhere, other users need write access to the tree
+        let uid = std::fs::metadata("/proc/self").map_or(0, |meta| meta.uid());
+        if let Ok(meta) = path.metadata() {
+            if uid != 0 && meta.uid() != uid && meta.mode() & 0o002 == 0 {
+                return invalid(format!(
+                    "owned by uid {} and not writable for uid {uid}",
+                    meta.uid()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asks for a source tree and builds it. Returns the report of the build, `None` if nothing
+    /// was built.
+    ///
+    /// # Errors
+    ///
+    /// - Error on missing kernel config
+    /// - Failing creating symlinks
+    /// - Failing kernel build
+    ///
+    /// if selected:
+    /// - Failing installing kernel modules
+    /// - Failing generating initramfs
+    pub fn build(&self, cli: &Args) -> Result<Option<BuildReport>, BuilderErr> {
+        self.start_run();
+        let mut state = self.load_state()?;
+        let new_sources = self.new_sources(&state);
+        let releases = self.releases();
+        let selected = match &cli.source {
+            Some(version) => self.select_source(version)?,
+            None => self.prompt_for_kernel_version(&new_sources, &state, releases.as_ref()),
+        };
+        let options = BuildOptions::from(cli);
+        // remember the trees of this run, so only later additions are highlighted
+        if !options.dry_run {
+            state.known_sources = Some(
+                self.discover_versions()
+                    .into_iter()
+                    .map(|entry| entry.version_string)
+                    .collect(),
+            );
+            self.save_state(&state)?;
+        }
+        let Some(mut version_entry) = selected else {
+            return Ok(None);
+        };
+        // a dry run plans the build of the current checkout
+        if !options.dry_run && git::is_git_tree(&version_entry.path) {
+            let Some(checked_out) = self.checkout_git_ref(version_entry, cli.git_ref.as_deref())?
+            else {
+                return Ok(None);
+            };
+            version_entry = checked_out;
+        }
+        if !self.confirm_eol(releases.as_ref(), &version_entry, &options)? {
+            return Ok(None);
+        }
+        if !self.confirm_downgrade(&version_entry)? {
+            return Ok(None);
+        }
+        if cli.changelog && !self.show_changelog(&state, &version_entry)? {
+            return Ok(None);
+        }
+
+        self.build_tree(&options, &version_entry)
+    }
+
+    /// Warns when the series of the tree reached its end of life, returns whether to build it
+    /// anyway
+    fn confirm_eol(
+        &self,
+        releases: Option<&releases::Releases>,
+        version_entry: &VersionEntry,
+        options: &BuildOptions,
+    ) -> Result<bool, BuilderErr> {
+        if !releases.is_some_and(|releases| releases.is_eol(&version_entry.version_string)) {
+            return Ok(true);
+        }
+
+        self.warn(format!(
+            "the series of {} reached its end of life and gets no more fixes",
+            version_entry.version_string
+        ));
+        self.confirm_or("Build it anyway?", options.build_eol)
+    }
+
+    /// Builds and installs the newest source tree without asking, unless its kernel is installed
+    /// already. Meant for unattended runs, e.g. triggered by the Portage hook. Returns the report
+    /// of the build, `None` if nothing was built.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to read the state database
+    /// - Any error of the build and install
+    pub fn auto(&self, cli: &Args) -> Result<Option<BuildReport>, BuilderErr> {
+        self.start_run();
+        let pin = self.config.pin_version.as_deref();
+        let track = self
+            .config
+            .track
+            .as_deref()
+            .map(|track| track.trim_end_matches(".*"));
+        let Some(version_entry) = self.available_versions().iter().find(|entry| {
+            !git::is_git_tree(&entry.path)
+                && pin.is_none_or(|pin| version::matches(&entry.version_string, pin))
+                && track.is_none_or(|track| version::matches(&entry.version_string, track))
+        }) else {
+            match (pin, track) {
+                (Some(pin), _) => println!("No kernel sources of pinned version {pin} found"),
+                (None, Some(track)) => {
+                    println!("No kernel sources of tracked series {track} found")
+                }
+                (None, None) => println!("No kernel sources found"),
+            }
+            return Ok(None);
+        };
+
+        let kver = self.kernel_release(&version_entry.path, &version_entry.version_string);
+        if self
+            .load_state()?
+            .installs
+            .iter()
+            .any(|install| install.version == kver)
+        {
+            println!("Newest kernel {kver} is already installed");
+            return Ok(None);
+        }
+
+        println!("Building {}", version_entry.version_string);
+        self.build_tree(&BuildOptions::from(cli), version_entry)
+    }
+
+    /// Called from the Portage `post_pkg_postinst` hook, builds new kernel sources unattended.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start the background build
+    /// - Any error of the build when launched directly
+    pub fn portage_hook(&self, cli: &Args) -> Result<(), BuilderErr> {
+        let package = portage::emerged_sources();
+        if portage::emerged_by_builder() || package.is_none() && !portage::emerged_module_package()
+        {
+            return Ok(());
+        }
+
+        let Some(package) = package else {
+            return self.schedule_module_initramfs();
+        };
+
+        match self.config.portage_hook {
+            PortageHook::Schedule => {
+                let scheduled = portage::schedule(
+                    self,
+                    "kernel-builder-auto",
+                    &["auto", "--yes"],
+                    "/var/log/kernel-builder-auto.log",
+                )
+                .map_err(BuilderErr::KernelBuildFail)?;
+                if scheduled {
+                    println!("Scheduled kernel-builder auto build for {package}");
+                } else {
+                    println!("kernel-builder auto build is already scheduled");
+                }
+                Ok(())
+            }
+            PortageHook::Launch => {
+                println!("Building kernel for {package}");
+                self.auto(cli).map(|_| ())
+            }
+        }
+    }
+
+    /// Source tree versions available for [`Self::build_version`], newest first. Git trees are
+    /// left out as their `git describe` names are not kernel versions.
+    #[must_use]
+    pub fn versions(&self) -> Vec<KernelVersion> {
+        self.available_versions()
+            .iter()
+            .filter_map(|entry| KernelVersion::parse(&entry.version_string))
+            .collect()
+    }
+
+    /// Builds and installs the kernel of a source tree without asking anything, for tools
+    /// driving builds from code. Questions about the steps are answered with yes, the ones
+    /// whose safe answer is no like building an end-of-life series take the answer from
+    /// `options`. Reboots only happen if requested in `options`. A dry run returns the report
+    /// of the steps that would run.
+    ///
+    /// # Errors
+    ///
+    /// - No source tree of the version
+    /// - [`BuilderErr::Cancelled`] when the answer of `options` stopped the build
+    /// - Any error of the build and install
+    pub fn build_version(
+        &self,
+        version: &KernelVersion,
+        options: &BuildOptions,
+    ) -> Result<BuildReport, BuilderErr> {
+        let Some(version_entry) = self
+            .available_versions()
+            .iter()
+            .find(|entry| KernelVersion::parse(&entry.version_string).as_ref() == Some(version))
+        else {
+            return Err(BuilderErr::InvalidSourceTree(format!(
+                "no source tree for {version}"
+            )));
+        };
+
+        self.start_run();
+        let assume_yes = self.assume_yes.replace(true);
+        let report = self
+            .confirm_eol(self.releases().as_ref(), version_entry, options)
+            .and_then(|build| {
+                if build {
+                    self.build_tree(options, version_entry)
+                } else {
+                    Ok(None)
+                }
+            });
+        self.assume_yes.set(assume_yes);
+
+        report?.ok_or(BuilderErr::Cancelled)
+    }
+
+    /// Builds and installs the kernel of a selected source tree. `None` if the build was
+    /// cancelled, a dry run reports the steps that would run.
+    fn build_tree(
+        &self,
+        options: &BuildOptions,
+        version_entry: &VersionEntry,
+    ) -> Result<Option<BuildReport>, BuilderErr> {
+        let mut report = BuildReport {
+            version: version_entry.version_string.clone(),
+            flavor: self.flavor.clone(),
+            dry_run: options.dry_run,
+            ..BuildReport::default()
+        };
+        let result = self.run_build(options, version_entry, &mut report);
+        report.steps = self.timings.take();
+        report.warnings = self.warnings.take();
+        match result {
+            Ok(false) => Ok(None),
+            Ok(true) => {
+                self.last_report.replace(Some(report.clone()));
+                Ok(Some(report))
+            }
+            Err(err) => {
+                report.error = Some(err.to_string());
+                self.last_report.replace(Some(report));
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs the build of `build_tree`, filling in `report` as far as it gets. Returns false if
+    /// the build was cancelled.
+    fn run_build(
+        &self,
+        options: &BuildOptions,
+        version_entry: &VersionEntry,
+        report: &mut BuildReport,
+    ) -> Result<bool, BuilderErr> {
+        let VersionEntry {
+            path,
+            version_string,
+        } = &version_entry;
+        Self::validate_source_tree(path)?;
+        // refuse before building rather than after installing
+        if options.kexec_reboot && !reboot::systemd_running() && !reboot::openrc_running() {
+            return Err(BuilderErr::KexecError(
+                "kexec reboot needs systemd or OpenRC to shut the system down".into(),
+            ));
+        }
+        // a dry run leaves the tree and the boot partitions alone
+        if !options.dry_run && !self.prepare_tree(options, version_entry)? {
+            return Ok(false);
+        }
+
+        let kver = self.kernel_release(path, version_string);
+        report.kver.clone_from(&kver);
+        let _mounts = if options.dry_run {
+            None
+        } else {
+            Some(self.mount_boot_partitions(&kver)?)
+        };
+        self.check_layout(&kver);
+        let mut run = pipeline::Run::new(options, version_entry, kver);
+        let result = self.run_steps(&pipeline::steps(), &mut run);
+        report.artifacts = std::mem::take(&mut run.artifacts);
+        report.modules = std::mem::take(&mut run.rebuilt);
+        result?;
+        if !options.dry_run {
+            self.print_summary(&run.kver, &report.modules);
+        }
+
+        if !options.dry_run && !options.kexec_reboot && !options.no_build {
+            self.offer_reboot(&run.kver, options.reboot, options.reboot_at.as_deref())?;
+        }
+
+        Ok(true)
+    }
+
+    /// Forgets the steps, warnings and report of an earlier build before starting a new one, and
+    /// fixes the date of the new one so artifact paths do not change at midnight
+    fn start_run(&self) {
+        self.timings.take();
+        self.warnings.take();
+        self.last_report.take();
+        self.run_date.replace(Some(template::today()));
+    }
+
+    /// Report of the most recent build, including a failed one with the steps up to the failure
+    /// and its error. `None` before the first build and after a cancelled one.
+    #[must_use]
+    pub fn last_report(&self) -> Option<BuildReport> {
+        self.last_report.borrow().clone()
+    }
+
+    /// Gets the tree ready for building: patches, the `.config` of the selected flavor, the
+    /// `/usr/src/linux` symlink and the optional menuconfig. Returns false if the build was
+    /// cancelled.
+    fn prepare_tree(
+        &self,
+        options: &BuildOptions,
+        version_entry: &VersionEntry,
+    ) -> Result<bool, BuilderErr> {
+        let VersionEntry {
+            path,
+            version_string,
+        } = &version_entry;
+        if !self.check_module_compat(version_string, options)? {
+            return Ok(false);
+        }
+        self.apply_patches(path)?;
+
+        // create symlink from /usr/src/.config, pointing it to the config of the selected flavor
+        let link = path.join(".config");
+        let dot_config = self
+            .selected_flavor()
+            .and_then(|flavor| flavor.kernel_config.as_ref())
+            .unwrap_or(&self.config.kernel_config_file_path);
+        if !link.exists()
+            || (link.is_symlink() && link.read_link().ok().as_ref() != Some(dot_config))
+        {
+            if !dot_config.exists() || !dot_config.is_file() {
+                return Err(BuilderErr::KernelConfigMissing);
+            }
+
+            if link.is_symlink() {
+                std::fs::remove_file(&link).map_err(BuilderErr::LinkingFileError)?;
+            }
+            unix::fs::symlink(dot_config, link).map_err(BuilderErr::LinkingFileError)?;
+        }
+
+        self.update_src_symlink(version_entry)?;
+
+        if self.config.efi_stub {
+            self.prepare_efi_stub(path)?;
+        }
+
+        if options.menuconfig {
+            self.make_menuconfig(path)?;
+            if !self.confirm_prompt("Continue build process?")? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Points `/usr/src/linux` to the selected tree, through `eselect kernel` if configured so
+    /// the rest of the Gentoo tooling agrees on the selected kernel. A missing symlink is created,
+    /// and with `manage-src-symlink = false` it is never touched.
+    fn update_src_symlink(
+        &self,
+        VersionEntry {
+            path,
+            version_string,
+        }: &VersionEntry,
+    ) -> Result<(), BuilderErr> {
+        if !self.config.manage_src_symlink {
+            return Ok(());
+        }
+
+        // eselect kernel only knows the trees in /usr/src, others are linked directly
+        if self.config.use_eselect && path.parent() == Some(Path::new("/usr/src")) {
+            if self.config.kernel_src != Path::new("/usr/src") {
+                self.warn("eselect kernel only manages /usr/src/linux");
+            }
+            return eselect::set(self, version_string).map_err(BuilderErr::LinkingFileError);
+        }
+
+        let linux = PathBuf::from(&self.config.kernel_src).join("linux");
+        match linux.read_link() {
+            Ok(target) if target.to_string_lossy() == *version_string || target == *path => {
+                return Ok(());
+            }
+            Ok(_) => std::fs::remove_file(&linux).map_err(BuilderErr::LinkingFileError)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(BuilderErr::LinkingFileError(e)),
+        }
+        unix::fs::symlink(path, linux).map_err(BuilderErr::LinkingFileError)?;
+
+        Ok(())
+    }
+
+    /// Applies the configured patches to the tree in order. Applied patches are recorded in the
+    /// tree by checksum, so building it again does not apply them twice.
+    fn apply_patches(&self, path: &Path) -> Result<(), BuilderErr> {
+        if self.config.patches.is_empty() {
+            return Ok(());
+        }
+
+        let patch_files = patches::collect(
+            self,
+            &self.config.patches,
+            &self.config.state_dir.join("patches"),
+        )
+        .map_err(|e| BuilderErr::PatchError(e.to_string()))?;
+        let applied = patches::applied(path);
+        for patch in patch_files {
+            let checksum = install::sha256(self, &patch)
+                .map_err(|e| BuilderErr::PatchError(format!("{}: {e}", patch.display())))?;
+            if applied.contains(&checksum) {
+                continue;
+            }
+
+            if patches::is_applied(self, path, &patch) {
+                println!("{} is already applied", patch.display());
+            } else {
+                patches::apply(self, path, &patch)
+                    .map_err(|e| BuilderErr::PatchError(format!("{}: {e}", patch.display())))?;
+                println!("Applied {}", patch.display());
+            }
+            patches::record(path, &checksum).map_err(|e| BuilderErr::PatchError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks if objects of an earlier build in the tree were built with another config or
+    /// compiler and offers `make clean`, as mixing them leads to subtle breakage.
+    pub(crate) fn clean_stale_tree(&self, path: &Path) -> Result<(), BuilderErr> {
+        let Ok(auto_conf) = std::fs::read_to_string(path.join("include/config/auto.conf")) else {
+            // never built or already clean
+            return Ok(());
+        };
+
+        let mut reasons = vec![];
+        let recorded = std::fs::read_to_string(path.join(Self::CONFIG_HASH_FILE)).ok();
+        let current = install::sha256(self, &path.join(".config")).ok();
+        if let (Some(recorded), Some(current)) = (recorded, current) {
+            if recorded.trim() != current {
+                reasons.push("the kernel config changed since the last build".to_string());
+            }
+        }
+
+        let built_with = auto_conf.lines().find_map(|line| {
+            line.strip_prefix("CONFIG_CC_VERSION_TEXT=")
+                .map(|text| text.trim_matches('"').to_string())
+        });
+        let compiler = std::env::var("CC").unwrap_or_else(|_| "gcc".to_string());
+        let installed = self
+            .run_output(&Invocation::new(&compiler).arg("--version"))
+            .ok()
+            .and_then(|output| output.stdout.lines().next().map(ToString::to_string));
+        if let (Some(built_with), Some(installed)) = (built_with, installed) {
+            if built_with != installed {
+                reasons.push(format!(
+                    "objects were built with `{built_with}`, now `{installed}` is installed"
+                ));
+            }
+        }
+
+        if reasons.is_empty() {
+            return Ok(());
+        }
+        for reason in &reasons {
+            self.warn(format!("{} is stale, {reason}", path.display()));
+        }
+        if !self.confirm_prompt("Run `make clean` before building?")? {
+            return Ok(());
+        }
+
+        self.progress.on_step_start("Cleaning source tree...");
+        let output = self
+            .run_output(&self.make().current_dir(path).arg("clean"))
+            .map_err(BuilderErr::KernelBuildFail)?;
+        self.progress.on_step_end(true, "");
+        if !output.success {
+            return Err(BuilderErr::KernelBuildFail(std::io::Error::other(
+                output.stderr.trim().to_string(),
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// `make` with the `LOCALVERSION` of the selected flavor, which ends up in the release
+    pub(crate) fn make(&self) -> Invocation {
+        let make = Invocation::new("make");
+        match self
+            .selected_flavor()
+            .and_then(|flavor| flavor.localversion.as_ref())
+        {
+            Some(localversion) => make.env("LOCALVERSION", localversion),
+            None => make,
+        }
+    }
+
+    pub(crate) fn build_kernel(&self, path: &Path) -> Result<(), BuilderErr> {
+        let new_flags = self
+            .run_output(&self.make().arg("listnewconfigs").current_dir(path))
+            .map_err(BuilderErr::KernelBuildFail)?;
+
+        if !new_flags.stdout.is_empty() {
+            // asks for the new options on the terminal
+            let success = self
+                .run_interactive(&self.make().arg("oldconfig").current_dir(path))
+                .map_err(BuilderErr::KernelBuildFail)?;
+            if !success {
+                return Err(BuilderErr::KernelBuildFail(std::io::Error::other(
+                    "make oldconfig exited with an error",
+                )));
+            }
+        }
+
+        let threads: NonZeroUsize = self.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism().unwrap_or(NonZeroUsize::new(1).unwrap())
+        });
+        self.progress.on_step_start("Compiling kernel");
+        let make = self
+            .make()
+            .current_dir(path)
+            .args(["-j", &threads.to_string()]);
+        let success = self
+            .run_streamed(&make, &mut |line| {
+                self.progress
+                    .on_progress(&format!("Compiling kernel: {}", line.to_ascii_lowercase()));
+            })
+            .map_err(BuilderErr::KernelBuildFail)?;
+        if !success {
+            self.progress.on_step_end(false, "Failed compiling kernel");
+            return Err(BuilderErr::KernelBuildFail(std::io::Error::other(
+                "make exited with an error",
+            )));
+        }
+
+        self.progress.on_step_end(true, "Finished compiling Kernel");
+
+        Ok(())
+    }
+
+    /// Runs a build step and records its outcome in the state database. Failing to record it
+    /// only warns, so it never hides the result of the step.
+    pub(crate) fn track_step<T>(
+        &self,
+        kver: &str,
+        step: state::BuildStep,
+        run: impl FnOnce() -> Result<T, BuilderErr>,
+    ) -> Result<T, BuilderErr> {
+        let result = run();
+
+        let recorded = self.load_state().and_then(|mut state| {
+            state.record_build(kver, step, result.is_ok());
+            self.save_state(&state)
+        });
+        if let Err(e) = recorded {
+            self.warn(format!(
+                "could not record the build step in the state database: {e}"
+            ));
+        }
+
+        result
+    }
+
+    /// Offers to rebuild packages with out-of-tree modules like nvidia-drivers or zfs-kmod against
+    /// the new kernel, which `/usr/src/linux` points to by now. Without them the new kernel
+    /// would boot without graphics or root filesystem.
+    pub(crate) fn offer_module_rebuild(&self, options: &BuildOptions) -> Result<(), BuilderErr> {
+        let packages = portage::module_packages();
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        println!("Packages with out-of-tree kernel modules:");
+        for package in &packages {
+            println!("  {package}");
+        }
+        if !self.confirm_or(
+            "Run `emerge @module-rebuild` for the new kernel?",
+            options.module_rebuild,
+        )? {
+            return Ok(());
+        }
+
+        portage::emerge(self, &["--oneshot", "@module-rebuild"])
+            .map_err(|e| BuilderErr::EmergeFailed(e.to_string()))
+    }
+
+    /// On a ZFS root the kernel is unbootable without zfs-kmod built for it, so it is rebuilt
+    /// right after the modules are installed, before any initramfs is generated.
+    pub(crate) fn rebuild_zfs_module(&self) -> Result<(), BuilderErr> {
+        if snapshot::zfs_root().is_none() {
+            return Ok(());
+        }
+
+        println!("Root is on ZFS, rebuilding {}", Self::ZFS_KMOD);
+        portage::emerge(self, &["--oneshot", Self::ZFS_KMOD])
+            .map_err(|e| BuilderErr::ZfsError(format!("rebuilding zfs-kmod failed: {e}")))
+    }
+
+    /// Blocks the install when the root is on ZFS and the zfs module is missing for the kernel
+    pub(crate) fn check_zfs_module(&self, kver: &str) -> Result<(), BuilderErr> {
+        if snapshot::zfs_root().is_none() {
+            return Ok(());
+        }
+
+        let modules = Path::new(Self::MODULES_PATH).join(kver);
+        if signing::find_modules(&modules, &["*zfs.ko*".to_string()]).is_empty() {
+            return Err(BuilderErr::ZfsError(format!(
+                "no zfs module in {}, rebuild {} for {kver}",
+                modules.display(),
+                Self::ZFS_KMOD
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Warns when installed packages with out-of-tree modules like nvidia-drivers or zfs-kmod do
+    /// not support the kernel version yet, before an hour is spent building a kernel without
+    /// graphics or root filesystem. Returns whether to continue.
+    fn check_module_compat(
+        &self,
+        version_string: &str,
+        options: &BuildOptions,
+    ) -> Result<bool, BuilderErr> {
+        let Some(kernel) = KernelVersion::parse(version_string) else {
+            return Ok(true);
+        };
+        let reasons: Vec<String> = compat::installed()
+            .iter()
+            .filter_map(|package| package.unsupported(&kernel))
+            .collect();
+        if reasons.is_empty() {
+            return Ok(true);
+        }
+
+        for reason in &reasons {
+            self.warn(reason.clone());
+        }
+        eprintln!("The kernel would boot without the modules of these packages");
+        self.confirm_or("Build it anyway?", options.build_unsupported)
+    }
+
+    /// Rebuilds the configured packages with out-of-tree modules one after another. A failing
+    /// package does not stop the others, the results end up in the summary of the run.
+    pub(crate) fn rebuild_module_packages(&self) -> Vec<external::ModuleBuild> {
+        let mut packages = self.config.module_packages.clone();
+        // rebuilt before already when the root is on ZFS
+        if snapshot::zfs_root().is_some() {
+            packages.retain(|package| !package.contains(Self::ZFS_KMOD));
+        }
+        // a kernel without matching nvidia modules means a desktop without graphics
+        if compat::ModulePackage::installed(compat::NVIDIA).is_some()
+            && !packages
+                .iter()
+                .any(|package| package.contains(compat::NVIDIA))
+        {
+            packages.push(compat::NVIDIA.to_string());
+        }
+        let count = packages.len();
+        packages
+            .iter()
+            .enumerate()
+            .map(|(index, package)| {
+                println!("[{}/{count}] Rebuilding {package}", index + 1);
+                let error = portage::emerge(self, &["--oneshot", package])
+                    .err()
+                    .map(|e| e.to_string());
+                match &error {
+                    None => println!("[{}/{count}] {package} rebuilt", index + 1),
+                    Some(error) => eprintln!("[{}/{count}] {package} failed: {error}", index + 1),
+                }
+                external::ModuleBuild {
+                    name: package.clone(),
+                    error,
+                }
+            })
+            .collect()
+    }
+
+    /// Checks that the modules of packages like virtualbox-modules were built for the kernel and
+    /// resolve with `modprobe --dry-run` against its module tree. Only failing packages are
+    /// returned for the summary.
+    pub(crate) fn check_module_packages(&self, kver: &str) -> Vec<external::ModuleBuild> {
+        let tree = format!("/lib/modules/{kver}/");
+        portage::module_packages()
+            .into_iter()
+            // distribution kernels own the module trees of their own releases
+            .filter(|package| !package.starts_with("sys-kernel/"))
+            .filter_map(|package| {
+                let modules: Vec<String> = portage::package_modules(&package)
+                    .iter()
+                    .filter(|module| module.to_string_lossy().contains(&tree))
+                    .filter_map(|module| {
+                        let name = module.file_name()?.to_string_lossy();
+                        name.split(".ko").next().map(ToString::to_string)
+                    })
+                    .collect();
+                if modules.is_empty() {
+                    return Some(external::ModuleBuild {
+                        name: package,
+                        error: Some(format!("not built for {kver}")),
+                    });
+                }
+
+                let failed: Vec<String> = modules
+                    .into_iter()
+                    .filter(|module| external::modprobe_dry_run(self, kver, module).is_err())
+                    .collect();
+                (!failed.is_empty()).then(|| external::ModuleBuild {
+                    name: package,
+                    error: Some(format!("modules do not resolve: {}", failed.join(", "))),
+                })
+            })
+            .collect()
+    }
+
+    /// Checks that the configured critical modules resolve with their dependencies in the module
+    /// tree of the new kernel, so a missing driver shows up before rebooting into it.
+    pub(crate) fn check_critical_modules(&self, kver: &str) -> Vec<external::ModuleBuild> {
+        self.config
+            .critical_modules
+            .iter()
+            .filter_map(|module| {
+                let err = external::modprobe_dry_run(self, kver, module).err()?;
+                self.warn(format!(
+                    "critical module {module} does not resolve for {kver}: {err}"
+                ));
+                Some(external::ModuleBuild {
+                    name: format!("module {module}"),
+                    error: Some(err.to_string()),
+                })
+            })
+            .collect()
+    }
+
+    /// Warns about modules in use by the running kernel that the new release does not have, e.g.
+    /// because they were renamed or the driver got disabled in the config.
+    pub(crate) fn compare_loaded_modules(&self, kver: &str) {
+        let missing = modules::missing_loaded(&Path::new(Self::MODULES_PATH).join(kver));
+        if missing.is_empty() {
+            return;
+        }
+
+        self.warn(format!(
+            "modules loaded now are not available in {kver}: {}",
+            missing.join(", ")
+        ));
+        eprintln!("Check the config for the drivers of these devices before rebooting");
+    }
+
+    /// Builds and installs the configured out-of-tree module directories against the tree, each
+    /// independently like the module packages.
+    pub(crate) fn build_external_modules(&self, path: &Path) -> Vec<external::ModuleBuild> {
+        self.config
+            .external_modules
+            .iter()
+            .map(|dir| {
+                self.progress
+                    .on_step_start(&format!("Building modules in {}", dir.display()));
+                let error = self.build_external(path, dir).err().map(|e| e.to_string());
+                self.progress.on_step_end(true, "");
+                match &error {
+                    None => println!("Built modules in {}", dir.display()),
+                    Some(error) => {
+                        eprintln!("Building modules in {} failed: {error}", dir.display())
+                    }
+                }
+                external::ModuleBuild {
+                    name: dir.display().to_string(),
+                    error,
+                }
+            })
+            .collect()
+    }
+
+    /// Signs out-of-tree modules like nvidia or zfs with the module signing key of the kernel
+    /// tree, so they still load with enforced module signatures. Compressed modules cannot be
+    /// signed afterwards and are skipped.
+    pub(crate) fn sign_external_modules(&self, path: &Path, kver: &str) -> Result<(), BuilderErr> {
+        let kernel_config = kconfig::KernelConfig::load(&path.join(".config"))
+            .map_err(BuilderErr::KernelBuildFail)?;
+        let mut globs = self.config.module_sign_globs.clone();
+        // rebuilt nvidia modules are unsigned unless the ebuild signs them itself
+        if kernel_config.get("MODULE_SIG") == Some("y")
+            && compat::ModulePackage::installed(compat::NVIDIA).is_some()
+            && !globs.iter().any(|glob| glob.contains("nvidia"))
+        {
+            globs.push("video/nvidia*.ko".to_string());
+        }
+        if globs.is_empty() {
+            return Ok(());
+        }
+
+        let hash = kernel_config.get("MODULE_SIG_HASH").unwrap_or("sha512");
+        let key = self
+            .config
+            .module_signing_key
+            .clone()
+            .unwrap_or_else(|| path.join("certs/signing_key.pem"));
+        let cert = self
+            .config
+            .module_signing_cert
+            .clone()
+            .unwrap_or_else(|| path.join("certs/signing_key.x509"));
+
+        let modules = Path::new(Self::MODULES_PATH).join(kver);
+        for module in signing::find_modules(&modules, &globs) {
+            if module.extension().is_some_and(|ext| ext != "ko") {
+                self.warn(format!(
+                    "cannot sign compressed module {}",
+                    module.display()
+                ));
+                continue;
+            }
+            if signing::module_signed(&module).map_err(BuilderErr::KernelBuildFail)? {
+                continue;
+            }
+            signing::sign_module(self, path, hash, &key, &cert, &module)
+                .map_err(BuilderErr::SigningError)?;
+            println!("Signed module {}", module.display());
+        }
+
+        Ok(())
+    }
+
+    /// Runs the scripts in one of the `/etc/kernel` hook directories with the kernel release and
+    /// the installed image as arguments.
+    pub(crate) fn run_kernel_hooks(&self, dir: &str, kver: &str) -> Result<(), BuilderErr> {
+        hooks::run_parts(self, Path::new(dir), kver, &self.kernel_path(kver))
+            .map_err(BuilderErr::HookFailed)
+    }
+
+    /// Log in the state directory collecting the output of the step hooks
+    const RUN_LOG: &'static str = "run.log";
+
+    /// Runs the hooks configured for a step of the build. They get the step, the source tree and
+    /// kernel release, the artifact paths and the flavor as `KERNEL_BUILDER_*` variables, and
+    /// the same with the timings of the steps so far in a JSON file at `KERNEL_BUILDER_CONTEXT`.
+    pub(crate) fn run_step_hooks(
+        &self,
+        step: hooks::HookStep,
+        version_entry: &VersionEntry,
+        kver: &str,
+    ) -> Result<(), BuilderErr> {
+        let scripts = self.config.hooks.get(step);
+        if scripts.is_empty() {
+            return Ok(());
+        }
+
+        let path_var = |path: Option<PathBuf>| {
+            path.map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let mut env = vec![
+            ("KERNEL_BUILDER_STEP", step.name().to_string()),
+            (
+                "KERNEL_BUILDER_VERSION",
+                version_entry.version_string.clone(),
+            ),
+            ("KERNEL_BUILDER_RELEASE", kver.to_string()),
+            (
+                "KERNEL_BUILDER_SOURCE",
+                version_entry.path.to_string_lossy().to_string(),
+            ),
+            (
+                "KERNEL_BUILDER_KERNEL",
+                path_var(Some(self.kernel_path(kver))),
+            ),
+            (
+                "KERNEL_BUILDER_INITRAMFS",
+                path_var(self.initramfs_path(kver)),
+            ),
+            ("KERNEL_BUILDER_UKI", path_var(self.uki_path(kver))),
+            (
+                "KERNEL_BUILDER_FLAVOR",
+                self.flavor.clone().unwrap_or_default(),
+            ),
+        ];
+
+        let context = hooks::Context {
+            step: step.name(),
+            version: &version_entry.version_string,
+            kernelrelease: kver,
+            source: &version_entry.path,
+            kernel: self.kernel_path(kver),
+            initramfs: self.initramfs_path(kver),
+            uki: self.uki_path(kver),
+            flavor: self.flavor.as_deref(),
+            timings: &self.timings.borrow(),
+        };
+        // private dir below the state dir, removed with the context once the hooks are done
+        let context_dir = std::fs::create_dir_all(&self.config.state_dir)
+            .and_then(|()| tmp::TempDir::new_in(&self.config.state_dir, "hook"));
+        let context = context_dir
+            .and_then(|dir| {
+                let context_path = dir.join(format!("{}.json", step.name()));
+                let json = serde_json::to_string_pretty(&context).map_err(std::io::Error::other)?;
+                std::fs::File::create_new(&context_path)?.write_all(json.as_bytes())?;
+                Ok((dir, context_path))
+            })
+            .inspect_err(|err| self.warn(format!("cannot write the hook context: {err}")))
+            .ok();
+        env.push((
+            "KERNEL_BUILDER_CONTEXT",
+            path_var(context.as_ref().map(|(_, path)| path.clone())),
+        ));
+
+        let log_path = self.config.state_dir.join(Self::RUN_LOG);
+        let log = std::fs::create_dir_all(&self.config.state_dir).and_then(|_| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_path)
+        });
+        let mut log: Box<dyn std::io::Write> = match log {
+            Ok(log) => Box::new(log),
+            Err(err) => {
+                self.warn(format!("cannot open {}: {err}", log_path.display()));
+                Box::new(std::io::sink())
+            }
+        };
+        let _ = writeln!(log, "[{}] {} {kver}", template::timestamp(), step.name());
+
+        hooks::run_step(self, scripts, &env, &mut log, |warning| self.warn(warning))
+            .map_err(BuilderErr::HookFailed)
+    }
+
+    fn make_menuconfig(&self, path: &Path) -> Result<(), BuilderErr> {
+        let success = self
+            .run_interactive(&self.make().current_dir(path).arg("menuconfig"))
+            .map_err(|_| BuilderErr::MenuconfigError)?;
+        if !success {
+            return Err(BuilderErr::MenuconfigError);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn install_kernel_modules(&self, path: &Path) -> Result<(), BuilderErr> {
+        self.progress.on_step_start("Install kernel modules");
+        let output = self
+            .run_output(&self.make().current_dir(path).arg("modules_install"))
+            .map_err(BuilderErr::KernelBuildFail)?;
+        if !output.success {
+            self.progress
+                .on_step_end(false, "Failed installing modules");
+            return Err(BuilderErr::KernelBuildFail(std::io::Error::other(
+                output.stderr.trim().to_string(),
+            )));
+        }
+        self.progress
+            .on_step_end(true, "Finished installing modules");
+
+        Ok(())
+    }
+
+    /// Schedules regenerating the initramfs of the kernel a package emerged modules for, e.g. by
+    /// `emerge @module-rebuild` after a build, when the modules are newer than the image. The
+    /// kernel is the `KV_FULL` of the package, or else the one `/usr/src/linux` points to. The
+    /// initramfs is regenerated once after emerge is done, however many packages it merges.
+    fn schedule_module_initramfs(&self) -> Result<(), BuilderErr> {
+        #[cfg(feature = "dracut")]
+        if let Some(kver) = portage::module_package_kernel().or_else(|| self.linked_kernel()) {
+            if !self.initramfs_less() && self.initramfs_outdated(&kver) {
+                let scheduled = portage::schedule(
+                    self,
+                    &format!("kernel-builder-initramfs-{kver}"),
+                    &["initramfs", "--kver", &kver, "--yes"],
+                    "/var/log/kernel-builder-initramfs.log",
+                )
+                .map_err(BuilderErr::KernelBuildFail)?;
+                if scheduled {
+                    println!(
+                        "Modules of {kver} changed after its initramfs was generated, scheduled regenerating it"
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the kernel config can be booted directly as EFI application and embeds the
+    /// managed kernel command line into it, as there is no boot loader passing one. A `.config`
+    /// symlinked to the master config is replaced with a copy first, so the command line of
+    /// this tree does not end up in the config shared with other trees.
+    fn prepare_efi_stub(&self, path: &Path) -> Result<(), BuilderErr> {
+        let dot_config = path.join(".config");
+        let kernel_config =
+            kconfig::KernelConfig::load(&dot_config).map_err(BuilderErr::KernelBuildFail)?;
+        if !kernel_config.is_builtin("EFI_STUB") {
+            return Err(BuilderErr::EfiStubError(
+                "CONFIG_EFI_STUB is not enabled in the kernel config".into(),
+            ));
+        }
+
+        if let Some(cmdline) = self.kernel_cmdline()? {
+            // the value is a kconfig string, stored with backslashes and quotes escaped
+            let escaped = cmdline.replace('\\', "\\\\").replace('"', "\\\"");
+            if kernel_config.get("CMDLINE") != Some(escaped.as_str())
+                || !kernel_config.is_builtin("CMDLINE_BOOL")
+            {
+                if dot_config.is_symlink() {
+                    let master = std::fs::read(&dot_config).map_err(BuilderErr::KernelBuildFail)?;
+                    std::fs::remove_file(&dot_config).map_err(BuilderErr::LinkingFileError)?;
+                    std::fs::write(&dot_config, master).map_err(BuilderErr::KernelBuildFail)?;
+                }
+                kconfig::set_options(
+                    &dot_config,
+                    &[
+                        ("CMDLINE_BOOL", "y"),
+                        ("CMDLINE", &format!("\"{escaped}\"")),
+                    ],
+                )
+                .map_err(BuilderErr::KernelBuildFail)?;
+                // let kconfig settle the dependencies of the changed options
+                let olddefconfig = self
+                    .run_output(&self.make().current_dir(path).arg("olddefconfig"))
+                    .map_err(BuilderErr::KernelBuildFail)?;
+                if !olddefconfig.success {
+                    return Err(BuilderErr::EfiStubError(format!(
+                        "make olddefconfig failed: {}",
+                        olddefconfig.stderr.trim()
+                    )));
+                }
+                println!("Embedded kernel command line into CONFIG_CMDLINE: {cmdline}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure the kernel can mount the root filesystem without an initramfs, i.e. the
+    /// filesystem and block device drivers of the running root are compiled in.
+    pub(crate) fn verify_builtin_root(path: &Path) -> Result<(), BuilderErr> {
+        let Some(root) = rootfs::RootStack::detect() else {
+            return Err(BuilderErr::RootNotBuiltin(vec![
+                "could not detect the root filesystem".into(),
+            ]));
+        };
+        let kernel_config = kconfig::KernelConfig::load(&path.join(".config"))
+            .map_err(BuilderErr::KernelBuildFail)?;
+
+        let mut missing = vec![];
+        if !root
+            .fs_kconfig()
+            .is_some_and(|opt| kernel_config.is_builtin(opt))
+        {
+            missing.push(format!(
+                "filesystem driver `{}` is not built in",
+                root.fs_module()
+            ));
+        }
+        if root.encrypted {
+            missing.push(format!(
+                "root device {} is encrypted and needs an initramfs to be unlocked",
+                root.device.display()
+            ));
+        }
+
+        // modules.builtin only exists after the kernel has been built
+        if let Ok(builtin) = std::fs::read_to_string(path.join("modules.builtin")) {
+            let builtin = builtin
+                .lines()
+                .filter_map(|line| Path::new(line).file_stem())
+                .map(|name| name.to_string_lossy().replace('-', "_"))
+                .collect::<Vec<_>>();
+            missing.extend(
+                root.driver_modules()
+                    .into_iter()
+                    .filter(|module| !builtin.contains(&module.replace('-', "_")))
+                    .map(|module| format!("block device driver `{module}` is not built in")),
+            );
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(BuilderErr::RootNotBuiltin(missing))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRunner, KBConfig, Output, RecordingRunner, Silent};
+
+    /// Every program fails with an error message
+    #[derive(Debug)]
+    struct FailingRunner;
+
+    impl CommandRunner for FailingRunner {
+        fn output(&self, _invocation: &Invocation) -> std::io::Result<Output> {
+            Ok(Output {
+                success: false,
+                code: Some(2),
+                stderr: "make: *** [Makefile:1234: modules_install] Error 2\n".to_string(),
+                ..Output::default()
+            })
+        }
+
+        fn stream(
+            &self,
+            _invocation: &Invocation,
+            _on_line: &mut dyn FnMut(&str),
+        ) -> std::io::Result<bool> {
+            Ok(false)
+        }
+
+        fn interactive(&self, _invocation: &Invocation) -> std::io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn build_kernel_runs_make_in_the_tree() {
+        let dir = tmp::TempDir::new("build-test").unwrap();
+        let recorder = RecordingRunner::default();
+        let builder = KernelBuilder::builder(KBConfig::for_test(dir.path()))
+            .runner(Box::new(recorder.clone()))
+            .progress(Box::new(Silent))
+            .jobs(NonZeroUsize::new(4).unwrap())
+            .build()
+            .unwrap();
+
+        builder.build_kernel(dir.path()).unwrap();
+
+        assert_eq!(
+            recorder.invocations(),
+            [
+                Invocation::new("make")
+                    .arg("listnewconfigs")
+                    .current_dir(dir.path()),
+                Invocation::new("make")
+                    .current_dir(dir.path())
+                    .args(["-j", "4"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn external_modules_build_against_the_tree() {
+        let dir = tmp::TempDir::new("build-test").unwrap();
+        let recorder = RecordingRunner::default();
+        let builder = KernelBuilder::builder(KBConfig::for_test(dir.path()))
+            .runner(Box::new(recorder.clone()))
+            .build()
+            .unwrap();
+        let modules = dir.join("module");
+
+        builder.build_external(dir.path(), &modules).unwrap();
+
+        let make = |target| {
+            Invocation::new("make")
+                .arg("-C")
+                .arg(dir.path())
+                .arg(format!("M={}", modules.display()))
+                .arg(target)
+        };
+        assert_eq!(
+            recorder.invocations(),
+            [make("modules"), make("modules_install")]
+        );
+    }
+
+    #[test]
+    fn failing_make_fails_the_step() {
+        let dir = tmp::TempDir::new("build-test").unwrap();
+        let builder = KernelBuilder::builder(KBConfig::for_test(dir.path()))
+            .runner(Box::new(FailingRunner))
+            .progress(Box::new(Silent))
+            .build()
+            .unwrap();
+
+        let Err(BuilderErr::KernelBuildFail(e)) = builder.install_kernel_modules(dir.path()) else {
+            panic!("failed modules_install succeeded");
+        };
+        assert_eq!(
+            e.to_string(),
+            "make: *** [Makefile:1234: modules_install] Error 2"
+        );
+        assert!(matches!(
+            builder.make_menuconfig(dir.path()),
+            Err(BuilderErr::MenuconfigError)
+        ));
+    }
+
+    #[test]
+    fn flavor_localversion_is_passed_to_make() {
+        let dir = tmp::TempDir::new("build-test").unwrap();
+        let mut config = KBConfig::for_test(dir.path());
+        config.flavors.insert(
+            "rt".to_string(),
+            crate::config::Flavor {
+                localversion: Some("-rt".to_string()),
+                ..Default::default()
+            },
+        );
+        let recorder = RecordingRunner::default();
+        let builder = KernelBuilder::builder(config)
+            .runner(Box::new(recorder.clone()))
+            .flavor("rt")
+            .build()
+            .unwrap();
+
+        builder.install_kernel_modules(dir.path()).unwrap();
+
+        assert_eq!(
+            recorder.invocations(),
+            [Invocation::new("make")
+                .env("LOCALVERSION", "-rt")
+                .current_dir(dir.path())
+                .arg("modules_install")]
+        );
+        assert!(std::env::var_os("LOCALVERSION").is_none());
+    }
+
+    #[test]
+    fn track_step_returns_the_step_error_when_the_state_is_unwritable() {
+        let dir = tmp::TempDir::new("build-test").unwrap();
+        // a file where the state directory should be
+        std::fs::write(dir.join("state"), "").unwrap();
+        let builder = KernelBuilder::new(KBConfig::for_test(dir.path()));
+
+        let result: Result<(), _> = builder.track_step("6.12.1", state::BuildStep::Modules, || {
+            Err(BuilderErr::Cancelled)
+        });
+
+        assert!(matches!(result, Err(BuilderErr::Cancelled)));
+        assert_eq!(builder.warnings.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{CommandRunner, Invocation};
+use std::io;
+use std::path::Path;
+
+/// ChangeLog of a stable release like `6.12.8` as published on kernel.org, it lists the
+/// commits since the previous release of the series.
+pub fn stable_changelog(runner: &dyn CommandRunner, version: &str) -> io::Result<String> {
+    let major = version.split('.').next().unwrap_or(version);
+    runner.run(
+        &Invocation::new("curl")
+            .args(["--fail", "--location", "--silent", "--show-error"])
+            .arg(format!(
+                "https://cdn.kernel.org/pub/linux/kernel/v{major}.x/ChangeLog-{version}"
+            )),
+    )
+}
+
+/// Subject lines of the commits in a ChangeLog in `git log` format
+pub fn subjects(changelog: &str) -> Vec<&str> {
+    let mut subjects = vec![];
+    let mut lines = changelog.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with("Date:") {
+            if let Some(subject) = lines.find(|line| !line.trim().is_empty()) {
+                subjects.push(subject.trim());
+            }
+        }
+    }
+
+    subjects
+}
+
+/// `git shortlog` of a git tree from a tag like `v6.12.5` to the checked out commit
+pub fn shortlog(runner: &dyn CommandRunner, path: &Path, from: &str) -> io::Result<String> {
+    runner.run(
+        &Invocation::new("git")
+            .current_dir(path)
+            .args(["shortlog", "--no-merges"])
+            .arg(format!("{from}..HEAD")),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subjects_of_changelog_commits() {
+        let changelog = "\
+commit 1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e
+Author: Greg Kroah-Hartman <gregkh@linuxfoundation.org>
+Date:   Thu Jan 2 10:32:05 2025 +0100
+
+    Linux 6.12.8
+
+commit 0a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d
+Author: Jane Doe <jane@example.org>
+Date:   Mon Dec 30 16:12:44 2024 +0100
+
+    drm/amdgpu: fix suspend on some laptops
+    
+    [ Upstream commit 123456789abc ]
+
+    Details of the fix.
+";
+
+        assert_eq!(
+            subjects(changelog),
+            ["Linux 6.12.8", "drm/amdgpu: fix suspend on some laptops"]
+        );
+        assert!(subjects("").is_empty());
+    }
+}
@@ -1,11 +1,99 @@
+use crate::Verbosity;
+use std::path::PathBuf;
+
 #[derive(Debug)]
 pub struct Args {
+    pub subcommand: Option<Subcommand>,
     pub no_build: bool,
     #[cfg(feature = "dracut")]
     pub no_initramfs: bool,
     pub no_modules: bool,
+    pub no_uki: bool,
     pub menuconfig: bool,
     pub replace: bool,
+    pub kexec_reboot: bool,
+    pub reboot: bool,
+    pub reboot_at: Option<String>,
+    pub flavor: Option<String>,
+    pub yes: bool,
+    pub changelog: bool,
+    pub git_ref: Option<String>,
+    pub source: Option<String>,
+    pub skip: Vec<String>,
+    pub resume_from: Option<String>,
+    pub dry_run: bool,
+    pub report: Option<PathBuf>,
+    pub verbosity: Verbosity,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subcommand {
+    Cmdline(CmdlineAction),
+    #[cfg(feature = "dracut")]
+    Initramfs {
+        rescue: bool,
+        all: bool,
+        kver: Option<String>,
+    },
+    TestBoot {
+        kver: Option<String>,
+        timeout: u64,
+    },
+    Init,
+    Auto,
+    Hook {
+        from_portage: bool,
+    },
+    Prune {
+        keep: Option<usize>,
+        sources: bool,
+    },
+    Verify,
+    Export {
+        kver: Option<String>,
+        output: Option<PathBuf>,
+    },
+    Import {
+        archive: PathBuf,
+    },
+    Binpkg {
+        kver: Option<String>,
+    },
+    Fetch {
+        version: String,
+    },
+    Status {
+        json: bool,
+    },
+    List {
+        json: bool,
+    },
+    MarkGood,
+    Alias {
+        name: Option<String>,
+        target: Option<String>,
+        remove: bool,
+    },
+    /// Invoked as plugin by systemd's `kernel-install`
+    KernelInstall {
+        action: KernelInstallAction,
+        kver: String,
+        image: Option<PathBuf>,
+        /// Initrd files in boot order, e.g. microcode followed by the initramfs
+        initrds: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelInstallAction {
+    Add,
+    Remove,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdlineAction {
+    Show,
+    Edit,
 }
 
 impl Args {
@@ -14,6 +102,7 @@ impl Args {
 Kernel Builder
 USAGE:
   kernel-builder [OPTIONS]
+  kernel-builder <SUBCOMMAND>
 FLAGS:
   -h, --help            Prints help information
   -v, --version         Print version
@@ -21,8 +110,59 @@ OPTIONS:
   --no-build          skip build
   --no-initramfs      skip generating initramfs (only if compiled with dracut feature)
   --no-modules        skip installing kernel modules
+  --no-uki            skip generating the unified kernel image (only if `uki` is configured)
   --menuconfig        open menuconfig for kernel configuration
-  --replace           replace the current installed kerne (useful if you have configured to keep the last kernel)
+  --replace           replace the current installed kernel without keeping it (useful if you have configured `keep-old`)
+  --kexec-reboot      reboot into the installed kernel with kexec, skipping firmware and boot loader
+  --reboot            reboot after a successful install instead of asking
+  --reboot-at <HH:MM> schedule a reboot at the given time after a successful install
+  --source <VERSION>  build the tree of a version like 6.12.8 instead of asking, offers to emerge
+                      gentoo-sources if it is missing
+  --git-ref <REF>     tag or branch to check out when building from a git tree
+  --flavor <NAME>     use the overrides of a flavor defined in the config
+  --yes               answer all questions with yes, for unattended builds. Building an end-of-life
+                      or unsupported version, @module-rebuild and depclean are answered with no
+  --changelog         show the changes since the installed kernel of the series before building
+  --skip <STEP>       leave out a step of the build, can be given multiple times
+  --resume-from <STEP> start at a step, e.g. after fixing the cause of a failed run
+  --dry-run           only list the steps a build would run
+  --report <FILE>     write the steps, artifacts and warnings of the build as JSON
+  --verbose           show the commands run and all output of external tools like dracut
+  --quiet             only show errors of external tools
+SUBCOMMANDS:
+  cmdline show        print the kernel command line used for boot artifacts
+  cmdline edit        edit /etc/kernel/cmdline in $EDITOR
+  initramfs           regenerate the initramfs of an installed kernel (only if compiled with dracut feature)
+    --kver <RELEASE>  any kernel release in /lib/modules, prompts for one if omitted
+    --all             regenerate the initramfs of every kernel in /lib/modules
+    --rescue          generate a generic initramfs for the running kernel instead
+  test-boot           boot the installed kernel and initramfs in QEMU/KVM
+    --kver <RELEASE>  kernel release to boot, defaults to the tree /usr/src/linux points to
+    --timeout <SECS>  seconds to wait for init to start, defaults to 60
+  fetch <VERSION>     download, verify and unpack a kernel.org release, e.g. 6.12.8
+  auto                build the newest source tree without asking unless it is installed already
+  hook --from-portage run from the Portage post_pkg_postinst hook after kernel sources were emerged
+    --snippet         print the snippet to add to /etc/portage/bashrc
+  init                detect the boot layout and write a suggested config
+  prune               remove old kernels together with their modules in /lib/modules
+    --keep <N>        number of newest kernels to keep, defaults to `prune-keep` of the config
+    --sources         remove source trees older than all installed kernels instead
+  status              show installed kernels, whether they booted successfully and if a reboot is required
+    --json            print the status as JSON for monitoring and MOTD scripts
+  list                show the source trees and how far the last build of each got
+    --json            print the list as JSON
+  mark-good           record the running kernel as booted successfully, run late during boot
+  alias [NAME [TREE]] list aliases, show one or name a source tree like 6.12.8-gentoo
+    --remove          remove the alias NAME
+  verify              check installed artifacts against the checksums recorded at install time
+  export              pack an installed kernel with its modules into a tar.zst with a manifest of checksums
+    --kver <RELEASE>  kernel release to export, defaults to the tree /usr/src/linux points to
+    --output <FILE>   path of the archive, defaults to kernel-<RELEASE>.tar.zst
+  import <FILE>       verify and install a kernel archive created by export
+  binpkg              package an installed kernel as Portage binary package for `emerge --usepkgonly`
+    --kver <RELEASE>  kernel release to package, defaults to the tree /usr/src/linux points to
+  kernel-install <add|remove> <RELEASE> [<ENTRY-DIR> [<IMAGE> [<INITRD>...]]]
+                      plugin interface for systemd's kernel-install
 ";
 
     #[must_use]
@@ -40,13 +180,182 @@ OPTIONS:
             std::process::exit(0);
         }
 
-        Self {
+        let subcommand = match pargs.subcommand().ok().flatten().as_deref() {
+            None => None,
+            Some("cmdline") => match pargs.subcommand().ok().flatten().as_deref() {
+                Some("show") | None => Some(Subcommand::Cmdline(CmdlineAction::Show)),
+                Some("edit") => Some(Subcommand::Cmdline(CmdlineAction::Edit)),
+                Some(other) => Self::exit_with_usage(&format!("unknown cmdline action `{other}`")),
+            },
+            #[cfg(feature = "dracut")]
+            Some("initramfs") => Some(Subcommand::Initramfs {
+                rescue: pargs.contains("--rescue"),
+                all: pargs.contains("--all"),
+                kver: pargs
+                    .opt_value_from_str("--kver")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            }),
+            Some("test-boot") => Some(Subcommand::TestBoot {
+                kver: pargs
+                    .opt_value_from_str("--kver")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+                timeout: pargs
+                    .opt_value_from_str("--timeout")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string()))
+                    .unwrap_or(60),
+            }),
+            Some("init") => Some(Subcommand::Init),
+            Some("auto") => Some(Subcommand::Auto),
+            Some("hook") => {
+                if pargs.contains("--snippet") {
+                    print!("{}", crate::portage::BASHRC_SNIPPET);
+                    std::process::exit(0);
+                }
+                Some(Subcommand::Hook {
+                    from_portage: pargs.contains("--from-portage"),
+                })
+            }
+            Some("fetch") => Some(Subcommand::Fetch {
+                version: pargs
+                    .free_from_str()
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            }),
+            Some("prune") => Some(Subcommand::Prune {
+                keep: pargs
+                    .opt_value_from_str("--keep")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+                sources: pargs.contains("--sources"),
+            }),
+            Some("verify") => Some(Subcommand::Verify),
+            Some("export") => Some(Subcommand::Export {
+                kver: pargs
+                    .opt_value_from_str("--kver")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+                output: pargs
+                    .opt_value_from_str("--output")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            }),
+            Some("binpkg") => Some(Subcommand::Binpkg {
+                kver: pargs
+                    .opt_value_from_str("--kver")
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            }),
+            Some("import") => Some(Subcommand::Import {
+                archive: pargs
+                    .free_from_str()
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            }),
+            Some("status") => Some(Subcommand::Status {
+                json: pargs.contains("--json"),
+            }),
+            Some("list") => Some(Subcommand::List {
+                json: pargs.contains("--json"),
+            }),
+            Some("mark-good") => Some(Subcommand::MarkGood),
+            Some("alias") => Some(Subcommand::Alias {
+                remove: pargs.contains("--remove"),
+                name: pargs
+                    .opt_free_from_str()
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+                target: pargs
+                    .opt_free_from_str()
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            }),
+            Some("kernel-install") => {
+                let action = match pargs.subcommand().ok().flatten().as_deref() {
+                    Some("add") => KernelInstallAction::Add,
+                    Some("remove") => KernelInstallAction::Remove,
+                    Some(other) => {
+                        Self::exit_with_usage(&format!("unknown kernel-install action `{other}`"))
+                    }
+                    None => Self::exit_with_usage("missing kernel-install action"),
+                };
+                let kver = pargs
+                    .free_from_str()
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string()));
+                // the entry directory is only meaningful to the boot loader spec plugins
+                let _entry_dir: Option<PathBuf> = pargs
+                    .opt_free_from_str()
+                    .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string()));
+                Some(Subcommand::KernelInstall {
+                    action,
+                    kver,
+                    image: pargs
+                        .opt_free_from_str()
+                        .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+                    // the remaining arguments once all options are parsed
+                    initrds: vec![],
+                })
+            }
+            Some(other) => Self::exit_with_usage(&format!("unknown subcommand `{other}`")),
+        };
+
+        let mut args = Self {
+            subcommand,
             no_build: pargs.contains("--no-build"),
             #[cfg(feature = "dracut")]
             no_initramfs: pargs.contains("--no-initramfs"),
             no_modules: pargs.contains("--no-modules"),
+            no_uki: pargs.contains("--no-uki"),
             menuconfig: pargs.contains("--menuconfig"),
             replace: pargs.contains("--replace"),
+            kexec_reboot: pargs.contains("--kexec-reboot"),
+            reboot: pargs.contains("--reboot"),
+            reboot_at: pargs
+                .opt_value_from_fn("--reboot-at", crate::reboot::parse_time)
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            source: pargs
+                .opt_value_from_str("--source")
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            git_ref: pargs
+                .opt_value_from_str("--git-ref")
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            yes: pargs.contains("--yes"),
+            changelog: pargs.contains("--changelog"),
+            flavor: pargs
+                .opt_value_from_str("--flavor")
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            skip: pargs
+                .values_from_str("--skip")
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            resume_from: pargs
+                .opt_value_from_str("--resume-from")
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            dry_run: pargs.contains("--dry-run"),
+            report: pargs
+                .opt_value_from_str("--report")
+                .unwrap_or_else(|e| Self::exit_with_usage(&e.to_string())),
+            verbosity: if pargs.contains("--verbose") {
+                Verbosity::Verbose
+            } else if pargs.contains("--quiet") {
+                Verbosity::Quiet
+            } else {
+                Verbosity::Normal
+            },
+        };
+
+        let rest = pargs.finish();
+        if let Some(Subcommand::KernelInstall { initrds, .. }) = &mut args.subcommand {
+            if let Some(option) = rest
+                .iter()
+                .find(|arg| arg.to_string_lossy().starts_with('-'))
+            {
+                Self::exit_with_usage(&format!("unknown option `{}`", option.to_string_lossy()));
+            }
+            initrds.extend(rest.into_iter().map(PathBuf::from));
+        } else if let Some(unknown) = rest.first() {
+            Self::exit_with_usage(&format!(
+                "unexpected argument `{}`",
+                unknown.to_string_lossy()
+            ));
         }
+
+        args
+    }
+
+    fn exit_with_usage(message: &str) -> ! {
+        eprintln!("error: {message}");
+        eprint!("{}", Self::HELP);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,78 @@
+use crate::{BuilderErr, KernelBuilder};
+use std::path::Path;
+
+impl KernelBuilder {
+    /// Returns the kernel command line, either from the `cmdline` config option or from
+    /// `/etc/kernel/cmdline`. Lines of the file are joined with a single space. If plymouth is
+    /// enabled, the `quiet splash` parameters are appended when missing.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to read an existing `/etc/kernel/cmdline`
+    pub fn kernel_cmdline(&self) -> Result<Option<String>, BuilderErr> {
+        let mut cmdline = match &self.config.cmdline {
+            Some(cmdline) => Some(cmdline.trim().to_string()),
+            None => Self::read_cmdline_file()?,
+        };
+
+        if self.config.plymouth {
+            let params = cmdline.get_or_insert_with(String::new);
+            for param in ["quiet", "splash"] {
+                if !params.split_whitespace().any(|p| p == param) {
+                    if !params.is_empty() {
+                        params.push(' ');
+                    }
+                    params.push_str(param);
+                }
+            }
+        }
+
+        Ok(cmdline)
+    }
+
+    fn read_cmdline_file() -> Result<Option<String>, BuilderErr> {
+        let path = Path::new(Self::CMDLINE_PATH);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(path).map_err(BuilderErr::CmdlineError)?;
+        let cmdline = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Ok((!cmdline.is_empty()).then_some(cmdline))
+    }
+
+    /// Opens the kernel command line in `$EDITOR` and writes it back to `/etc/kernel/cmdline`.
+    /// Nothing is edited when the `cmdline` config option overrides the file.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to read or write `/etc/kernel/cmdline`
+    /// - Failing to spawn the editor
+    pub fn edit_cmdline(&self) -> Result<(), BuilderErr> {
+        if self.config.cmdline.is_some() {
+            self.warn(format!(
+                "`cmdline` is set in the config file and takes precedence over {}, edit it there \
+                 instead",
+                Self::CMDLINE_PATH
+            ));
+            return Ok(());
+        }
+
+        let current = Self::read_cmdline_file()?.unwrap_or_default();
+        let Some(edited) = self.prompter.edit(&current)? else {
+            return Ok(());
+        };
+
+        let edited = edited.split_whitespace().collect::<Vec<_>>().join(" ");
+        std::fs::write(Self::CMDLINE_PATH, format!("{edited}\n"))
+            .map_err(BuilderErr::CmdlineError)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use crate::portage;
+use crate::version::KernelVersion;
+use std::path::Path;
+
+pub const NVIDIA: &str = "x11-drivers/nvidia-drivers";
+
+/// Installed package with out-of-tree modules and the kernel series it supports according to
+/// the `MODULES_KERNEL_MIN` and `MODULES_KERNEL_MAX` of its ebuild, as set by nvidia-drivers,
+/// zfs-kmod and other linux-mod-r1 ebuilds
+#[derive(Debug, Clone)]
+pub struct ModulePackage {
+    /// Package name like `x11-drivers/nvidia-drivers`
+    pub name: String,
+    pub version: String,
+    /// Oldest supported kernel
+    pub kernel_min: Option<KernelVersion>,
+    /// Newest supported kernel series
+    pub kernel_max: Option<KernelVersion>,
+}
+
+/// Splits a package like `nvidia-drivers-550.78-r1` into name and version
+fn split_version(package: &str) -> Option<(&str, &str)> {
+    let index = package
+        .match_indices('-')
+        .map(|(index, _)| index)
+        .find(|index| {
+            package[index + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_digit())
+        })?;
+    Some((&package[..index], &package[index + 1..]))
+}
+
+impl ModulePackage {
+    /// Reads an installed package given as `category/name-version` from the Portage database
+    fn load(atom: &str) -> Option<Self> {
+        let (category, package) = atom.split_once('/')?;
+        let (name, version) = split_version(package)?;
+        let ebuild = std::fs::read_to_string(
+            Path::new("/var/db/pkg")
+                .join(atom)
+                .join(format!("{package}.ebuild")),
+        )
+        .ok()?;
+        let variable = |key: &str| {
+            ebuild.lines().find_map(|line| {
+                line.trim()
+                    .strip_prefix(key)
+                    .and_then(|value| value.strip_prefix('='))
+                    .and_then(|value| KernelVersion::parse(value.trim_matches(['"', '\''])))
+            })
+        };
+
+        Some(Self {
+            name: format!("{category}/{name}"),
+            version: version.to_string(),
+            kernel_min: variable("MODULES_KERNEL_MIN"),
+            kernel_max: variable("MODULES_KERNEL_MAX"),
+        })
+    }
+
+    /// Installed version of a package like `x11-drivers/nvidia-drivers`, `None` if it is not
+    /// installed
+    pub fn installed(name: &str) -> Option<Self> {
+        let (category, package) = name.split_once('/')?;
+        std::fs::read_dir(Path::new("/var/db/pkg").join(category))
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.file_name().to_str().map(ToString::to_string))
+            .find(|dir| split_version(dir).is_some_and(|(dir_name, _)| dir_name == package))
+            .and_then(|dir| Self::load(&format!("{category}/{dir}")))
+    }
+
+    /// Reason why the modules do not build for a kernel, `None` if it is in the supported range
+    /// or the ebuild does not declare one
+    pub fn unsupported(&self, kernel: &KernelVersion) -> Option<String> {
+        if let Some(min) = &self.kernel_min {
+            if kernel.cmp_release(min).is_lt() {
+                return Some(format!(
+                    "{} {} needs at least kernel {min}",
+                    self.name, self.version
+                ));
+            }
+        }
+        if let Some(max) = &self.kernel_max {
+            if (kernel.major(), kernel.minor()) > (max.major(), max.minor()) {
+                return Some(format!(
+                    "{} {} supports kernels up to {}",
+                    self.name,
+                    self.version,
+                    max.series()
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+/// Installed packages with out-of-tree modules, the members of `@module-rebuild`
+pub fn installed() -> Vec<ModulePackage> {
+    portage::module_packages()
+        .iter()
+        .filter_map(|atom| ModulePackage::load(atom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_package_name_and_version() {
+        assert_eq!(
+            split_version("nvidia-drivers-550.78-r1"),
+            Some(("nvidia-drivers", "550.78-r1"))
+        );
+        assert_eq!(split_version("zfs-kmod-2.2.7"), Some(("zfs-kmod", "2.2.7")));
+        assert_eq!(
+            split_version("broadcom-sta-6.30.223.271-r9"),
+            Some(("broadcom-sta", "6.30.223.271-r9"))
+        );
+        assert_eq!(split_version("nvidia-drivers"), None);
+    }
+}
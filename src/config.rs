@@ -0,0 +1,423 @@
+use crate::{
+    bootloader, fetch, layout, signing::Signer, state, Binpkg, Bootloader, BuilderErr,
+    CommandRunner, Deploy, GrubDefault, Hooks, PortageHook, RefindVariant,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+pub struct KBConfig {
+    /// Path to the kernel bz image on the boot partition
+    #[serde(rename = "kernel")]
+    pub kernel_file_path: PathBuf,
+    /// Path to the initramfs on the boot partition
+    #[serde(rename = "initramfs")]
+    pub initramfs_file_path: Option<PathBuf>,
+    /// path to the `.config` file that will be symlinked
+    #[serde(rename = "kernel-config")]
+    pub kernel_config_file_path: PathBuf,
+    /// path to the kernel sources
+    #[serde(rename = "kernel-src")]
+    pub kernel_src: PathBuf,
+    /// Source root `fetch` unpacks kernel.org releases into, defaults to `kernel-src`
+    #[serde(rename = "fetch-dir")]
+    pub fetch_dir: Option<PathBuf>,
+    /// OpenPGP keys of the kernel.org release signers used by `fetch`
+    #[serde(rename = "kernel-org-keys", default = "fetch::default_keyring")]
+    pub kernel_org_keys: PathBuf,
+    /// Names for source trees like `daily = "6.12.8-gentoo"`, usable wherever a version is
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Packages with out-of-tree modules rebuilt in order after the modules are installed, e.g.
+    /// `x11-drivers/nvidia-drivers`, instead of offering `@module-rebuild`
+    #[serde(rename = "module-packages", default)]
+    pub module_packages: Vec<String>,
+    /// Source directories of out-of-tree modules built with kbuild after the modules are installed
+    #[serde(rename = "external-modules", default)]
+    pub external_modules: Vec<PathBuf>,
+    /// Modules the system cannot do without, like the GPU, network and root filesystem drivers,
+    /// checked to resolve in the module tree of the new kernel after installing the modules
+    #[serde(rename = "critical-modules", default)]
+    pub critical_modules: Vec<String>,
+    /// Patch files, directories of patches or URLs applied to the tree before building
+    #[serde(default)]
+    pub patches: Vec<String>,
+    /// Annotate versions with kernel.org release information, needs network access
+    #[serde(rename = "check-releases", default)]
+    pub check_releases: bool,
+    /// Show the disk usage of each source tree in the picker, which walks every tree
+    #[serde(rename = "picker-sizes", default)]
+    pub picker_sizes: bool,
+    /// Whether the Portage hook schedules or launches `auto` after sources were emerged
+    #[serde(rename = "portage-hook", default)]
+    pub portage_hook: PortageHook,
+    /// Git checkouts of the kernel offered for selection besides the release trees
+    #[serde(rename = "git-trees", default)]
+    pub git_trees: Vec<PathBuf>,
+    /// Further directories with kernel sources, merged with `kernel-src` for selection
+    #[serde(rename = "source-roots", default)]
+    pub source_roots: Vec<PathBuf>,
+    /// Globs of source tree names offered for selection, e.g. `linux-*-zen`
+    #[serde(rename = "source-include", default = "default_source_include")]
+    pub source_include: Vec<String>,
+    /// Globs of source tree names never offered for selection
+    #[serde(rename = "source-exclude", default)]
+    pub source_exclude: Vec<String>,
+    /// Series like `6.6.*` that `auto` is limited to, e.g. an LTS series
+    pub track: Option<String>,
+    /// Version or series like `6.6` that `auto` sticks to
+    #[serde(rename = "pin-version")]
+    pub pin_version: Option<String>,
+    /// Known bad versions or series hidden from selection
+    #[serde(rename = "exclude-versions", default)]
+    pub exclude_versions: Vec<String>,
+    /// Path to the unified kernel image on the ESP
+    #[serde(rename = "uki")]
+    pub uki_file_path: Option<PathBuf>,
+    /// Tool used to assemble the unified kernel image
+    #[serde(rename = "uki-generator", default)]
+    pub uki_generator: UkiGenerator,
+    /// Optional splash image embedded into the unified kernel image
+    #[serde(rename = "uki-splash")]
+    pub uki_splash: Option<PathBuf>,
+    /// Kernel command line, overrides the content of `/etc/kernel/cmdline`
+    #[serde(rename = "cmdline")]
+    pub cmdline: Option<String>,
+    /// Prepend the cpu microcode as early cpio to the initramfs
+    #[serde(rename = "early-microcode", default)]
+    pub early_microcode: bool,
+    /// Include plymouth in the initramfs and add `splash` to the kernel command line
+    #[serde(rename = "plymouth", default)]
+    pub plymouth: bool,
+    /// Plymouth theme that is set as default before generating the initramfs
+    #[serde(rename = "plymouth-theme")]
+    pub plymouth_theme: Option<String>,
+    /// Check the generated initramfs for the drivers needed to mount the root filesystem and
+    /// fail the build if they are missing
+    #[serde(rename = "verify-initramfs", default)]
+    pub verify_initramfs: bool,
+    /// Compression used for the initramfs, defaults to the choice of the generator
+    #[serde(rename = "initramfs-compression")]
+    pub initramfs_compression: Option<InitramfsCompression>,
+    /// Boot without initramfs, all drivers for the root filesystem have to be built in
+    #[serde(rename = "skip-initramfs", default)]
+    pub skip_initramfs: bool,
+    /// Path of the rescue initramfs, defaults to the initramfs path with a `-rescue` suffix
+    #[serde(rename = "rescue-initramfs")]
+    pub rescue_initramfs_file_path: Option<PathBuf>,
+    /// Warn when the initramfs exceeds this size in MiB, `0` disables the warning
+    #[serde(
+        rename = "initramfs-size-warning",
+        default = "default_initramfs_size_warning"
+    )]
+    pub initramfs_size_warning: u64,
+    /// dracut configuration directory, defaults to `/etc/dracut.conf.d`
+    #[serde(rename = "dracut-confdir")]
+    pub dracut_confdir: Option<PathBuf>,
+    /// Flavors that can be selected with `--flavor`
+    #[serde(rename = "flavors", default)]
+    pub flavors: HashMap<String, Flavor>,
+    /// Keep existing boot artifacts as `<name>.<old-suffix>` before overwriting them, the
+    /// older `keep-last-kernel` and `last-kernel-suffix` keys are read as the same settings
+    #[serde(rename = "keep-old", alias = "keep-last-kernel", default)]
+    pub keep_old: bool,
+    #[serde(
+        rename = "old-suffix",
+        alias = "last-kernel-suffix",
+        default = "default_old_suffix"
+    )]
+    pub old_suffix: String,
+    /// Load the installed kernel with `kexec -l` after installation to verify it is bootable
+    #[serde(rename = "kexec-test", default)]
+    pub kexec_test: bool,
+    /// Additional locations the kernel and initramfs are copied to, e.g. an ESP next to `/boot`
+    #[serde(rename = "destinations", default)]
+    pub destinations: Vec<Destination>,
+    /// Boot the kernel directly as EFI application without initramfs, the kernel command line
+    /// is embedded with `CONFIG_CMDLINE`
+    #[serde(rename = "efi-stub", default)]
+    pub efi_stub: bool,
+    /// Label of the EFI boot entry
+    #[serde(rename = "efi-label", default = "default_efi_label")]
+    pub efi_label: String,
+    /// Boot loader that is updated after installation
+    #[serde(rename = "bootloader")]
+    pub bootloader: Option<Bootloader>,
+    /// Path of the GRUB configuration generated by `grub-mkconfig`
+    #[serde(rename = "grub-config", default = "bootloader::default_grub_config")]
+    pub grub_config: PathBuf,
+    /// Select the new kernel as GRUB default, permanently or only for the next boot
+    #[serde(rename = "grub-default", default)]
+    pub grub_default: GrubDefault,
+    /// Boot attempts systemd-boot gives a new entry before falling back, enables boot counting
+    #[serde(rename = "boot-counting")]
+    pub boot_counting: Option<u32>,
+    /// Mount point of the ESP or XBOOTLDR partition holding the systemd-boot entries
+    #[serde(rename = "loader-root", default = "bootloader::default_loader_root")]
+    pub loader_root: PathBuf,
+    /// Put the EFI boot entry of a new kernel first in the boot order instead of last
+    #[serde(rename = "efi-boot-first", default = "default_true")]
+    pub efi_boot_first: bool,
+    /// Additional boot options offered by rEFInd besides the standard and single-user ones
+    #[serde(rename = "refind-variants", default)]
+    pub refind_variants: Vec<RefindVariant>,
+    /// Install the kernel by copying it or through the system's `installkernel`
+    #[serde(rename = "install-mode", default)]
+    pub install_mode: InstallMode,
+    /// Executables run around the steps of a build
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Run the `/etc/kernel/preinst.d` and `/etc/kernel/postinst.d` hooks around the install
+    #[serde(rename = "kernel-hooks", default)]
+    pub kernel_hooks: bool,
+    /// Directory of the state database
+    #[serde(rename = "state-dir", default = "state::default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Copy the installed kernel and initramfs into the backup directory before overwriting them
+    #[serde(rename = "backup", default)]
+    pub backup: bool,
+    /// Directory of the backups, one subdirectory per kernel release
+    #[serde(rename = "backup-dir", default = "default_backup_dir")]
+    pub backup_dir: PathBuf,
+    /// Number of backups kept, older ones are removed when a new one is taken
+    #[serde(rename = "backup-keep", default = "default_backup_keep")]
+    pub backup_keep: usize,
+    /// Take a snapper snapshot before installing when the root filesystem is btrfs with snapper
+    #[serde(rename = "snapshot", default = "default_true")]
+    pub snapshot: bool,
+    /// Clone the ZFS root dataset into a boot environment before installing modules and boot
+    /// the new kernel's entry into it
+    #[serde(rename = "boot-environment", default)]
+    pub boot_environment: bool,
+    /// Category, name and `PKGDIR` of the packages created by `binpkg`
+    #[serde(rename = "binpkg", default)]
+    pub binpkg: Binpkg,
+    /// Remote hosts the built kernel is deployed to over SSH
+    #[serde(rename = "deploy")]
+    pub deploy: Option<Deploy>,
+    /// Number of newest kernels `prune` keeps
+    #[serde(rename = "prune-keep", default = "default_prune_keep")]
+    pub prune_keep: usize,
+    /// Install `System.map-<release>` next to the kernel image
+    #[serde(rename = "install-system-map", default)]
+    pub install_system_map: bool,
+    /// Install the used kernel config as `config-<release>` next to the kernel image
+    #[serde(rename = "install-config", default)]
+    pub install_config: bool,
+    /// Point `/usr/src/linux` to the selected tree, disable to manage the symlink yourself
+    #[serde(rename = "manage-src-symlink", default = "default_true")]
+    pub manage_src_symlink: bool,
+    /// Switch `/usr/src/linux` with `eselect kernel set` instead of replacing the symlink directly
+    #[serde(rename = "use-eselect", default)]
+    pub use_eselect: bool,
+    /// Modules below `/lib/modules/<release>` signed with the module signing key, e.g.
+    /// `video/nvidia*.ko`
+    #[serde(rename = "module-sign-globs", default)]
+    pub module_sign_globs: Vec<String>,
+    /// Private module signing key, defaults to `certs/signing_key.pem` of the kernel tree
+    #[serde(rename = "module-signing-key")]
+    pub module_signing_key: Option<PathBuf>,
+    /// Module signing certificate, defaults to `certs/signing_key.x509` of the kernel tree
+    #[serde(rename = "module-signing-cert")]
+    pub module_signing_cert: Option<PathBuf>,
+    /// Tool used to sign the kernel image and UKI for Secure Boot
+    #[serde(rename = "secure-boot-signer", default)]
+    pub secure_boot_signer: Signer,
+    /// Private key used by `sbsign` to sign the kernel image and UKI for Secure Boot
+    #[serde(rename = "secure-boot-key")]
+    pub secure_boot_key: Option<PathBuf>,
+    /// Certificate matching the Secure Boot key
+    #[serde(rename = "secure-boot-cert")]
+    pub secure_boot_cert: Option<PathBuf>,
+}
+
+fn default_efi_label() -> String {
+    String::from("Gentoo")
+}
+
+/// Additional location for the boot artifacts
+#[derive(Debug, Deserialize, Clone)]
+pub struct Destination {
+    /// Path of the kernel image
+    #[serde(rename = "kernel")]
+    pub kernel: PathBuf,
+    /// Path of the initramfs
+    #[serde(rename = "initramfs")]
+    pub initramfs: Option<PathBuf>,
+    /// Boot loader set up for the copies, independent of the top level `bootloader`
+    #[serde(rename = "bootloader")]
+    pub bootloader: Option<Bootloader>,
+    /// ESP or XBOOTLDR mount point for systemd-boot, defaults to the mount point holding the
+    /// kernel copy
+    #[serde(rename = "loader-root")]
+    pub loader_root: Option<PathBuf>,
+    /// GRUB config to regenerate, defaults to the top level `grub-config`
+    #[serde(rename = "grub-config")]
+    pub grub_config: Option<PathBuf>,
+    /// Label of EFI boot entries, defaults to `<efi-label>-<N>` for the N-th destination
+    #[serde(rename = "efi-label")]
+    pub efi_label: Option<String>,
+}
+
+fn default_source_include() -> Vec<String> {
+    vec!["linux-*".to_string()]
+}
+
+fn default_prune_keep() -> usize {
+    2
+}
+
+fn default_backup_dir() -> PathBuf {
+    state::default_state_dir().join("backups")
+}
+
+fn default_backup_keep() -> usize {
+    3
+}
+
+fn default_old_suffix() -> String {
+    String::from("old")
+}
+
+/// Named set of overrides selected with `--flavor`, e.g. for a realtime kernel
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Flavor {
+    /// dracut configuration directory used instead of the global one
+    #[serde(rename = "dracut-confdir")]
+    pub dracut_confdir: Option<PathBuf>,
+    /// Suffix appended to the kernel release like `-rt`, so the flavor gets its own module
+    /// directory and the same tree can be built once per flavor
+    pub localversion: Option<String>,
+    /// Kernel config used instead of the global one
+    #[serde(rename = "kernel-config")]
+    pub kernel_config: Option<PathBuf>,
+}
+
+fn default_initramfs_size_warning() -> u64 {
+    100
+}
+
+/// Detects the boot layout with the programs run by `runner` and writes a config with suggested
+/// artifact paths to `path`. An existing config is never overwritten, the suggestion is only
+/// printed then.
+///
+/// # Errors
+///
+/// - Failing to write the config
+pub fn init_config(runner: &dyn CommandRunner, path: &Path) -> Result<(), BuilderErr> {
+    let layout = layout::BootLayout::detect(runner);
+    let boot = layout.boot_dir();
+    match &layout.esp {
+        Some(esp) => println!("Detected EFI system partition at {}", esp.display()),
+        None => println!("No EFI system partition detected"),
+    }
+    if let Some(boot) = &layout.boot {
+        println!("Detected separate boot partition at {}", boot.display());
+    }
+
+    let mut config = format!(
+        "kernel = \"{}\"\ninitramfs = \"{}\"\nkernel-config = \"/usr/src/.config\"\n\
+         kernel-src = \"/usr/src\"\nkeep-old = true\n",
+        boot.join("vmlinuz-{version}").display(),
+        boot.join("initramfs-{version}.img").display(),
+    );
+    if let (true, Some(esp)) = (layout.uefi, &layout.esp) {
+        config.push_str(&format!(
+            "# uki = \"{}\"\n",
+            esp.join("EFI/Linux/gentoo-{version}.efi").display()
+        ));
+    }
+    if let Some(bootloader) = layout.bootloader() {
+        config.push_str(&format!("bootloader = \"{}\"\n", bootloader.config_name()));
+    }
+
+    if path.exists() {
+        println!(
+            "{} already exists, suggested config:\n\n{config}",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(BuilderErr::KernelBuildFail)?;
+    }
+    std::fs::write(path, &config).map_err(BuilderErr::KernelBuildFail)?;
+    println!("Wrote {}:\n\n{config}", path.display());
+
+    Ok(())
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UkiGenerator {
+    /// `dracut --uefi`, only available with the `dracut` feature
+    #[cfg(feature = "dracut")]
+    Dracut,
+    /// `ukify build` from systemd
+    Ukify,
+}
+
+impl Default for UkiGenerator {
+    fn default() -> Self {
+        #[cfg(feature = "dracut")]
+        return Self::Dracut;
+        #[cfg(not(feature = "dracut"))]
+        return Self::Ukify;
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InitramfsCompression {
+    Zstd,
+    Xz,
+    Lz4,
+    Gzip,
+}
+
+impl InitramfsCompression {
+    #[must_use]
+    pub fn dracut_flag(self) -> &'static str {
+        match self {
+            Self::Zstd => "--zstd",
+            Self::Xz => "--xz",
+            Self::Lz4 => "--lz4",
+            Self::Gzip => "--gzip",
+        }
+    }
+}
+
+/// How the built kernel image gets installed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallMode {
+    /// Copy the image to the configured kernel path
+    #[default]
+    Copy,
+    /// Call the system's `installkernel` after the modules are installed
+    Installkernel,
+    /// Call systemd's `kernel-install add` after the modules are installed
+    #[serde(rename = "kernel-install")]
+    KernelInstall,
+}
+
+#[cfg(test)]
+impl KBConfig {
+    /// Minimal config with all paths below `dir`
+    pub(crate) fn for_test(dir: &Path) -> Self {
+        toml::from_str(&format!(
+            "kernel = \"{0}/vmlinuz-{{version}}\"\n\
+             kernel-config = \"{0}/config\"\n\
+             kernel-src = \"{0}/src\"\n\
+             state-dir = \"{0}/state\"\n",
+            dir.display()
+        ))
+        .expect("valid test config")
+    }
+}
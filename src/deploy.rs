@@ -0,0 +1,81 @@
+use crate::{CommandRunner, Invocation};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Remote hosts that receive the built kernel after a successful local build
+#[derive(Debug, Deserialize, Clone)]
+pub struct Deploy {
+    /// SSH targets like `root@host`
+    pub hosts: Vec<String>,
+    /// Install the kernel with `installkernel` on the host instead of copying it to the same path
+    #[serde(default)]
+    pub installkernel: bool,
+    /// Run `depmod` on the host after unpacking the modules
+    #[serde(default = "default_depmod")]
+    pub depmod: bool,
+}
+
+fn default_depmod() -> bool {
+    true
+}
+
+/// Creates a private staging directory on the host with `mktemp -d`
+pub fn staging_dir(runner: &dyn CommandRunner, host: &str) -> io::Result<PathBuf> {
+    let dir = runner
+        .run(
+            &Invocation::new("ssh")
+                .arg(host)
+                .arg("mktemp -d /var/tmp/kernel-builder-XXXXXXXXXX"),
+        )?
+        .trim()
+        .to_string();
+    if !dir.starts_with("/var/tmp/kernel-builder-") {
+        return Err(io::Error::other(format!(
+            "unexpected staging directory `{dir}`"
+        )));
+    }
+
+    Ok(PathBuf::from(dir))
+}
+
+/// Packs `/lib/modules/<kver>` into a tarball in `dir`
+pub fn modules_tarball(runner: &dyn CommandRunner, kver: &str, dir: &Path) -> io::Result<PathBuf> {
+    let tarball = dir.join(format!("modules-{kver}.tar.gz"));
+    runner.run(&Invocation::new("tar").arg("-czf").arg(&tarball).args([
+        "-C",
+        "/lib/modules",
+        kver,
+    ]))?;
+
+    Ok(tarball)
+}
+
+/// Copies files into `dir` on the host
+pub fn rsync(
+    runner: &dyn CommandRunner,
+    host: &str,
+    files: &[&Path],
+    dir: &Path,
+) -> io::Result<()> {
+    runner
+        .run(
+            &Invocation::new("rsync")
+                .args(["--archive", "--compress"])
+                .args(files.iter().copied())
+                .arg(format!("{host}:{}/", dir.display())),
+        )
+        .map(drop)
+}
+
+/// Runs a shell script on the host
+pub fn ssh(runner: &dyn CommandRunner, host: &str, script: &str) -> io::Result<()> {
+    runner
+        .run(&Invocation::new("ssh").arg(host).arg(script))
+        .map(drop)
+}
+
+/// Quotes a path for the remote shell
+pub fn quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', r"'\''"))
+}
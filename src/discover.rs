@@ -0,0 +1,558 @@
+use crate::{git, pattern, releases, state, version, BuilderErr, CommandRunner, KernelBuilder};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionEntry {
+    pub path: PathBuf,
+    pub version_string: String,
+}
+
+/// Which entries of a source root are offered for selection: names starting with `linux-` that
+/// match one of the include globs, none of the exclude globs and none of the excluded versions
+#[derive(Debug, Clone, Copy)]
+pub struct SourceFilter<'a> {
+    pub include: &'a [String],
+    pub exclude: &'a [String],
+    /// Versions or series like `6.12.3` or `6.6`
+    pub exclude_versions: &'a [String],
+}
+
+impl SourceFilter<'_> {
+    pub fn matches(&self, name: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| pattern::glob_match(pattern, name))
+        };
+
+        name.starts_with("linux-")
+            && matches(self.include)
+            && !matches(self.exclude)
+            && !self
+                .exclude_versions
+                .iter()
+                .any(|spec| version::matches(name, spec))
+    }
+}
+
+/// Name of a git tree in the selection, `linux-` followed by `git describe`
+pub fn git_version_string(runner: &dyn CommandRunner, path: &Path) -> String {
+    format!(
+        "linux-{}",
+        git::describe(runner, path).unwrap_or_else(|| "git".to_string())
+    )
+}
+
+/// Source trees in `roots` and the git trees, newest first.
+///
+/// Every directory entry of a root is a candidate. It is listed when its name starts with
+/// `linux-`, matches the filter and it is a directory or a symlink resolving to one. Symlinked
+/// trees are listed under the name of the link, a tree reachable through several entries, like a
+/// link in one root to a tree in another one, only once under the entry that is not a symlink.
+/// Missing or unreadable roots are skipped. Git trees are named after `git describe`, trees
+/// without a parsable version sort last.
+pub fn discover(
+    runner: &dyn CommandRunner,
+    roots: &[PathBuf],
+    filter: &SourceFilter,
+    git_trees: &[PathBuf],
+) -> Vec<VersionEntry> {
+    let mut candidates: Vec<(bool, VersionEntry)> = roots
+        .iter()
+        .filter_map(|root| std::fs::read_dir(root).ok())
+        .flat_map(|entries| entries.filter_map(Result::ok))
+        .filter_map(|entry| {
+            let version_string = entry.file_name().to_str()?.to_string();
+            let path = entry.path();
+            (filter.matches(&version_string) && path.is_dir()).then(|| {
+                (
+                    path.is_symlink(),
+                    VersionEntry {
+                        path,
+                        version_string,
+                    },
+                )
+            })
+        })
+        .collect();
+    // real directories win over links to them
+    candidates.sort_by_key(|(is_symlink, _)| *is_symlink);
+
+    let mut seen = HashSet::new();
+    let mut versions: Vec<VersionEntry> = candidates
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| {
+            seen.insert(
+                entry
+                    .path
+                    .canonicalize()
+                    .unwrap_or_else(|_| entry.path.clone()),
+            )
+        })
+        .collect();
+    versions.extend(git_trees.iter().map(|path| VersionEntry {
+        path: path.clone(),
+        version_string: git_version_string(runner, path),
+    }));
+    versions.sort_by_cached_key(|entry| {
+        std::cmp::Reverse((
+            version::KernelVersion::parse(&entry.version_string),
+            entry.version_string.clone(),
+        ))
+    });
+
+    versions
+}
+
+impl KernelBuilder {
+    /// Release of the kernel built from a tree as used for `/lib/modules` and `uname -r`. It
+    /// differs from the directory name for release candidates, e.g. `linux-6.13-rc3` builds
+    /// `6.13.0-rc3`, and for trees with a local version, so it is taken from the kernel's own
+    /// `make kernelrelease`.
+    pub(crate) fn kernel_release(&self, path: &Path, version_string: &str) -> String {
+        self.run_output(&self.make().current_dir(path).args(["-s", "kernelrelease"]))
+            .ok()
+            .filter(|output| output.success)
+            .map(|output| output.stdout.trim().to_string())
+            .or_else(|| {
+                std::fs::read_to_string(path.join("include/config/kernel.release"))
+                    .ok()
+                    .map(|release| release.trim().to_string())
+            })
+            .filter(|release| !release.is_empty() && !release.contains(char::is_whitespace))
+            .unwrap_or_else(|| {
+                version_string
+                    .strip_prefix("linux-")
+                    .unwrap_or(version_string)
+                    .to_string()
+            })
+    }
+
+    /// Checks out the tag or branch to build in a git tree, given on the command line or picked
+    /// from the local branches and newest tags.
+    pub(crate) fn checkout_git_ref(
+        &self,
+        version_entry: VersionEntry,
+        reference: Option<&str>,
+    ) -> Result<Option<VersionEntry>, BuilderErr> {
+        let path = version_entry.path;
+        let reference = match reference {
+            Some(reference) => reference.to_string(),
+            None => {
+                let mut refs = git::refs(self, &path).map_err(BuilderErr::GitError)?;
+                let current = version_entry.version_string.clone();
+                refs.insert(0, format!("{current} (keep checked out)"));
+                let Some(selection) =
+                    self.prompter
+                        .select_version("Pick tag or branch to build", &refs, 0)?
+                else {
+                    return Ok(None);
+                };
+                if selection == 0 {
+                    return Ok(Some(VersionEntry {
+                        path,
+                        version_string: current,
+                    }));
+                }
+                refs.swap_remove(selection)
+            }
+        };
+
+        git::checkout(self, &path, &reference).map_err(BuilderErr::GitError)?;
+        println!("Checked out {reference}");
+
+        Ok(Some(VersionEntry {
+            version_string: git_version_string(self, &path),
+            path,
+        }))
+    }
+
+    /// `kernel-src` followed by the further `source-roots`
+    fn source_roots(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.config.kernel_src).chain(&self.config.source_roots)
+    }
+
+    /// Source tree of a kernel release in any of the source roots
+    pub(crate) fn source_tree(&self, kver: &str) -> Option<PathBuf> {
+        self.source_roots()
+            .map(|root| root.join(format!("linux-{kver}")))
+            .find(|path| path.is_dir())
+    }
+
+    /// Source trees found on first use, so builders that never look at them skip the scan
+    pub(crate) fn available_versions(&self) -> &[VersionEntry] {
+        self.versions.get_or_init(|| self.discover_versions())
+    }
+
+    /// Source trees in all source roots and git trees, newest first
+    pub(crate) fn discover_versions(&self) -> Vec<VersionEntry> {
+        let roots: Vec<PathBuf> = self.source_roots().cloned().collect();
+        let filter = SourceFilter {
+            include: &self.config.source_include,
+            exclude: &self.config.source_exclude,
+            exclude_versions: &self.config.exclude_versions,
+        };
+
+        discover(self, &roots, &filter, &self.config.git_trees)
+    }
+
+    /// Newest tree of a version like `6.12.8` or a full tree name without `linux-` prefix
+    pub(crate) fn find_source<'a>(
+        versions: &'a [VersionEntry],
+        version: &str,
+    ) -> Option<&'a VersionEntry> {
+        versions.iter().find(|entry| {
+            entry
+                .version_string
+                .strip_prefix("linux-")
+                .is_some_and(|name| name == version || name.starts_with(&format!("{version}-")))
+        })
+    }
+
+    /// Aliases of the config and the ones defined with `alias`, which take precedence
+    pub(crate) fn aliases(&self) -> HashMap<String, String> {
+        let mut aliases = self.config.aliases.clone();
+        if let Ok(state) = self.load_state() {
+            aliases.extend(state.aliases);
+        }
+
+        aliases
+    }
+
+    /// Kernel release of the tree an alias names, other versions are returned as they are
+    pub(crate) fn resolve_kver(&self, kver: &str) -> String {
+        self.aliases()
+            .get(kver)
+            .and_then(|target| {
+                Self::find_source(
+                    self.available_versions(),
+                    target.strip_prefix("linux-").unwrap_or(target),
+                )
+            })
+            .map_or_else(
+                || kver.to_string(),
+                |entry| self.kernel_release(&entry.path, &entry.version_string),
+            )
+    }
+
+    /// Lists, defines or removes aliases of source trees. Aliases are stored in the state
+    /// database, aliases of the config can be overridden but not removed.
+    ///
+    /// # Errors
+    ///
+    /// - Alias pointing to a source tree that does not exist
+    /// - Failing to update the state database
+    pub fn alias(
+        &self,
+        name: Option<&str>,
+        target: Option<&str>,
+        remove: bool,
+    ) -> Result<(), BuilderErr> {
+        let Some(name) = name else {
+            let mut aliases: Vec<_> = self.aliases().into_iter().collect();
+            aliases.sort();
+            for (name, target) in aliases {
+                println!("{name:<16} {target}");
+            }
+            return Ok(());
+        };
+
+        let mut state = self.load_state()?;
+        if remove {
+            if state.aliases.remove(name).is_none() {
+                println!("No alias {name} defined with `alias`");
+                return Ok(());
+            }
+        } else if let Some(target) = target {
+            let version = target.strip_prefix("linux-").unwrap_or(target);
+            let Some(entry) = Self::find_source(self.available_versions(), version) else {
+                return Err(BuilderErr::InvalidSourceTree(format!(
+                    "no source tree for {target}"
+                )));
+            };
+            state
+                .aliases
+                .insert(name.to_string(), entry.version_string.clone());
+        } else {
+            match self.aliases().get(name) {
+                Some(target) => println!("{target}"),
+                None => println!("No alias {name}"),
+            }
+            return Ok(());
+        }
+
+        self.save_state(&state)
+    }
+
+    /// Release information of kernel.org if enabled, a failed download only warns
+    pub(crate) fn releases(&self) -> Option<releases::Releases> {
+        if !self.config.check_releases {
+            return None;
+        }
+
+        self.progress
+            .on_step_start("Fetching kernel.org releases...");
+        let releases = releases::Releases::fetch(self);
+        self.progress.on_step_end(true, "");
+        releases
+            .inspect_err(|err| self.warn(format!("could not fetch kernel.org releases: {err}")))
+            .ok()
+    }
+
+    /// Source trees that appeared since the last build, nothing is new before the first build.
+    pub(crate) fn new_sources(&self, state: &state::State) -> Vec<String> {
+        let Some(known) = &state.known_sources else {
+            return vec![];
+        };
+
+        self.available_versions()
+            .iter()
+            .map(|entry| entry.version_string.clone())
+            .filter(|name| !known.contains(name))
+            .collect()
+    }
+
+    /// Kernel release of the tree `/usr/src/linux` points to
+    pub(crate) fn linked_kernel(&self) -> Option<String> {
+        self.config
+            .kernel_src
+            .join("linux")
+            .read_link()
+            .ok()
+            .and_then(|target| {
+                target.file_name().and_then(|name| {
+                    name.to_string_lossy()
+                        .strip_prefix("linux-")
+                        .map(String::from)
+                })
+            })
+    }
+
+    /// Kernel releases that have modules installed in `/lib/modules`, oldest first
+    #[must_use]
+    pub fn installed_kernels() -> Vec<String> {
+        let mut kernels = std::fs::read_dir(Self::MODULES_PATH)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.path().is_dir())
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        sort_releases(&mut kernels);
+
+        kernels
+    }
+}
+
+/// Sorts kernel releases by version, oldest first, so `6.9.1` comes before `6.12.1`. Names that
+/// are no version go first.
+fn sort_releases(kernels: &mut [String]) {
+    kernels.sort_by(|a, b| {
+        version::KernelVersion::parse(a)
+            .cmp(&version::KernelVersion::parse(b))
+            .then_with(|| a.cmp(b))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+    use std::os::unix::fs::symlink;
+
+    /// Fresh directory for a fixture layout, removed on drop
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "kernel-builder-discover-{}-{name}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn tree(&self, path: &str) -> PathBuf {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("Makefile"), "VERSION = 6\nPATCHLEVEL = 12\n").unwrap();
+            path
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn filter<'a>(
+        include: &'a [String],
+        exclude: &'a [String],
+        versions: &'a [String],
+    ) -> SourceFilter<'a> {
+        SourceFilter {
+            include,
+            exclude,
+            exclude_versions: versions,
+        }
+    }
+
+    fn names(versions: &[VersionEntry]) -> Vec<&str> {
+        versions
+            .iter()
+            .map(|entry| entry.version_string.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn lists_matching_directories_newest_first() {
+        let fixture = Fixture::new("newest-first");
+        fixture.tree("linux-6.6.30-gentoo");
+        fixture.tree("linux-6.12.8-gentoo");
+        fixture.tree("linux-6.12.8-gentoo-r1");
+        fixture.tree("linux-6.13-rc3");
+        fixture.tree("linux-6.13");
+        fixture.tree("linux-next");
+        fixture.tree("modules-6.12.8");
+        std::fs::write(fixture.0.join("linux-6.14.tar.xz"), "").unwrap();
+        symlink("linux-6.12.8-gentoo", fixture.0.join("linux")).unwrap();
+
+        let include = ["linux-*".to_string()];
+        let versions = discover(
+            &RecordingRunner::default(),
+            std::slice::from_ref(&fixture.0),
+            &filter(&include, &[], &[]),
+            &[],
+        );
+
+        assert_eq!(
+            names(&versions),
+            [
+                "linux-6.13",
+                "linux-6.13-rc3",
+                "linux-6.12.8-gentoo-r1",
+                "linux-6.12.8-gentoo",
+                "linux-6.6.30-gentoo",
+                "linux-next",
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_symlinks_to_trees_elsewhere() {
+        let fixture = Fixture::new("symlinks");
+        let root = fixture.0.join("src");
+        std::fs::create_dir_all(&root).unwrap();
+        let elsewhere = fixture.tree("build/linux-6.12.8-gentoo");
+        symlink(&elsewhere, root.join("linux-6.12.8-gentoo")).unwrap();
+        symlink(root.join("missing"), root.join("linux-6.1.0")).unwrap();
+
+        let include = ["linux-*".to_string()];
+        let versions = discover(
+            &RecordingRunner::default(),
+            std::slice::from_ref(&root),
+            &filter(&include, &[], &[]),
+            &[],
+        );
+
+        assert_eq!(
+            versions,
+            [VersionEntry {
+                path: root.join("linux-6.12.8-gentoo"),
+                version_string: "linux-6.12.8-gentoo".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn lists_trees_reachable_from_several_roots_once() {
+        let fixture = Fixture::new("dedup");
+        let tree = fixture.tree("a/linux-6.12.8-gentoo");
+        fixture.tree("b/linux-6.6.30-gentoo");
+        symlink(&tree, fixture.0.join("b/linux-6.12.8-gentoo")).unwrap();
+
+        let include = ["linux-*".to_string()];
+        let roots = [
+            fixture.0.join("b"),
+            fixture.0.join("a"),
+            fixture.0.join("missing"),
+        ];
+        let versions = discover(
+            &RecordingRunner::default(),
+            &roots,
+            &filter(&include, &[], &[]),
+            &[],
+        );
+
+        assert_eq!(
+            names(&versions),
+            ["linux-6.12.8-gentoo", "linux-6.6.30-gentoo"]
+        );
+        assert_eq!(versions[0].path, tree);
+    }
+
+    #[test]
+    fn applies_include_exclude_and_version_filters() {
+        let fixture = Fixture::new("filters");
+        fixture.tree("linux-6.12.8-gentoo");
+        fixture.tree("linux-6.12.3-gentoo");
+        fixture.tree("linux-6.12.8-zen");
+        fixture.tree("linux-6.6.30-gentoo");
+        fixture.tree("linux-6.6.30-rt");
+
+        let include = ["linux-*-gentoo".to_string(), "linux-*-rt".to_string()];
+        let exclude = ["linux-*-rt".to_string()];
+        let versions = ["6.12.3".to_string()];
+        let found = discover(
+            &RecordingRunner::default(),
+            std::slice::from_ref(&fixture.0),
+            &filter(&include, &exclude, &versions),
+            &[],
+        );
+
+        assert_eq!(
+            names(&found),
+            ["linux-6.12.8-gentoo", "linux-6.6.30-gentoo"]
+        );
+    }
+
+    #[test]
+    fn requires_linux_prefix() {
+        let include = ["*".to_string()];
+        let filter = filter(&include, &[], &[]);
+
+        assert!(filter.matches("linux-6.12.8"));
+        assert!(!filter.matches("linux"));
+        assert!(!filter.matches("kernel-6.12.8"));
+    }
+
+    #[test]
+    fn sorts_installed_releases_by_version() {
+        let mut kernels = [
+            "6.9.1-gentoo",
+            "6.12.1-gentoo",
+            "6.12.1-gentoo-dist",
+            "6.6.30-gentoo",
+        ]
+        .map(String::from)
+        .to_vec();
+        sort_releases(&mut kernels);
+
+        assert_eq!(
+            kernels,
+            [
+                "6.6.30-gentoo",
+                "6.9.1-gentoo",
+                "6.12.1-gentoo",
+                "6.12.1-gentoo-dist"
+            ]
+        );
+    }
+}
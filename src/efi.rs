@@ -0,0 +1,229 @@
+use crate::{mounts, CommandRunner, Invocation};
+use std::path::{Path, PathBuf};
+
+/// Location of a file on the EFI system partition as needed by `efibootmgr`
+#[derive(Debug, Clone)]
+pub struct EspLocation {
+    pub disk: PathBuf,
+    pub partition: u32,
+    /// GPT partition GUID, `None` if udev does not know it
+    pub partuuid: Option<String>,
+    /// Path relative to the partition root with backslashes, e.g. `\EFI\Gentoo\vmlinuz.efi`
+    pub loader: String,
+}
+
+impl EspLocation {
+    /// Resolves the disk, partition number and loader path of a file on a mounted partition.
+    pub fn locate(file: &Path) -> Option<Self> {
+        let mount = mounts::find(file)?;
+        let relative = file.strip_prefix(&mount.mountpoint).ok()?;
+        let loader = format!("\\{}", relative.to_string_lossy().replace('/', "\\"));
+
+        let device = mount.device.canonicalize().ok()?;
+        let name = device.file_name()?.to_string_lossy().to_string();
+        let sys = Path::new("/sys/class/block").join(&name);
+        let partition = std::fs::read_to_string(sys.join("partition"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        let disk = sys
+            .canonicalize()
+            .ok()?
+            .parent()?
+            .file_name()
+            .map(|disk| Path::new("/dev").join(disk))?;
+
+        let partuuid = std::fs::read_dir("/dev/disk/by-partuuid")
+            .ok()?
+            .filter_map(Result::ok)
+            .find(|link| link.path().canonicalize().is_ok_and(|path| path == device))
+            .map(|link| link.file_name().to_string_lossy().to_string());
+
+        Some(Self {
+            disk,
+            partition,
+            partuuid,
+            loader,
+        })
+    }
+
+    /// Whether a boot entry points to a file on this partition
+    pub fn contains(&self, entry: &BootEntry) -> bool {
+        self.partuuid
+            .as_ref()
+            .zip(entry.partuuid.as_ref())
+            .is_some_and(|(esp, entry)| esp.eq_ignore_ascii_case(entry))
+    }
+}
+
+/// Boot entry listed by `efibootmgr`
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    /// Hexadecimal boot number, e.g. `0003`
+    pub number: String,
+    pub label: String,
+    /// GPT partition GUID of the `HD(...)` device path of the entry
+    pub partuuid: Option<String>,
+    /// Loader path of the entry if it points to a file, e.g. `\EFI\Gentoo\vmlinuz.efi`
+    pub loader: Option<String>,
+}
+
+fn efibootmgr(runner: &dyn CommandRunner, args: &[&str]) -> std::io::Result<String> {
+    runner.run(&Invocation::new("efibootmgr").args(args))
+}
+
+/// Lists the EFI boot entries
+pub fn entries(runner: &dyn CommandRunner) -> std::io::Result<Vec<BootEntry>> {
+    efibootmgr(runner, &["--verbose"]).map(|output| parse_entries(&output))
+}
+
+/// Boot entries in the output of `efibootmgr --verbose`
+fn parse_entries(output: &str) -> Vec<BootEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Boot")?;
+            let (number, rest) = rest.split_at_checked(4)?;
+            if !number.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let rest = rest.trim_start_matches('*').trim_start();
+            let mut fields = rest.split('\t');
+            let label = fields.next().unwrap_or(rest).trim();
+            let device_path = fields.next().unwrap_or_default();
+            // HD(<partition>,GPT,<guid>,<start>,<size>)
+            let partuuid = device_path
+                .split_once("HD(")
+                .and_then(|(_, hd)| hd.split(',').nth(2))
+                .map(|guid| guid.trim_end_matches(')').to_string());
+            let loader = device_path
+                .split_once("File(")
+                .and_then(|(_, file)| file.split_once(')'))
+                .map(|(file, _)| file.to_string());
+            Some(BootEntry {
+                number: number.to_string(),
+                label: label.to_string(),
+                partuuid,
+                loader,
+            })
+        })
+        .collect()
+}
+
+/// Creates a new boot entry, `efibootmgr` puts it first in the boot order.
+pub fn create_entry(
+    runner: &dyn CommandRunner,
+    location: &EspLocation,
+    label: &str,
+    cmdline: Option<&str>,
+) -> std::io::Result<()> {
+    let mut create = Invocation::new("efibootmgr")
+        .args(["--create", "--disk"])
+        .arg(&location.disk)
+        .args(["--part", &location.partition.to_string()])
+        .args(["--label", label])
+        .args(["--loader", &location.loader]);
+    if let Some(cmdline) = cmdline {
+        create = create.args(["--unicode", cmdline]);
+    }
+
+    runner.run(&create).map(drop)
+}
+
+/// Deletes the boot entry with the given boot number
+pub fn delete_entry(runner: &dyn CommandRunner, number: &str) -> std::io::Result<()> {
+    efibootmgr(runner, &["--delete-bootnum", "--bootnum", number]).map(|_| ())
+}
+
+/// Reads the current boot order as list of boot numbers
+pub fn boot_order(runner: &dyn CommandRunner) -> std::io::Result<Vec<String>> {
+    efibootmgr(runner, &[]).map(|output| parse_boot_order(&output))
+}
+
+fn parse_boot_order(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("BootOrder:"))
+        .map(|order| {
+            order
+                .trim()
+                .split(',')
+                .filter(|number| !number.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Replaces the boot order
+pub fn set_boot_order(runner: &dyn CommandRunner, order: &[String]) -> std::io::Result<()> {
+    efibootmgr(runner, &["--bootorder", &order.join(",")]).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "\
+BootCurrent: 0001
+Timeout: 1 seconds
+BootOrder: 0001,0000,0002
+Boot0000* Gentoo (previous)\tHD(1,GPT,5e3a7c1f-9b2d-4c6e-8f0a-1b2c3d4e5f60,0x800,0x100000)/File(\\EFI\\Gentoo\\vmlinuz.old.efi)
+Boot0001* Gentoo\tHD(1,GPT,5e3a7c1f-9b2d-4c6e-8f0a-1b2c3d4e5f60,0x800,0x100000)/File(\\EFI\\Gentoo\\vmlinuz.efi)dracut
+Boot0002  UEFI OS\tPciRoot(0x0)/Pci(0x1d,0x0)/NVMe(0x1,00-00-00-00-00-00-00-00)
+";
+
+    #[test]
+    fn parses_verbose_boot_entries() {
+        let entries = parse_entries(OUTPUT);
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.number.as_str(),
+                    entry.label.as_str(),
+                    entry.loader.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "0000",
+                    "Gentoo (previous)",
+                    Some("\\EFI\\Gentoo\\vmlinuz.old.efi")
+                ),
+                ("0001", "Gentoo", Some("\\EFI\\Gentoo\\vmlinuz.efi")),
+                ("0002", "UEFI OS", None),
+            ]
+        );
+        assert_eq!(
+            entries[0].partuuid.as_deref(),
+            Some("5e3a7c1f-9b2d-4c6e-8f0a-1b2c3d4e5f60")
+        );
+        assert_eq!(entries[2].partuuid, None);
+    }
+
+    #[test]
+    fn parses_the_boot_order() {
+        assert_eq!(parse_boot_order(OUTPUT), ["0001", "0000", "0002"]);
+        assert!(parse_boot_order("BootCurrent: 0001\n").is_empty());
+    }
+
+    #[test]
+    fn esp_contains_entries_with_its_partition_guid() {
+        let esp = EspLocation {
+            disk: PathBuf::from("/dev/nvme0n1"),
+            partition: 1,
+            partuuid: Some("5E3A7C1F-9B2D-4C6E-8F0A-1B2C3D4E5F60".to_string()),
+            loader: String::new(),
+        };
+        let entries = parse_entries(OUTPUT);
+
+        assert!(esp.contains(&entries[0]));
+        assert!(!esp.contains(&entries[2]));
+    }
+}
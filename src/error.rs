@@ -17,6 +17,74 @@ pub enum BuilderErr {
     ConfigError(#[from] ConfigError),
     #[error("Could not create prompt: {0}")]
     PromptError(dialoguer::Error),
+    #[error("Installing kernel sources failed: {0}")]
+    EmergeFailed(String),
+    #[error("Fetching kernel sources failed: {0}")]
+    FetchError(String),
+    #[error("Not a usable kernel source tree: {0}")]
+    InvalidSourceTree(String),
+    #[error("Kernel cannot mount the ZFS root: {0}")]
+    ZfsError(String),
+    #[error("Applying patch failed: {0}")]
+    PatchError(String),
+    #[error("git failed: {0}")]
+    GitError(std::io::Error),
     #[error("Error while starting `menuconfig`")]
     MenuconfigError,
+    #[error("Error generating unified kernel image: {0}")]
+    UkiError(std::io::Error),
+    #[error("Error handling kernel command line: {0}")]
+    CmdlineError(std::io::Error),
+    #[error("Could not set plymouth theme `{0}`")]
+    PlymouthThemeError(String),
+    #[error("Generated initramfs is likely not bootable:\n  - {0}")]
+    InitramfsIncomplete(String),
+    #[error("Kernel cannot boot without initramfs: {}", .0.join(", "))]
+    RootNotBuiltin(Vec<String>),
+    #[error("Could not determine the running kernel")]
+    RunningKernelUnknown,
+    #[error("No modules installed for kernel {0}")]
+    ModulesMissing(String),
+    #[error("Error generating initramfs: {0}")]
+    InitramfsError(String),
+    #[error("Flavor `{0}` is not defined in the config")]
+    UnknownFlavor(String),
+    #[error("Could not back up previous boot artifact: {0}")]
+    BackupError(std::io::Error),
+    #[error("Could not take snapshot: {0}")]
+    SnapshotError(std::io::Error),
+    #[error("Could not run boot test: {0}")]
+    BootTestError(std::io::Error),
+    #[error("Boot test of the new kernel failed")]
+    BootTestFailed,
+    #[error("Could not reboot: {0}")]
+    RebootError(std::io::Error),
+    #[error("kexec failed: {0}")]
+    KexecError(String),
+    #[error("Kernel archive error: {0}")]
+    ArchiveError(String),
+    #[error("Deploying to some hosts failed: {0:?}")]
+    DeployFailed(Vec<String>),
+    #[error("Installing to some destinations failed: {0:?}")]
+    DestinationsFailed(Vec<std::path::PathBuf>),
+    #[error("EFI stub setup failed: {0}")]
+    EfiStubError(String),
+    #[error("Boot loader update failed: {0}")]
+    BootloaderError(String),
+    #[error("Kernel hook failed: {0}")]
+    HookFailed(String),
+    #[error("Could not access the state database: {0}")]
+    StateError(std::io::Error),
+    #[error("{} is not mounted", .0.display())]
+    NotMounted(std::path::PathBuf),
+    #[error("Secure Boot signing failed: {0}")]
+    SigningError(String),
+    #[error("{0} installed artifacts do not match the recorded checksums")]
+    VerifyFailed(usize),
+    #[error("Could not write the build report: {0}")]
+    ReportError(std::io::Error),
+    #[error("Build was cancelled")]
+    Cancelled,
+    #[error("Unknown step `{0}`")]
+    UnknownStep(String),
 }
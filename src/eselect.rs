@@ -0,0 +1,45 @@
+use crate::{CommandRunner, Invocation};
+use std::io;
+
+/// Symlink target listed by `eselect kernel list`
+#[derive(Debug, Clone)]
+pub struct KernelTarget {
+    pub index: usize,
+    /// Directory name in `/usr/src`, e.g. `linux-6.12.8-gentoo`
+    pub name: String,
+    pub selected: bool,
+}
+
+fn eselect(runner: &dyn CommandRunner, args: &[&str]) -> io::Result<String> {
+    runner.run(&Invocation::new("eselect").arg("kernel").args(args))
+}
+
+/// Parses the targets of `eselect kernel list`
+pub fn list(runner: &dyn CommandRunner) -> io::Result<Vec<KernelTarget>> {
+    Ok(eselect(runner, &["list"])?
+        .lines()
+        .filter_map(|line| {
+            let (index, rest) = line.trim().strip_prefix('[')?.split_once(']')?;
+            let mut fields = rest.split_whitespace();
+            Some(KernelTarget {
+                index: index.parse().ok()?,
+                name: fields.next()?.to_string(),
+                selected: fields.next() == Some("*"),
+            })
+        })
+        .collect())
+}
+
+/// Points `/usr/src/linux` to the target with the given directory name using
+/// `eselect kernel set <N>`.
+pub fn set(runner: &dyn CommandRunner, name: &str) -> io::Result<()> {
+    let target = list(runner)?
+        .into_iter()
+        .find(|target| target.name == name)
+        .ok_or_else(|| io::Error::other(format!("{name} is not listed by eselect kernel")))?;
+    if target.selected {
+        return Ok(());
+    }
+
+    eselect(runner, &["set", &target.index.to_string()]).map(|_| ())
+}
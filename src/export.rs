@@ -0,0 +1,225 @@
+use crate::{archive, install, template, tmp, BuilderErr, Invocation, KernelBuilder};
+use std::path::{Path, PathBuf};
+
+impl KernelBuilder {
+    /// Packs kernel, initramfs, `System.map`, config and modules of an installed kernel into a
+    /// zstd compressed tarball with a manifest of checksums, for archiving or `import` on another
+    /// machine.
+    ///
+    /// # Errors
+    ///
+    /// - Unknown kernel release or missing kernel image
+    /// - Failing to copy, hash or pack the artifacts
+    pub fn export(&self, kver: Option<&str>, output: Option<&Path>) -> Result<(), BuilderErr> {
+        let kver = kver
+            .map(|kver| self.resolve_kver(kver))
+            .or_else(|| self.linked_kernel())
+            .ok_or(BuilderErr::RunningKernelUnknown)?;
+        let output = output.map_or_else(
+            || PathBuf::from(format!("kernel-{kver}.tar.zst")),
+            Path::to_path_buf,
+        );
+        let staging =
+            tmp::TempDir::new("export").map_err(|e| BuilderErr::ArchiveError(e.to_string()))?;
+
+        self.progress.on_step_start(&format!("Exporting {kver}"));
+        self.stage_export(&kver, staging.path())
+            .and_then(|()| archive::pack(self, staging.path(), &output))
+            .map_err(|e| BuilderErr::ArchiveError(e.to_string()))?;
+        self.progress
+            .on_step_end(true, &format!("Exported {kver} to {}", output.display()));
+
+        Ok(())
+    }
+
+    /// Copies the artifacts of `kver` into `staging` and writes the manifest.
+    fn stage_export(&self, kver: &str, staging: &Path) -> std::io::Result<()> {
+        let source = self
+            .source_tree(kver)
+            .unwrap_or_else(|| self.config.kernel_src.join(format!("linux-{kver}")));
+        let kernel = self.kernel_path(kver);
+        let dir = kernel.parent().unwrap_or(Path::new("/boot"));
+        let installed_or_source = |installed: PathBuf, in_tree: &str| {
+            Some(installed)
+                .filter(|path| path.exists())
+                .or_else(|| Some(source.join(in_tree)).filter(|path| path.exists()))
+        };
+
+        let mut artifacts = vec![(archive::ArtifactKind::Kernel, kernel.clone())];
+        artifacts.extend(
+            self.initramfs_path(kver)
+                .filter(|path| !self.initramfs_less() && path.exists())
+                .map(|path| (archive::ArtifactKind::Initramfs, path)),
+        );
+        artifacts.extend(
+            installed_or_source(dir.join(format!("System.map-{kver}")), "System.map")
+                .map(|path| (archive::ArtifactKind::SystemMap, path)),
+        );
+        artifacts.extend(
+            installed_or_source(dir.join(format!("config-{kver}")), ".config")
+                .map(|path| (archive::ArtifactKind::Config, path)),
+        );
+
+        let boot = staging.join("boot");
+        std::fs::create_dir_all(&boot)?;
+        let mut files = vec![];
+        for (kind, path) in artifacts {
+            let name = match kind {
+                archive::ArtifactKind::SystemMap => format!("System.map-{kver}").into(),
+                archive::ArtifactKind::Config => format!("config-{kver}").into(),
+                _ => path.file_name().unwrap_or_default().to_os_string(),
+            };
+            std::fs::copy(&path, boot.join(&name))?;
+            files.push((kind, Path::new("boot").join(name)));
+        }
+
+        let modules = staging.join("lib/modules");
+        std::fs::create_dir_all(&modules)?;
+        archive::copy_tree(self, &Path::new("/lib/modules").join(kver), &modules)?;
+        files.extend(
+            archive::files_below(staging, &Path::new("lib/modules").join(kver))?
+                .into_iter()
+                .map(|path| (archive::ArtifactKind::Module, path)),
+        );
+
+        let paths: Vec<PathBuf> = files.iter().map(|(_, path)| path.clone()).collect();
+        let sums = install::sha256_all(self, staging, &paths)?;
+        archive::Manifest {
+            version: kver.to_string(),
+            date: template::today(),
+            files: files
+                .into_iter()
+                .zip(sums)
+                .map(|((kind, path), sha256)| archive::ManifestEntry { path, kind, sha256 })
+                .collect(),
+        }
+        .save(staging)
+    }
+
+    /// Packages an installed kernel with its modules as Portage binary package, so other hosts
+    /// can install it with `emerge --usepkgonly`. Like the dist-kernel packages the image is
+    /// installed to `/lib/modules/<release>/vmlinuz` and handed to `installkernel`.
+    ///
+    /// # Errors
+    ///
+    /// - Unknown kernel release or missing kernel image
+    /// - Failing to create the package
+    pub fn binpkg(&self, kver: Option<&str>) -> Result<(), BuilderErr> {
+        let kver = kver
+            .map(|kver| self.resolve_kver(kver))
+            .or_else(|| self.linked_kernel())
+            .ok_or(BuilderErr::RunningKernelUnknown)?;
+        let staging =
+            tmp::TempDir::new("binpkg").map_err(|e| BuilderErr::ArchiveError(e.to_string()))?;
+
+        self.progress.on_step_start(&format!("Packaging {kver}"));
+        let package = self
+            .stage_binpkg(&kver, staging.path())
+            .and_then(|()| self.config.binpkg.create(self, &kver, staging.path()))
+            .map_err(|e| BuilderErr::ArchiveError(e.to_string()))?;
+        self.progress.on_step_end(
+            true,
+            &format!("Created binary package {}", package.display()),
+        );
+
+        Ok(())
+    }
+
+    /// Copies modules, kernel image, `System.map` and config into the package image.
+    fn stage_binpkg(&self, kver: &str, staging: &Path) -> std::io::Result<()> {
+        let modules = staging.join("image/lib/modules");
+        std::fs::create_dir_all(&modules)?;
+        archive::copy_tree(self, &Path::new("/lib/modules").join(kver), &modules)?;
+
+        let dir = modules.join(kver);
+        // the symlinks point into the source tree of the build host
+        for link in ["build", "source"] {
+            let _ = std::fs::remove_file(dir.join(link));
+        }
+        std::fs::copy(self.kernel_path(kver), dir.join("vmlinuz"))?;
+        let source = self
+            .source_tree(kver)
+            .unwrap_or_else(|| self.config.kernel_src.join(format!("linux-{kver}")));
+        for (file, name) in [("System.map", "System.map"), (".config", "config")] {
+            if source.join(file).exists() {
+                std::fs::copy(source.join(file), dir.join(name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Installs a kernel exported with `export` after checking it against its manifest. Boot
+    /// artifacts go to the paths configured on this machine and the boot loader is updated.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to unpack the archive or a checksum mismatch
+    /// - Failing to install an artifact or the modules
+    pub fn import(&self, archive_path: &Path) -> Result<(), BuilderErr> {
+        let staging =
+            tmp::TempDir::new("import").map_err(|e| BuilderErr::ArchiveError(e.to_string()))?;
+        self.import_staged(archive_path, staging.path())
+    }
+
+    fn import_staged(&self, archive_path: &Path, staging: &Path) -> Result<(), BuilderErr> {
+        let archive_err = |e: std::io::Error| BuilderErr::ArchiveError(e.to_string());
+        archive::unpack(self, archive_path, staging).map_err(archive_err)?;
+        let manifest = archive::Manifest::load(staging).map_err(archive_err)?;
+        manifest.check_paths().map_err(archive_err)?;
+        let corrupted = manifest.verify(self, staging).map_err(archive_err)?;
+        if !corrupted.is_empty() {
+            return Err(BuilderErr::ArchiveError(format!(
+                "checksum mismatch of {corrupted:?}"
+            )));
+        }
+
+        let kver = manifest.version.as_str();
+        let _mounts = self.mount_boot_partitions(kver)?;
+        let kernel = self.kernel_path(kver);
+        let dir = kernel.parent().unwrap_or(Path::new("/boot")).to_path_buf();
+        for file in &manifest.files {
+            let target = match file.kind {
+                archive::ArtifactKind::Kernel => kernel.clone(),
+                archive::ArtifactKind::Initramfs => match self.initramfs_path(kver) {
+                    Some(path) => path,
+                    None => continue,
+                },
+                archive::ArtifactKind::SystemMap => dir.join(format!("System.map-{kver}")),
+                archive::ArtifactKind::Config => dir.join(format!("config-{kver}")),
+                archive::ArtifactKind::Module => continue,
+            };
+            self.backup_old(&target)?;
+            install::atomic_copy(self, &staging.join(&file.path), &target)
+                .map_err(BuilderErr::KernelBuildFail)?;
+            println!("Installed {}", target.display());
+        }
+
+        // only the modules listed in the manifest, whose checksums were verified
+        for file in manifest
+            .files
+            .iter()
+            .filter(|file| file.kind == archive::ArtifactKind::Module)
+        {
+            let target = Path::new("/").join(&file.path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).map_err(archive_err)?;
+            }
+            std::fs::copy(staging.join(&file.path), &target).map_err(archive_err)?;
+        }
+        let depmod = self
+            .run_output(&Invocation::new("depmod").arg(kver))
+            .map_err(BuilderErr::KernelBuildFail)?;
+        if !depmod.success {
+            return Err(BuilderErr::ArchiveError(format!(
+                "depmod {kver} failed: {}",
+                depmod.stderr.trim()
+            )));
+        }
+        println!("Installed modules of {kver}");
+
+        self.update_bootloaders(kver)?;
+
+        self.record_install(kver, None).map(|_| ())
+    }
+}
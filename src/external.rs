@@ -0,0 +1,53 @@
+use crate::{CommandRunner, Invocation, KernelBuilder};
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+/// Outcome of building a package or directory of out-of-tree modules against a new kernel
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleBuild {
+    /// Package atom or module source directory
+    pub name: String,
+    pub error: Option<String>,
+}
+
+impl KernelBuilder {
+    fn make_external(&self, kernel: &Path, dir: &Path, target: &str) -> io::Result<()> {
+        let output = self.run_output(
+            &self
+                .make()
+                .arg("-C")
+                .arg(kernel)
+                .arg(format!("M={}", dir.display()))
+                .arg(target),
+        )?;
+        if !output.success {
+            return Err(io::Error::other(
+                output
+                    .stderr
+                    .lines()
+                    .last()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Builds the modules in `dir` against the kernel tree and installs them into
+    /// `/lib/modules/<kver>/updates`
+    pub(crate) fn build_external(&self, kernel: &Path, dir: &Path) -> io::Result<()> {
+        self.make_external(kernel, dir, "modules")?;
+        self.make_external(kernel, dir, "modules_install")
+    }
+}
+
+/// Checks that a module and its dependencies resolve in the module tree of a kernel, without
+/// loading anything
+pub fn modprobe_dry_run(runner: &dyn CommandRunner, kver: &str, module: &str) -> io::Result<()> {
+    runner
+        .run(&Invocation::new("modprobe").args(["--dry-run", "--set-version", kver, module]))
+        .map(drop)
+}
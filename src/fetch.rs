@@ -0,0 +1,155 @@
+use crate::{tmp, BuilderErr, CommandRunner, Invocation, KernelBuilder};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Keys of the kernel.org release signers as installed by sec-keys/openpgp-keys-kernel
+pub fn default_keyring() -> PathBuf {
+    PathBuf::from("/usr/share/openpgp-keys/kernel.org.asc")
+}
+
+/// URL of the release tarball on kernel.org, the signature has the `.tar.sign` extension
+pub fn tarball_url(version: &str) -> Option<String> {
+    let major: u32 = version.split('.').next()?.parse().ok()?;
+    Some(format!(
+        "https://cdn.kernel.org/pub/linux/kernel/v{major}.x/linux-{version}.tar.xz"
+    ))
+}
+
+pub fn download(runner: &dyn CommandRunner, url: &str, target: &Path) -> io::Result<()> {
+    runner
+        .run(
+            &Invocation::new("curl")
+                .args([
+                    "--fail",
+                    "--location",
+                    "--silent",
+                    "--show-error",
+                    "--output",
+                ])
+                .arg(target)
+                .arg(url),
+        )
+        .map(drop)
+}
+
+/// Decompresses the downloaded tarball next to it and verifies the signature of the result
+/// against the keys in `keyring`. Returns the uncompressed tarball, which is the file to unpack so
+/// exactly the verified data ends up in the tree. The keys are imported into a throwaway GnuPG
+/// home, so the user's keyring is never touched. All files have to be in a directory only root
+/// can write to.
+pub fn verify(
+    runner: &dyn CommandRunner,
+    tarball: &Path,
+    signature: &Path,
+    keyring: &Path,
+    home: &Path,
+) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(home)?;
+    runner.run(
+        &Invocation::new("gpg")
+            .arg("--homedir")
+            .arg(home)
+            .args(["--batch", "--quiet", "--import"])
+            .arg(keyring),
+    )?;
+
+    runner.run(
+        &Invocation::new("xz")
+            .args(["--decompress", "--keep", "--force"])
+            .arg(tarball),
+    )?;
+    let uncompressed = tarball.with_extension("");
+    runner
+        .run(
+            &Invocation::new("gpg")
+                .arg("--homedir")
+                .arg(home)
+                .args(["--batch", "--verify"])
+                .arg(signature)
+                .arg(&uncompressed),
+        )
+        .map_err(|e| io::Error::other(format!("bad signature: {e}")))?;
+
+    Ok(uncompressed)
+}
+
+/// Unpacks the verified, uncompressed tarball into `dir`
+pub fn unpack(runner: &dyn CommandRunner, tarball: &Path, dir: &Path) -> io::Result<()> {
+    runner
+        .run(
+            &Invocation::new("tar")
+                .arg("-xf")
+                .arg(tarball)
+                .arg("-C")
+                .arg(dir),
+        )
+        .map(drop)
+}
+
+impl KernelBuilder {
+    /// Downloads a release tarball and its signature from kernel.org, verifies the signature and
+    /// unpacks the tree into `fetch-dir`, where it is offered for building.
+    ///
+    /// # Errors
+    ///
+    /// - Invalid version or already present tree
+    /// - Failing download, bad signature or failing to unpack
+    pub fn fetch(&self, version: &str) -> Result<(), BuilderErr> {
+        let dir = self
+            .config
+            .fetch_dir
+            .as_ref()
+            .unwrap_or(&self.config.kernel_src);
+        let tree = dir.join(format!("linux-{version}"));
+        if tree.exists() {
+            return Err(BuilderErr::FetchError(format!(
+                "{} already exists",
+                tree.display()
+            )));
+        }
+        if version.contains("-rc") {
+            return Err(BuilderErr::FetchError(
+                "release candidates are not published as signed tarballs".into(),
+            ));
+        }
+        let url = tarball_url(version)
+            .ok_or_else(|| BuilderErr::FetchError(format!("invalid version `{version}`")))?;
+
+        // next to the trees, the uncompressed tarball is too large for a tmpfs
+        let staging = tmp::TempDir::new_in(dir, "fetch")
+            .map_err(|e| BuilderErr::FetchError(e.to_string()))?;
+        let tarball = staging.join(format!("linux-{version}.tar.xz"));
+        let signature = staging.join(format!("linux-{version}.tar.sign"));
+
+        self.progress
+            .on_step_start(&format!("Downloading linux-{version}"));
+        let result = download(self, &url, &tarball)
+            .and_then(|()| download(self, &url.replace(".tar.xz", ".tar.sign"), &signature))
+            .and_then(|()| {
+                self.progress.on_progress("Verifying signature");
+                let verified = verify(
+                    self,
+                    &tarball,
+                    &signature,
+                    &self.config.kernel_org_keys,
+                    &staging.join("gnupg"),
+                )?;
+                self.progress
+                    .on_progress(&format!("Unpacking into {}", dir.display()));
+                unpack(self, &verified, dir)
+            });
+        drop(staging);
+        match result {
+            Ok(()) => {
+                self.progress
+                    .on_step_end(true, &format!("Fetched {}", tree.display()));
+                Ok(())
+            }
+            Err(e) => {
+                self.progress
+                    .on_step_end(false, &format!("Failed fetching linux-{version}"));
+                Err(BuilderErr::FetchError(e.to_string()))
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::{CommandRunner, Invocation};
+use std::io;
+use std::path::Path;
+
+fn git(runner: &dyn CommandRunner, path: &Path, args: &[&str]) -> io::Result<String> {
+    runner
+        .run(&Invocation::new("git").current_dir(path).args(args))
+        .map(|output| output.trim().to_string())
+}
+
+/// `path` is the top level of a git checkout
+pub fn is_git_tree(path: &Path) -> bool {
+    path.join(".git").exists()
+}
+
+/// Version of the checked out commit as shown by `git describe`, without the leading `v` of
+/// kernel tags, e.g. `6.13-rc3-45-gabcdef012345`
+pub fn describe(runner: &dyn CommandRunner, path: &Path) -> Option<String> {
+    git(runner, path, &["describe", "--tags", "--always"])
+        .ok()
+        .map(|version| version.strip_prefix('v').unwrap_or(&version).to_string())
+}
+
+/// Local branches followed by the newest tags
+pub fn refs(runner: &dyn CommandRunner, path: &Path) -> io::Result<Vec<String>> {
+    let branches = git(
+        runner,
+        path,
+        &["for-each-ref", "--format=%(refname:short)", "refs/heads"],
+    )?;
+    let tags = git(
+        runner,
+        path,
+        &[
+            "for-each-ref",
+            "--sort=-creatordate",
+            "--count=20",
+            "--format=%(refname:short)",
+            "refs/tags",
+        ],
+    )?;
+
+    Ok(branches
+        .lines()
+        .chain(tags.lines())
+        .map(ToString::to_string)
+        .collect())
+}
+
+pub fn checkout(runner: &dyn CommandRunner, path: &Path, reference: &str) -> io::Result<()> {
+    git(runner, path, &["checkout", "--quiet", reference]).map(|_| ())
+}
@@ -0,0 +1,254 @@
+use crate::template;
+use crate::{CommandRunner, Invocation, StepReport};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Directory of the hooks run before a kernel gets installed
+pub const PREINST_DIR: &str = "/etc/kernel/preinst.d";
+/// Directory of the hooks run after a kernel got installed
+pub const POSTINST_DIR: &str = "/etc/kernel/postinst.d";
+
+/// Scripts `run-parts` would execute in `dir`: executable files with names consisting of
+/// letters, digits, underscores and hyphens, in lexical order.
+fn scripts(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut scripts: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry.file_name().to_str().is_some_and(|name| {
+                name.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            })
+        })
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+/// Runs the scripts of a hook directory like `run-parts --arg=<kver> --arg=<image>`, stopping
+/// at the first failing one.
+pub fn run_parts(
+    runner: &dyn CommandRunner,
+    dir: &Path,
+    kver: &str,
+    image: &Path,
+) -> Result<(), String> {
+    for script in scripts(dir) {
+        println!("Running hook {}", script.display());
+        let success = runner
+            .interactive(&Invocation::new(&script).arg(kver).arg(image))
+            .map_err(|e| format!("{}: {e}", script.display()))?;
+        if !success {
+            return Err(format!("{} failed", script.display()));
+        }
+    }
+
+    Ok(())
+}
+
+/// What happens when a hook exits with a non-zero status
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// Stop the build
+    #[default]
+    Abort,
+    /// Run the hook again, up to `retries` times, and stop the build if it keeps failing
+    Retry,
+    /// Print a warning and carry on
+    Warn,
+}
+
+/// A hook executable with its failure policy, given either as a plain path or as a table
+/// `{ path = "...", on-failure = "retry", retries = 3 }`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "HookEntry")]
+pub struct Hook {
+    pub path: PathBuf,
+    pub on_failure: OnFailure,
+    pub retries: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HookEntry {
+    Path(PathBuf),
+    Table {
+        path: PathBuf,
+        #[serde(rename = "on-failure", default)]
+        on_failure: OnFailure,
+        #[serde(default = "default_retries")]
+        retries: u32,
+    },
+}
+
+fn default_retries() -> u32 {
+    1
+}
+
+impl From<HookEntry> for Hook {
+    fn from(entry: HookEntry) -> Self {
+        match entry {
+            HookEntry::Path(path) => Self {
+                path,
+                on_failure: OnFailure::Abort,
+                retries: default_retries(),
+            },
+            HookEntry::Table {
+                path,
+                on_failure,
+                retries,
+            } => Self {
+                path,
+                on_failure,
+                retries,
+            },
+        }
+    }
+}
+
+/// Executables run around the steps of a build, configured in the `[hooks]` section
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Hooks {
+    #[serde(rename = "pre-build", default)]
+    pub pre_build: Vec<Hook>,
+    #[serde(rename = "post-build", default)]
+    pub post_build: Vec<Hook>,
+    #[serde(rename = "pre-install", default)]
+    pub pre_install: Vec<Hook>,
+    #[serde(rename = "post-install", default)]
+    pub post_install: Vec<Hook>,
+    #[serde(rename = "post-initramfs", default)]
+    pub post_initramfs: Vec<Hook>,
+}
+
+/// Point in the build a hook runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStep {
+    PreBuild,
+    PostBuild,
+    PreInstall,
+    PostInstall,
+    PostInitramfs,
+}
+
+impl HookStep {
+    /// Name as used in the config and passed to hooks
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PreBuild => "pre-build",
+            Self::PostBuild => "post-build",
+            Self::PreInstall => "pre-install",
+            Self::PostInstall => "post-install",
+            Self::PostInitramfs => "post-initramfs",
+        }
+    }
+}
+
+impl Hooks {
+    pub fn get(&self, step: HookStep) -> &[Hook] {
+        match step {
+            HookStep::PreBuild => &self.pre_build,
+            HookStep::PostBuild => &self.post_build,
+            HookStep::PreInstall => &self.pre_install,
+            HookStep::PostInstall => &self.post_install,
+            HookStep::PostInitramfs => &self.post_initramfs,
+        }
+    }
+}
+
+/// Build a hook runs for, written to the JSON file at `KERNEL_BUILDER_CONTEXT`
+#[derive(Debug, Serialize)]
+pub struct Context<'a> {
+    pub step: &'a str,
+    /// Name of the source tree like `linux-6.12.8-gentoo`
+    pub version: &'a str,
+    pub kernelrelease: &'a str,
+    pub source: &'a Path,
+    pub kernel: PathBuf,
+    pub initramfs: Option<PathBuf>,
+    pub uki: Option<PathBuf>,
+    pub flavor: Option<&'a str>,
+    /// Steps finished so far in order
+    pub timings: &'a [StepReport],
+}
+
+/// Runs the hooks of a step in order with the build described by environment variables. Their
+/// output is shown and appended to `log` together with the exit status of every attempt. A
+/// failing hook is retried or passed to `warn` according to its policy, otherwise it stops the
+/// step.
+pub fn run_step(
+    runner: &dyn CommandRunner,
+    hooks: &[Hook],
+    env: &[(&str, String)],
+    log: &mut impl Write,
+    mut warn: impl FnMut(String),
+) -> Result<(), String> {
+    for hook in hooks {
+        let attempts = match hook.on_failure {
+            OnFailure::Retry => hook.retries + 1,
+            OnFailure::Abort | OnFailure::Warn => 1,
+        };
+
+        let mut failure = None;
+        for attempt in 1..=attempts {
+            println!("Running hook {}", hook.path.display());
+            let _ = writeln!(
+                log,
+                "[{}] hook {} (attempt {attempt}/{attempts})",
+                template::timestamp(),
+                hook.path.display()
+            );
+            let invocation = env
+                .iter()
+                .fold(Invocation::new(&hook.path), |invocation, (key, value)| {
+                    invocation.env(key, value)
+                });
+            let output = match runner.output(&invocation) {
+                Ok(output) => output,
+                Err(e) => {
+                    let _ = writeln!(log, "failed to run: {e}");
+                    failure = Some(format!("{}: {e}", hook.path.display()));
+                    continue;
+                }
+            };
+
+            print!("{}", output.stdout);
+            eprint!("{}", output.stderr);
+            let _ = log.write_all(output.stdout.as_bytes());
+            let _ = log.write_all(output.stderr.as_bytes());
+            let status = output.code.map_or_else(
+                || "killed by a signal".to_string(),
+                |code| format!("exit status: {code}"),
+            );
+            let _ = writeln!(log, "{status}");
+
+            if output.success {
+                failure = None;
+                break;
+            }
+            failure = Some(format!("{} exited with {status}", hook.path.display()));
+        }
+
+        if let Some(failure) = failure {
+            if hook.on_failure == OnFailure::Warn {
+                warn(format!("hook {failure}"));
+            } else {
+                return Err(failure);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,579 @@
+use crate::{
+    discover::VersionEntry, install, kconfig, kconfig::KernelConfig, microcode, rootfs,
+    rootfs::RootStack, running_kernel, signing, snapshot, template, tmp, BuilderErr, CommandRunner,
+    Invocation, KernelBuilder, Verbosity,
+};
+use indicatif::HumanBytes;
+use std::path::{Path, PathBuf};
+
+/// Lists the content of an initramfs image with `lsinitrd`.
+pub fn list_contents(runner: &dyn CommandRunner, initramfs: &Path) -> std::io::Result<Vec<String>> {
+    Ok(runner
+        .run(&Invocation::new("lsinitrd").arg(initramfs))?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Names of the kernel modules included in the image, compressed modules are handled as well
+pub fn included_modules(contents: &[String]) -> Vec<String> {
+    contents
+        .iter()
+        .filter_map(|line| line.split_whitespace().last())
+        .filter_map(|file| Path::new(file).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .filter_map(|name| {
+            [".ko", ".ko.xz", ".ko.zst", ".ko.gz"]
+                .iter()
+                .find_map(|ext| name.strip_suffix(ext).map(String::from))
+        })
+        .collect()
+}
+
+/// Firmware files requested by the included modules that are not installed in `/lib/firmware`,
+/// grouped by module. Compressed firmware is taken into account.
+pub fn missing_firmware(
+    runner: &dyn CommandRunner,
+    kver: &str,
+    contents: &[String],
+) -> Vec<(String, Vec<String>)> {
+    let firmware_dir = Path::new("/lib/firmware");
+
+    included_modules(contents)
+        .into_iter()
+        .filter_map(|module| {
+            let output = runner
+                .output(&Invocation::new("modinfo").args(["-k", kver, "-F", "firmware", &module]))
+                .ok()?;
+            let missing = output
+                .stdout
+                .lines()
+                .map(str::trim)
+                // wildcard entries cannot be checked reliably
+                .filter(|firmware| !firmware.is_empty() && !firmware.contains('*'))
+                .filter(|firmware| {
+                    ["", ".xz", ".zst"]
+                        .iter()
+                        .all(|ext| !firmware_dir.join(format!("{firmware}{ext}")).exists())
+                })
+                .map(String::from)
+                .collect::<Vec<_>>();
+
+            (!missing.is_empty()).then_some((module, missing))
+        })
+        .collect()
+}
+
+fn contains_module(contents: &[String], module: &str) -> bool {
+    let needle = format!("/{module}.ko");
+    contents.iter().any(|line| line.contains(&needle))
+}
+
+fn contains_file(contents: &[String], file: &str) -> bool {
+    contents.iter().any(|line| line.ends_with(file))
+}
+
+/// Checks that everything needed to mount the root filesystem is either part of the initramfs or
+/// compiled into the kernel. Returns a list of human readable problems, an empty list means the
+/// image looks bootable.
+pub fn verify_contents(
+    contents: &[String],
+    kernel_config: &KernelConfig,
+    root: &RootStack,
+) -> Vec<String> {
+    let mut missing = vec![];
+    let available = |option: Option<&str>, module: &str| {
+        option.is_some_and(|opt| kernel_config.is_builtin(opt)) || contains_module(contents, module)
+    };
+
+    if !available(root.fs_kconfig(), root.fs_module()) {
+        missing.push(format!(
+            "root filesystem driver `{}` is neither built in nor included",
+            root.fs_module()
+        ));
+    }
+
+    if root.encrypted {
+        if !available(Some("DM_CRYPT"), "dm-crypt") {
+            missing.push("dm-crypt is neither built in nor included".into());
+        }
+        if !contains_file(contents, "/cryptsetup") {
+            missing.push(format!(
+                "root device {} is encrypted but `cryptsetup` is missing",
+                root.device.display()
+            ));
+        }
+        if !available(Some("USB_HID"), "usbhid") && !available(Some("KEYBOARD_ATKBD"), "atkbd") {
+            missing.push("no keyboard driver available to enter the passphrase".into());
+        }
+    }
+
+    if Path::new("/etc/vconsole.conf").exists() && !contains_file(contents, "/loadkeys") {
+        missing.push("console keymap is configured but `loadkeys` is missing".into());
+    }
+
+    missing
+}
+
+/// Available space in bytes on the filesystem containing `path`
+fn available_space(runner: &dyn CommandRunner, path: &Path) -> Option<u64> {
+    let output = runner
+        .output(
+            &Invocation::new("df")
+                .args(["--output=avail", "-B1"])
+                .arg(path),
+        )
+        .ok()?;
+
+    output
+        .stdout
+        .lines()
+        .nth(1)
+        .and_then(|avail| avail.trim().parse().ok())
+}
+
+impl KernelBuilder {
+    pub(crate) fn generate_initramfs(
+        &self,
+        VersionEntry {
+            path,
+            version_string,
+        }: &VersionEntry,
+        replace: bool,
+    ) -> Result<(), BuilderErr> {
+        let kver = self.kernel_release(path, version_string);
+        let kver = kver.as_str();
+        let initramfs_file_path = self
+            .initramfs_path(kver)
+            .ok_or(BuilderErr::KernelConfigMissingOption("initramfs".into()))?;
+
+        self.install_initramfs(kver, path, &initramfs_file_path, replace)?;
+        self.copy_to_destinations("initramfs", kver, &initramfs_file_path, |dest| {
+            dest.initramfs.as_deref()
+        })
+    }
+
+    /// Regenerates the initramfs of an already installed kernel without building anything. Any
+    /// kernel release with modules in `/lib/modules` can be used, if none is given the user is
+    /// prompted to pick one. Kernels not built from the tree `/usr/src/linux` points to get their
+    /// image named `initramfs-<release>.img` next to the configured initramfs.
+    ///
+    /// # Errors
+    ///
+    /// - Missing modules for the kernel release
+    /// - Missing `initramfs` option in the config
+    /// - Failing generating initramfs
+    pub fn regenerate_initramfs(&self, kver: Option<&str>) -> Result<(), BuilderErr> {
+        let linked = self.linked_kernel();
+        let kver = match kver {
+            Some(kver) => self.resolve_kver(kver),
+            None => {
+                let Some(kver) = self.prompt_for_installed_kernel(linked.as_deref())? else {
+                    return Ok(());
+                };
+                kver
+            }
+        };
+
+        self.regenerate_initramfs_for(&kver, linked.as_deref())
+    }
+
+    /// Whether modules of a kernel release were installed after its initramfs was generated, so
+    /// the image misses their current build
+    pub(crate) fn initramfs_outdated(&self, kver: &str) -> bool {
+        let modified = |path: &Path| path.metadata().and_then(|meta| meta.modified()).ok();
+        let Some(generated) = self
+            .initramfs_path(kver)
+            .and_then(|initramfs| modified(&initramfs))
+        else {
+            return false;
+        };
+
+        signing::find_modules(
+            &Path::new(Self::MODULES_PATH).join(kver),
+            &["*.ko*".to_string()],
+        )
+        .iter()
+        .filter_map(|module| modified(module))
+        .any(|installed| installed > generated)
+    }
+
+    /// Regenerates the initramfs of every kernel release in `/lib/modules`. Failures are reported
+    /// per kernel and do not stop the remaining ones from being regenerated.
+    ///
+    /// # Errors
+    ///
+    /// - No kernel modules installed at all
+    /// - Failing generating the initramfs of at least one kernel
+    pub fn regenerate_all_initramfs(&self) -> Result<(), BuilderErr> {
+        let kernels = Self::installed_kernels();
+        if kernels.is_empty() {
+            return Err(BuilderErr::ModulesMissing("any release".into()));
+        }
+
+        let linked = self.linked_kernel();
+        let mut failed = vec![];
+        for (idx, kver) in kernels.iter().enumerate() {
+            println!(
+                "[{}/{}] Regenerating initramfs for {kver}",
+                idx + 1,
+                kernels.len()
+            );
+            if let Err(e) = self.regenerate_initramfs_for(kver, linked.as_deref()) {
+                eprintln!("Failed regenerating initramfs for {kver}: {e}");
+                failed.push(kver.clone());
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BuilderErr::InitramfsError(format!(
+                "failed for {}",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    pub(crate) fn regenerate_initramfs_for(
+        &self,
+        kver: &str,
+        linked: Option<&str>,
+    ) -> Result<(), BuilderErr> {
+        let modules = Path::new(Self::MODULES_PATH).join(kver);
+        if !modules.is_dir() {
+            return Err(BuilderErr::ModulesMissing(kver.to_string()));
+        }
+
+        let template = self
+            .config
+            .initramfs_file_path
+            .as_ref()
+            .ok_or(BuilderErr::KernelConfigMissingOption("initramfs".into()))?;
+        let initramfs_file_path = self.render_path(template, kver);
+        // versioned paths never collide, otherwise only the kernel `/usr/src/linux` points to
+        // owns the configured path
+        if linked == Some(kver) || template::is_versioned(template) {
+            self.install_initramfs(kver, &modules.join("build"), &initramfs_file_path, true)?;
+            self.copy_to_destinations("initramfs", kver, &initramfs_file_path, |dest| {
+                dest.initramfs.as_deref()
+            })
+        } else {
+            let output = initramfs_file_path.with_file_name(format!("initramfs-{kver}.img"));
+            self.install_initramfs(kver, &modules.join("build"), &output, true)
+        }
+    }
+
+    pub(crate) fn install_initramfs(
+        &self,
+        kver: &str,
+        path: &Path,
+        initramfs_file_path: &Path,
+        replace: bool,
+    ) -> Result<(), BuilderErr> {
+        let microcode_vendor = if self.config.early_microcode {
+            let vendor = microcode::CpuVendor::detect();
+            match vendor {
+                Some(vendor) if !vendor.microcode_installed() => self.warn(format!(
+                    "no microcode found in {}, is {} installed?",
+                    vendor.firmware_dir().display(),
+                    vendor.package()
+                )),
+                Some(_) => {}
+                None => self.warn("could not detect cpu vendor for early microcode"),
+            }
+            vendor
+        } else {
+            None
+        };
+
+        let previous_size = std::fs::metadata(initramfs_file_path)
+            .map(|meta| meta.len())
+            .ok();
+
+        if let (true, Some(theme)) = (self.config.plymouth, &self.config.plymouth_theme) {
+            let output = self
+                .run_output(&Invocation::new("plymouth-set-default-theme").arg(theme))
+                .map_err(BuilderErr::KernelBuildFail)?;
+            if !output.success {
+                return Err(BuilderErr::PlymouthThemeError(theme.clone()));
+            }
+        }
+
+        self.run_dracut(kver, true, initramfs_file_path, replace)?;
+
+        Self::report_size("Initramfs", initramfs_file_path, previous_size)?;
+
+        self.verify_initramfs(kver, path, initramfs_file_path)?;
+
+        if let Some(vendor) = microcode_vendor {
+            if !microcode::initramfs_has_microcode(initramfs_file_path, vendor)
+                .map_err(BuilderErr::KernelBuildFail)?
+            {
+                self.warn(format!(
+                    "initramfs does not contain early microcode ({})",
+                    vendor.cpio_entry()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs dracut for the given kernel release. Host-only images only contain the drivers needed
+    /// on this machine, otherwise all available drivers are included. Unless `replace` is set the
+    /// existing image is kept by `keep-old`.
+    fn run_dracut(
+        &self,
+        kver: &str,
+        hostonly: bool,
+        output: &Path,
+        replace: bool,
+    ) -> Result<(), BuilderErr> {
+        self.progress.on_step_start("Generating initramfs");
+        let mut dracut = Invocation::new("dracut").args([
+            if hostonly {
+                "--hostonly"
+            } else {
+                "--no-hostonly"
+            },
+            "--kver",
+            kver,
+            "--force",
+        ]);
+        if self.config.early_microcode {
+            dracut = dracut.arg("--early-microcode");
+        }
+        if self.config.plymouth && hostonly {
+            dracut = dracut.args(["--add", "plymouth"]);
+        }
+        if let Some(compression) = self.config.initramfs_compression {
+            dracut = dracut.arg(compression.dracut_flag());
+        }
+        if let Some(confdir) = self
+            .selected_flavor()
+            .and_then(|flavor| flavor.dracut_confdir.as_ref())
+            .or(self.config.dracut_confdir.as_ref())
+        {
+            dracut = dracut.arg("--confdir").arg(confdir);
+        }
+        // generate into a private staging dir first, so a full boot partition cannot leave a
+        // truncated image behind
+        let staging = tmp::TempDir::new("initramfs").map_err(BuilderErr::KernelBuildFail)?;
+        let staged = staging.join(format!("initramfs-{kver}.img"));
+        match self.verbosity {
+            Verbosity::Quiet => dracut = dracut.arg("--quiet"),
+            Verbosity::Normal => {}
+            Verbosity::Verbose => dracut = dracut.arg("--verbose"),
+        }
+
+        // dracut logs to stderr, both streams are merged so warnings show up above the spinner
+        let success = self
+            .run_streamed(&dracut.arg(&staged), &mut |line| {
+                let show = match self.verbosity {
+                    Verbosity::Quiet => line.contains("[E]"),
+                    Verbosity::Normal => line.contains("[W]") || line.contains("[E]"),
+                    Verbosity::Verbose => true,
+                };
+                if show {
+                    self.progress.on_output_line(line);
+                }
+                self.progress
+                    .on_progress(&format!("Generating initramfs: {line}"));
+            })
+            .map_err(BuilderErr::KernelBuildFail)?;
+        if !success {
+            self.progress
+                .on_step_end(false, "Failed generating initramfs");
+            return Err(BuilderErr::InitramfsError(
+                "dracut exited with an error".into(),
+            ));
+        }
+        self.progress.on_step_end(true, "Finished initramfs");
+
+        self.check_initramfs_space(&staged, output)?;
+        if !replace {
+            self.backup_old(output)?;
+        }
+        install::atomic_copy(self, &staged, output).map_err(BuilderErr::KernelBuildFail)
+    }
+
+    /// Warns about images exceeding the configured size threshold and makes sure the partition of
+    /// the output has enough free space to hold the new image.
+    fn check_initramfs_space(&self, staged: &Path, output: &Path) -> Result<(), BuilderErr> {
+        let size = std::fs::metadata(staged)
+            .map_err(BuilderErr::KernelBuildFail)?
+            .len();
+
+        let threshold = self.config.initramfs_size_warning * 1024 * 1024;
+        if threshold > 0 && size > threshold {
+            self.warn(format!(
+                "initramfs is {}, exceeding the configured threshold of {}",
+                HumanBytes(size),
+                HumanBytes(threshold)
+            ));
+        }
+
+        let Some(available) = output.parent().and_then(|dir| available_space(self, dir)) else {
+            return Ok(());
+        };
+        // the existing image is overwritten, so its space is reclaimed unless it is kept as backup
+        let reclaimed = std::fs::metadata(output)
+            .ok()
+            .filter(|_| !self.config.keep_old)
+            .map_or(0, |meta| meta.len());
+        if size > available + reclaimed {
+            return Err(BuilderErr::InitramfsError(format!(
+                "not enough space for {} on the partition of {}, {} available",
+                HumanBytes(size),
+                output.display(),
+                HumanBytes(available + reclaimed)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Generates a generic initramfs containing all drivers for the running kernel and installs it
+    /// next to the configured initramfs with a `-rescue` suffix.
+    ///
+    /// # Errors
+    ///
+    /// - Missing `initramfs` option in the config
+    /// - Failing to determine the running kernel
+    /// - Failing generating initramfs
+    pub fn generate_rescue_initramfs(&self) -> Result<(), BuilderErr> {
+        let kver = running_kernel().ok_or(BuilderErr::RunningKernelUnknown)?;
+        let output = self.rescue_initramfs_path(&kver)?;
+
+        println!("Generating rescue initramfs for running kernel {kver}");
+        self.run_dracut(&kver, false, &output, false)?;
+        Self::report_size("Rescue initramfs", &output, None)?;
+        println!("Installed rescue initramfs to {}", output.display());
+
+        Ok(())
+    }
+
+    fn rescue_initramfs_path(&self, kver: &str) -> Result<PathBuf, BuilderErr> {
+        if let Some(path) = &self.config.rescue_initramfs_file_path {
+            return Ok(self.render_path(path, kver));
+        }
+
+        let initramfs_file_path = self
+            .initramfs_path(kver)
+            .ok_or(BuilderErr::KernelConfigMissingOption("initramfs".into()))?;
+        let mut filename = initramfs_file_path
+            .file_stem()
+            .map(|p| p.to_string_lossy().to_string())
+            .ok_or_else(|| {
+                BuilderErr::InitramfsError(format!(
+                    "initramfs path {} has no file name",
+                    initramfs_file_path.display()
+                ))
+            })?;
+        filename.push_str("-rescue.img");
+
+        Ok(initramfs_file_path.with_file_name(filename))
+    }
+
+    /// Warns about firmware missing for the included modules, and with `verify-initramfs` checks
+    /// the image can mount the root filesystem.
+    fn verify_initramfs(
+        &self,
+        kver: &str,
+        path: &Path,
+        initramfs_file_path: &Path,
+    ) -> Result<(), BuilderErr> {
+        // an initramfs without zfs cannot mount a ZFS root, so it is always checked then
+        let verify = self.config.verify_initramfs || snapshot::zfs_root().is_some();
+
+        let contents = match list_contents(self, initramfs_file_path) {
+            Ok(contents) => contents,
+            Err(e) if verify => return Err(BuilderErr::KernelBuildFail(e)),
+            Err(e) => {
+                self.warn(format!("could not list the initramfs contents: {e}"));
+                return Ok(());
+            }
+        };
+
+        for (module, firmware) in missing_firmware(self, kver, &contents) {
+            self.warn(format!(
+                "firmware for module `{module}` is missing in /lib/firmware: {}",
+                firmware.join(", ")
+            ));
+        }
+
+        if !verify {
+            return Ok(());
+        }
+
+        let Some(root) = rootfs::RootStack::detect() else {
+            self.warn("could not detect root filesystem, skipping initramfs verification");
+            return Ok(());
+        };
+        let Ok(kernel_config) = kconfig::KernelConfig::load(&path.join(".config")) else {
+            self.warn("no kernel config found, skipping initramfs verification");
+            return Ok(());
+        };
+
+        let missing = verify_contents(&contents, &kernel_config, &root);
+        if !missing.is_empty() {
+            return Err(BuilderErr::InitramfsIncomplete(missing.join("\n  - ")));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KBConfig, RecordingRunner, Silent};
+
+    #[test]
+    fn run_dracut_generates_into_staging() {
+        let dir = tmp::TempDir::new("initramfs-test").unwrap();
+        let recorder = RecordingRunner::default();
+        let builder = KernelBuilder::builder(KBConfig::for_test(dir.path()))
+            .runner(Box::new(recorder.clone()))
+            .progress(Box::new(Silent))
+            .build()
+            .unwrap();
+        let output = dir.join("initramfs-6.12.8-gentoo.img");
+
+        // the recorder creates no image, so the size check fails after dracut ran
+        assert!(builder
+            .run_dracut("6.12.8-gentoo", true, &output, false)
+            .is_err());
+        assert!(!output.exists());
+
+        let invocations = recorder.invocations();
+        assert_eq!(invocations.len(), 1);
+        let dracut = &invocations[0];
+        assert_eq!(dracut.program, "dracut");
+        assert_eq!(
+            dracut.args[..4],
+            ["--hostonly", "--kver", "6.12.8-gentoo", "--force"]
+        );
+        let staged = Path::new(dracut.args.last().unwrap());
+        assert!(staged.ends_with("initramfs-6.12.8-gentoo.img"));
+        assert_ne!(staged, output);
+    }
+
+    #[test]
+    fn rescue_initramfs_path_is_rendered_for_the_kernel() {
+        let dir = tmp::TempDir::new("initramfs-test").unwrap();
+        let mut config = KBConfig::for_test(dir.path());
+        config.initramfs_file_path = Some(dir.join("initramfs-{version}.img"));
+        let builder = KernelBuilder::builder(config)
+            .runner(Box::new(RecordingRunner::default()))
+            .progress(Box::new(Silent))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            builder.rescue_initramfs_path("6.12.8-gentoo").unwrap(),
+            dir.join("initramfs-6.12.8-gentoo-rescue.img")
+        );
+    }
+}
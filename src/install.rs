@@ -0,0 +1,1052 @@
+use crate::{
+    archive, deploy, discover::VersionEntry, git, layout, mounts, portage, running_kernel, signing,
+    signing::Signer, snapshot, state, template, tmp, version, Bootloader, BuilderErr,
+    CommandRunner, Deploy, Destination, Invocation, KernelBuilder, KernelVersion,
+};
+use indicatif::HumanBytes;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Temporary sibling of `dst` on the same filesystem, so it can be renamed into place atomically
+fn staging_path(dst: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(dst.file_name().unwrap_or_default());
+    name.push(".kernel-builder-tmp");
+    dst.with_file_name(name)
+}
+
+/// Characters FAT does not allow in file names
+const FAT_RESERVED: &[char] = &['"', '*', ':', '<', '>', '?', '\\', '|'];
+
+/// `path` is on a FAT filesystem like the ESP, which has no symlinks, hard links or permissions
+pub fn on_fat(path: &Path) -> bool {
+    crate::mounts::find(path)
+        .is_some_and(|mount| matches!(mount.fstype.as_str(), "vfat" | "msdos" | "exfat"))
+}
+
+/// Replaces characters FAT does not allow in the file name of `path` with `_` and strips trailing
+/// dots and spaces, which FAT silently drops.
+pub fn fat_safe(path: &Path) -> PathBuf {
+    let Some(name) = path.file_name() else {
+        return path.to_path_buf();
+    };
+    let name: String = name
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if FAT_RESERVED.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    path.with_file_name(name.trim_end_matches(['.', ' ']))
+}
+
+/// Copies the content only, `std::fs::copy` also sets the permissions which fails on FAT
+fn copy_content(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut reader = File::open(src)?;
+    let mut writer = File::create(dst)?;
+    io::copy(&mut reader, &mut writer)?;
+
+    Ok(())
+}
+
+/// Copies `src` to `dst` without ever exposing a partially written `dst`. The data is written to a
+/// temporary file next to `dst`, flushed to disk, compared by hash with `src` and atomically
+/// renamed into place. Finally the directory is synced so the rename itself survives a power loss.
+/// On FAT only the content is copied and the directory sync is skipped, FAT has no permissions and
+/// its directory entries are written with the file.
+pub fn atomic_copy(runner: &dyn CommandRunner, src: &Path, dst: &Path) -> io::Result<()> {
+    let tmp = staging_path(dst);
+    let fat = on_fat(dst);
+    let result = (|| {
+        if fat {
+            copy_content(src, &tmp)?;
+        } else {
+            std::fs::copy(src, &tmp)?;
+        }
+        File::open(&tmp)?.sync_all()?;
+        if sha256(runner, src)? != sha256(runner, &tmp)? {
+            return Err(io::Error::other(format!(
+                "copy of {} to {} is corrupted",
+                src.display(),
+                dst.display()
+            )));
+        }
+        std::fs::rename(&tmp, dst)?;
+        if let Some(dir) = dst.parent().filter(|_| !fat) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+
+    result.map_err(|e| {
+        let fs = if fat { " (FAT)" } else { "" };
+        io::Error::new(
+            e.kind(),
+            format!("copying {} to {}{fs}: {e}", src.display(), dst.display()),
+        )
+    })
+}
+
+/// Creates `backup` as a copy of `target` while `target` stays in place. A hard link is used when
+/// possible, so no extra space is needed and the backup is created instantly.
+pub fn backup(runner: &dyn CommandRunner, target: &Path, backup: &Path) -> io::Result<()> {
+    if backup.exists() {
+        std::fs::remove_file(backup)?;
+    }
+
+    if on_fat(backup) || std::fs::hard_link(target, backup).is_err() {
+        atomic_copy(runner, target, backup)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the files one after another into `output`
+fn concat(files: &[PathBuf], output: &Path) -> io::Result<()> {
+    let mut output = File::create_new(output)?;
+    for file in files {
+        io::copy(&mut File::open(file)?, &mut output)?;
+    }
+
+    output.sync_all()
+}
+
+/// Reads the kernel release from the setup header of an x86 boot image, e.g. `6.12.8-gentoo`.
+pub fn image_version(image: &Path) -> Option<String> {
+    let mut file = File::open(image).ok()?;
+    let mut header = [0u8; 0x10];
+    file.seek(SeekFrom::Start(0x202)).ok()?;
+    file.read_exact(&mut header).ok()?;
+    if &header[..4] != b"HdrS" {
+        return None;
+    }
+
+    let offset = u16::from_le_bytes([header[0xc], header[0xd]]);
+    let mut version = [0u8; 256];
+    file.seek(SeekFrom::Start(u64::from(offset) + 0x200)).ok()?;
+    let len = file.read(&mut version).ok()?;
+    let version = String::from_utf8_lossy(&version[..len]);
+    version
+        .split(['\0', ' '])
+        .next()
+        .filter(|version| !version.is_empty())
+        .map(ToString::to_string)
+}
+
+/// Total size of all files below `path`
+pub fn dir_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| match entry.file_type() {
+                    Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+                    Ok(kind) if kind.is_file() => entry.metadata().map_or(0, |meta| meta.len()),
+                    _ => 0,
+                })
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+/// SHA-256 checksum of a file as lowercase hex, computed by `sha256sum`
+pub fn sha256(runner: &dyn CommandRunner, path: &Path) -> io::Result<String> {
+    runner
+        .run(&Invocation::new("sha256sum").arg(path))?
+        .split_whitespace()
+        .next()
+        .map(ToString::to_string)
+        .ok_or_else(|| io::Error::other("sha256sum printed no checksum"))
+}
+
+/// SHA-256 checksums of many files relative to `dir`, computed by few `sha256sum` calls
+pub fn sha256_all(
+    runner: &dyn CommandRunner,
+    dir: &Path,
+    files: &[PathBuf],
+) -> io::Result<Vec<String>> {
+    let mut sums = Vec::with_capacity(files.len());
+    for chunk in files.chunks(256) {
+        let output = runner.run(
+            &Invocation::new("sha256sum")
+                .current_dir(dir)
+                .args(chunk.iter().cloned()),
+        )?;
+
+        sums.extend(
+            output
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .map(ToString::to_string),
+        );
+    }
+
+    if sums.len() != files.len() {
+        return Err(io::Error::other("sha256sum did not print all checksums"));
+    }
+
+    Ok(sums)
+}
+
+impl KernelBuilder {
+    /// Copies kernel, initramfs, `System.map` and modules to every deploy host and installs them
+    /// there, either at the same paths as locally or with the host's `installkernel`.
+    pub(crate) fn deploy(
+        &self,
+        deploy: &Deploy,
+        path: &Path,
+        kver: &str,
+    ) -> Result<(), BuilderErr> {
+        let kernel = self.kernel_path(kver);
+        let initramfs = self
+            .initramfs_path(kver)
+            .filter(|initramfs| !self.initramfs_less() && initramfs.exists());
+        let system_map = path.join("System.map");
+        let local = tmp::TempDir::new("deploy").map_err(BuilderErr::KernelBuildFail)?;
+        let modules = deploy::modules_tarball(self, kver, local.path())
+            .map_err(BuilderErr::KernelBuildFail)?;
+
+        let mut files = vec![kernel.as_path(), system_map.as_path(), modules.as_path()];
+        files.extend(initramfs.as_deref());
+        let script = |staging: &Path| {
+            let staged = |file: &Path| staging.join(file.file_name().unwrap_or_default());
+            let mut script = format!(
+                "tar -xzf {} -C /lib/modules",
+                deploy::quote(&staged(&modules))
+            );
+            if deploy.depmod {
+                script.push_str(&format!(" && depmod {kver}"));
+            }
+            if deploy.installkernel {
+                script.push_str(&format!(
+                    " && installkernel {kver} {} {}",
+                    deploy::quote(&staged(&kernel)),
+                    deploy::quote(&staged(&system_map))
+                ));
+            } else {
+                for file in std::iter::once(&kernel).chain(initramfs.as_ref()) {
+                    script.push_str(&format!(
+                        " && cp {} {}",
+                        deploy::quote(&staged(file)),
+                        deploy::quote(file)
+                    ));
+                }
+            }
+            // the staging dir goes away whether the install worked or not
+            format!(
+                "{script}; status=$?; rm -rf {}; exit $status",
+                deploy::quote(staging)
+            )
+        };
+
+        let mut failed = vec![];
+        for host in &deploy.hosts {
+            self.progress
+                .on_step_start(&format!("Deploying {kver} to {host}"));
+            let result = deploy::staging_dir(self, host).and_then(|staging| {
+                deploy::rsync(self, host, &files, &staging)
+                    .and_then(|()| deploy::ssh(self, host, &script(&staging)))
+                    .inspect_err(|_| {
+                        let _ =
+                            deploy::ssh(self, host, &format!("rm -rf {}", deploy::quote(&staging)));
+                    })
+            });
+            match result {
+                Ok(()) => self
+                    .progress
+                    .on_step_end(true, &format!("Deployed {kver} to {host}")),
+                Err(e) => {
+                    self.progress
+                        .on_step_end(false, &format!("Failed deploying {kver} to {host}: {e}"));
+                    failed.push(host.clone());
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BuilderErr::DeployFailed(failed))
+        }
+    }
+
+    pub(crate) fn install_kernel(
+        &self,
+        path: &Path,
+        kver: &str,
+        replace: bool,
+    ) -> Result<(), BuilderErr> {
+        let kernel_file_path = self.kernel_path(kver);
+        if self.config.backup && kernel_file_path.exists() {
+            self.backup_installed(kver, &kernel_file_path)?;
+        }
+
+        if !replace {
+            self.backup_old(&kernel_file_path)?;
+        }
+        let image = path.join("arch/x86/boot/bzImage");
+        if self.signing_enabled() {
+            let staging = tmp::TempDir::new("sign").map_err(BuilderErr::KernelBuildFail)?;
+            let signed = staging.join(format!("vmlinuz-{kver}"));
+            self.sign(&image, &signed)?;
+            atomic_copy(self, &signed, &kernel_file_path).map_err(BuilderErr::KernelBuildFail)?;
+            self.verify_signature(&kernel_file_path)?;
+        } else {
+            atomic_copy(self, &image, &kernel_file_path).map_err(BuilderErr::KernelBuildFail)?;
+        }
+        self.copy_to_destinations("kernel", kver, &kernel_file_path, |dest| Some(&dest.kernel))?;
+
+        for (source, target) in self.debug_artifacts(kver) {
+            atomic_copy(self, &path.join(source), &target).map_err(BuilderErr::KernelBuildFail)?;
+            println!("Installed {}", target.display());
+        }
+
+        Ok(())
+    }
+
+    /// `System.map` and `.config` of the tree with their install paths next to the kernel image,
+    /// as far as enabled. Debugging tools expect them as `System.map-<release>` and
+    /// `config-<release>`.
+    fn debug_artifacts(&self, kver: &str) -> Vec<(&'static str, PathBuf)> {
+        let kernel_file_path = self.kernel_path(kver);
+        let dir = kernel_file_path.parent().unwrap_or(Path::new("/boot"));
+        let mut artifacts = vec![];
+        if self.config.install_system_map {
+            artifacts.push(("System.map", dir.join(format!("System.map-{kver}"))));
+        }
+        if self.config.install_config {
+            artifacts.push((".config", dir.join(format!("config-{kver}"))));
+        }
+        artifacts
+    }
+
+    /// Takes a snapper snapshot of the root filesystem before installing, so the module tree and
+    /// `/boot` on the same volume can be rolled back. Skipped without btrfs and snapper.
+    pub(crate) fn take_snapshot(&self, kver: &str) -> Result<Option<u32>, BuilderErr> {
+        if !self.config.snapshot || !snapshot::snapper_available() {
+            return Ok(None);
+        }
+
+        let number =
+            snapshot::snapper_create(self, &format!("kernel-builder: before installing {kver}"))
+                .map_err(BuilderErr::SnapshotError)?;
+        println!("Created snapper snapshot {number}");
+
+        Ok(Some(number))
+    }
+
+    /// Boot environment the loader entry of `kver` boots
+    pub(crate) fn boot_environment(&self, kver: &str) -> Option<String> {
+        if !self.config.boot_environment {
+            return None;
+        }
+
+        snapshot::zfs_root()
+            .map(|root| snapshot::boot_environment(&root, &format!("kernel-builder-{kver}")))
+    }
+
+    /// Clones the ZFS root dataset before new modules are installed. The entry of the new
+    /// kernel boots the clone, the previous kernels keep booting the root dataset.
+    pub(crate) fn create_boot_environment(&self, kver: &str) -> Result<(), BuilderErr> {
+        let (Some(root), Some(dataset)) = (snapshot::zfs_root(), self.boot_environment(kver))
+        else {
+            return Ok(());
+        };
+        if snapshot::zfs_exists(self, &dataset) {
+            println!("Boot environment {dataset} already exists");
+            return Ok(());
+        }
+
+        snapshot::create_boot_environment(self, &root, &dataset)
+            .map_err(BuilderErr::SnapshotError)?;
+        println!("Created boot environment {dataset}");
+
+        Ok(())
+    }
+
+    /// Copies the installed modules of `kver` into its boot environment, which was cloned before
+    /// they existed.
+    pub(crate) fn sync_boot_environment(&self, kver: &str) -> Result<(), BuilderErr> {
+        let Some(dataset) = self
+            .boot_environment(kver)
+            .filter(|dataset| snapshot::zfs_exists(self, dataset))
+        else {
+            return Ok(());
+        };
+
+        let mountpoint = tmp::TempDir::new("be").map_err(BuilderErr::SnapshotError)?;
+        snapshot::mount_boot_environment(self, &dataset, mountpoint.path())
+            .map_err(BuilderErr::SnapshotError)?;
+        let modules = mountpoint.join(Self::MODULES_PATH.trim_start_matches('/'));
+        let result = std::fs::create_dir_all(&modules)
+            .and_then(|()| match std::fs::remove_dir_all(modules.join(kver)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            })
+            .and_then(|()| {
+                archive::copy_tree(self, &Path::new(Self::MODULES_PATH).join(kver), &modules)
+            });
+        let unmounted = snapshot::unmount(self, mountpoint.path());
+        result.and(unmounted).map_err(BuilderErr::SnapshotError)?;
+        println!("Copied the modules of {kver} into boot environment {dataset}");
+
+        Ok(())
+    }
+
+    /// Destroys the boot environment of a removed kernel unless it is the running root
+    fn remove_boot_environment(&self, kver: &str) -> Result<(), BuilderErr> {
+        let Some(dataset) = self
+            .boot_environment(kver)
+            .filter(|dataset| snapshot::zfs_exists(self, dataset))
+        else {
+            return Ok(());
+        };
+        if snapshot::zfs_root().as_ref() == Some(&dataset) {
+            self.warn(format!(
+                "Keeping boot environment {dataset}, it is the running root"
+            ));
+            return Ok(());
+        }
+
+        snapshot::destroy_boot_environment(self, &dataset).map_err(BuilderErr::SnapshotError)?;
+        println!("Destroyed boot environment {dataset}");
+
+        Ok(())
+    }
+
+    /// Copies the kernel currently installed at `kernel_file_path` and its initramfs into the
+    /// backup directory, keyed by the release that is about to be replaced, and records the backup
+    /// in the state database.
+    fn backup_installed(&self, kver: &str, kernel_file_path: &Path) -> Result<(), BuilderErr> {
+        let mut state = self.load_state()?;
+        let previous = state
+            .last_install_at(kernel_file_path)
+            .map(|install| install.version.clone())
+            .or_else(|| image_version(kernel_file_path))
+            .unwrap_or_else(|| "unknown".to_string());
+        let dir = self.config.backup_dir.join(&previous);
+        std::fs::create_dir_all(&dir).map_err(BuilderErr::BackupError)?;
+
+        let copy = |source: &Path| -> Result<PathBuf, BuilderErr> {
+            let target = dir.join(source.file_name().unwrap_or_default());
+            atomic_copy(self, source, &target).map_err(BuilderErr::BackupError)?;
+            Ok(target)
+        };
+        let kernel_backup = copy(kernel_file_path)?;
+        let initramfs = self.initramfs_path(kver).filter(|path| path.exists());
+        let initramfs_backup = initramfs.as_deref().map(copy).transpose()?;
+        println!("Backed up kernel {previous} to {}", dir.display());
+
+        state.backups.push(state::BackupRecord {
+            version: previous,
+            kernel: kernel_file_path.to_path_buf(),
+            initramfs,
+            kernel_backup,
+            initramfs_backup,
+            date: template::today(),
+        });
+        self.expire_backups(&mut state);
+        self.save_state(&state)
+    }
+
+    /// Removes the oldest backups beyond `backup-keep` with their copies
+    fn expire_backups(&self, state: &mut state::State) {
+        let expired = state.backups.len().saturating_sub(self.config.backup_keep);
+        for record in state.backups.drain(..expired).collect::<Vec<_>>() {
+            // a later backup of the same release reuses the same files
+            let files = std::iter::once(&record.kernel_backup)
+                .chain(record.initramfs_backup.as_ref())
+                .filter(|file| {
+                    !state.backups.iter().any(|kept| {
+                        kept.kernel_backup == **file
+                            || kept.initramfs_backup.as_ref() == Some(*file)
+                    })
+                });
+            for file in files {
+                let _ = std::fs::remove_file(file);
+            }
+            // only removed once empty
+            let _ = std::fs::remove_dir(self.config.backup_dir.join(&record.version));
+            println!("Removed backup of kernel {}", record.version);
+        }
+    }
+
+    pub(crate) fn record_install(
+        &self,
+        kver: &str,
+        snapshot: Option<u32>,
+    ) -> Result<Vec<state::ArtifactHash>, BuilderErr> {
+        let kernel = self.kernel_path(kver);
+        let initramfs = self
+            .initramfs_path(kver)
+            .filter(|path| !self.initramfs_less() && path.exists());
+        let uki = self.uki_path(kver).filter(|path| path.exists());
+
+        let mut artifacts = vec![kernel.clone()];
+        artifacts.extend(initramfs.clone());
+        artifacts.extend(uki.clone());
+        artifacts.extend(self.debug_artifacts(kver).into_iter().map(|(_, path)| path));
+        for dest in &self.config.destinations {
+            artifacts.push(self.render_path(&dest.kernel, kver));
+            artifacts.extend(
+                dest.initramfs
+                    .as_ref()
+                    .map(|path| self.render_path(path, kver)),
+            );
+        }
+        let hashes: Vec<state::ArtifactHash> = artifacts
+            .into_iter()
+            .filter(|path| path.exists())
+            .map(|path| sha256(self, &path).map(|sha256| state::ArtifactHash { path, sha256 }))
+            .collect::<Result<_, _>>()
+            .map_err(BuilderErr::StateError)?;
+
+        let mut state = self.load_state()?;
+        state.record_install(state::InstallRecord {
+            version: kver.to_string(),
+            kernel,
+            initramfs,
+            uki,
+            date: template::today(),
+            hashes: hashes.clone(),
+            booted: None,
+            snapshot,
+            path_date: Some(self.template_date(kver)),
+        });
+        self.save_state(&state)?;
+
+        Ok(hashes)
+    }
+
+    pub(crate) fn run_installkernel(&self, path: &Path, kver: &str) -> Result<(), BuilderErr> {
+        let kernel_file_path = self.kernel_path(kver);
+        let dir = kernel_file_path.parent().unwrap_or(Path::new("/boot"));
+        // attached to the terminal, the installkernel hooks may ask questions
+        let installed = self
+            .run_interactive(
+                &Invocation::new("installkernel")
+                    .arg(kver)
+                    .arg(path.join("arch/x86/boot/bzImage"))
+                    .arg(path.join("System.map"))
+                    .arg(dir),
+            )
+            .map_err(BuilderErr::KernelBuildFail)?;
+        if !installed {
+            return Err(BuilderErr::KernelBuildFail(io::Error::other(
+                "installkernel exited with an error",
+            )));
+        }
+        println!(
+            "Installed kernel {kver} with installkernel into {}",
+            dir.display()
+        );
+
+        Ok(())
+    }
+
+    /// Installs the kernel through systemd's `kernel-install add`, which lays out the boot loader
+    /// spec entries and runs the plugins in `/etc/kernel/install.d`.
+    pub(crate) fn run_kernel_install(&self, path: &Path, kver: &str) -> Result<(), BuilderErr> {
+        let installed = self
+            .run_interactive(
+                &Invocation::new("kernel-install")
+                    .args(["add", kver])
+                    .arg(path.join("arch/x86/boot/bzImage")),
+            )
+            .map_err(BuilderErr::KernelBuildFail)?;
+        if !installed {
+            return Err(BuilderErr::KernelBuildFail(io::Error::other(
+                "kernel-install exited with an error",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Installs a kernel handed over by `kernel-install add`: the image is copied to the configured
+    /// kernel path and destinations, the initramfs is taken over or generated, and the boot loader
+    /// gets updated. Without an image `/lib/modules/<kver>/vmlinuz` is used. Several initrds, e.g.
+    /// microcode and the initramfs, are concatenated in order into one image.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to copy the image or initramfs
+    /// - Failing generating initramfs
+    /// - Failing to update the boot loader
+    pub fn plugin_add(
+        &self,
+        kver: &str,
+        image: Option<&Path>,
+        initrds: &[PathBuf],
+    ) -> Result<(), BuilderErr> {
+        let image = image.map_or_else(
+            || Path::new(Self::MODULES_PATH).join(kver).join("vmlinuz"),
+            Path::to_path_buf,
+        );
+        let _mounts = self.mount_boot_partitions(kver)?;
+        let kernel_file_path = self.kernel_path(kver);
+        if self.config.backup && kernel_file_path.exists() {
+            self.backup_installed(kver, &kernel_file_path)?;
+        }
+        self.backup_old(&kernel_file_path)?;
+        atomic_copy(self, &image, &kernel_file_path).map_err(BuilderErr::KernelBuildFail)?;
+        println!("Installed kernel to {}", kernel_file_path.display());
+        self.copy_to_destinations("kernel", kver, &kernel_file_path, |dest| Some(&dest.kernel))?;
+
+        if let Some(initramfs_file_path) =
+            self.initramfs_path(kver).filter(|_| !self.initramfs_less())
+        {
+            match initrds {
+                [] => {
+                    #[cfg(feature = "dracut")]
+                    {
+                        let build = Path::new(Self::MODULES_PATH).join(kver).join("build");
+                        self.install_initramfs(kver, &build, &initramfs_file_path, true)?;
+                    }
+                }
+                [initrd] => {
+                    self.backup_old(&initramfs_file_path)?;
+                    atomic_copy(self, initrd, &initramfs_file_path)
+                        .map_err(BuilderErr::KernelBuildFail)?;
+                    println!("Installed initramfs to {}", initramfs_file_path.display());
+                }
+                initrds => {
+                    // cpio archives concatenate, the kernel unpacks them one after another
+                    let staging =
+                        tmp::TempDir::new("initrd").map_err(BuilderErr::KernelBuildFail)?;
+                    let combined = staging.join("initrd.img");
+                    concat(initrds, &combined).map_err(BuilderErr::KernelBuildFail)?;
+                    self.backup_old(&initramfs_file_path)?;
+                    atomic_copy(self, &combined, &initramfs_file_path)
+                        .map_err(BuilderErr::KernelBuildFail)?;
+                    println!(
+                        "Installed {} initrds as {}",
+                        initrds.len(),
+                        initramfs_file_path.display()
+                    );
+                }
+            }
+            if initramfs_file_path.exists() {
+                self.copy_to_destinations("initramfs", kver, &initramfs_file_path, |dest| {
+                    dest.initramfs.as_deref()
+                })?;
+            }
+        }
+
+        self.update_bootloaders(kver)?;
+
+        self.record_install(kver, None).map(|_| ())
+    }
+
+    /// Removes the artifacts of a kernel on `kernel-install remove`.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to remove an artifact
+    /// - Failing to update the state database
+    pub fn plugin_remove(&self, kver: &str) -> Result<(), BuilderErr> {
+        let _mounts = self.mount_boot_partitions(kver)?;
+        self.remove_artifacts(kver)
+    }
+
+    /// Removes source trees older than the running and all installed kernels, except the one
+    /// `/usr/src/linux` points to, each after confirmation. Trees still owned by a sources
+    /// package would be recreated by Portage, so the commands to drop the packages are printed.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to remove a source tree
+    pub fn prune_sources(&self) -> Result<(), BuilderErr> {
+        let Some(oldest) = Self::oldest_kernel() else {
+            println!("No installed kernels, keeping all source trees");
+            return Ok(());
+        };
+        let linked = self.linked_kernel();
+        let candidates: Vec<(&VersionEntry, version::KernelVersion, u64)> = self
+            .available_versions()
+            .iter()
+            .filter(|entry| {
+                !git::is_git_tree(&entry.path)
+                    && entry.version_string.strip_prefix("linux-") != linked.as_deref()
+            })
+            .filter_map(|entry| {
+                version::KernelVersion::parse(&entry.version_string)
+                    .filter(|kver| *kver < oldest)
+                    .map(|kver| (entry, kver, dir_size(&entry.path)))
+            })
+            .collect();
+
+        if candidates.is_empty() {
+            println!("Nothing to prune");
+            return Ok(());
+        }
+
+        let total: u64 = candidates.iter().map(|(_, _, size)| size).sum();
+        println!("Source trees older than all installed kernels:");
+        for (entry, _, size) in &candidates {
+            println!("  {} ({})", entry.path.display(), HumanBytes(*size));
+        }
+        println!("Total: {}", HumanBytes(total));
+
+        let mut packages = vec![];
+        for (entry, kver, size) in candidates {
+            if !self.confirm_prompt(&format!(
+                "Remove {} ({})?",
+                entry.path.display(),
+                HumanBytes(size)
+            ))? {
+                continue;
+            }
+
+            std::fs::remove_dir_all(&entry.path).map_err(BuilderErr::KernelBuildFail)?;
+            println!("Removed {}", entry.path.display());
+            packages.extend(portage::sources_package(&kver));
+        }
+
+        self.offer_depclean(&packages, false)
+    }
+
+    /// Oldest of the installed and the running kernel
+    fn oldest_kernel() -> Option<KernelVersion> {
+        Self::installed_kernels()
+            .iter()
+            .chain(running_kernel().as_ref())
+            .filter_map(|kver| KernelVersion::parse(kver))
+            .min()
+    }
+
+    /// Installed source packages older than every installed kernel, except the one
+    /// `/usr/src/linux` points to. Nothing boots from them anymore.
+    pub(crate) fn superseded_sources(&self) -> Vec<String> {
+        let Some(oldest) = Self::oldest_kernel() else {
+            return vec![];
+        };
+        let linked = self
+            .linked_kernel()
+            .and_then(|kver| KernelVersion::parse(&kver));
+
+        portage::installed_sources()
+            .into_iter()
+            .filter(|(_, version)| *version < oldest && Some(version) != linked.as_ref())
+            .map(|(package, _)| package)
+            .collect()
+    }
+
+    /// Offers to drop source packages from the world file and unmerge them, so Portage does not
+    /// keep or reinstall trees that were removed. Unattended runs only print the commands unless
+    /// `unattended` asks to run them.
+    pub(crate) fn offer_depclean(
+        &self,
+        packages: &[String],
+        unattended: bool,
+    ) -> Result<(), BuilderErr> {
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        let atoms: Vec<String> = packages
+            .iter()
+            .map(|package| format!("={package}"))
+            .collect();
+        let atoms: Vec<&str> = atoms.iter().map(String::as_str).collect();
+        println!("Source packages no longer needed:");
+        println!("  emerge --deselect {}", atoms.join(" "));
+        println!("  emerge --depclean {}", atoms.join(" "));
+        if self.assume_yes.get() && !unattended {
+            println!("Not running them unattended, run them by hand to remove the packages");
+            return Ok(());
+        }
+        if !self.confirm_or("Run these commands now?", unattended)? {
+            return Ok(());
+        }
+
+        for action in ["--deselect", "--depclean"] {
+            let args: Vec<&str> = std::iter::once(action)
+                .chain(atoms.iter().copied())
+                .collect();
+            portage::emerge(self, &args).map_err(|e| BuilderErr::EmergeFailed(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes old kernels with their modules in `/lib/modules` and their boot environments. All
+    /// releases except the newest `keep` ones, the running kernel, the last known good one and the
+    /// one `/usr/src/linux` points to are offered for removal, each after confirmation.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to remove an artifact or modules directory
+    /// - Failing to destroy a boot environment
+    /// - Failing to update the state database
+    pub fn prune(&self, keep: Option<usize>) -> Result<(), BuilderErr> {
+        let keep = keep.unwrap_or(self.config.prune_keep);
+        let running = running_kernel();
+        let linked = self.linked_kernel();
+        // never remove the kernel to fall back to when a new one does not boot
+        let good = self.load_state()?.last_known_good;
+        let candidates: Vec<String> = Self::installed_kernels()
+            .into_iter()
+            .rev()
+            .skip(keep)
+            .filter(|kver| {
+                Some(kver) != running.as_ref()
+                    && Some(kver) != linked.as_ref()
+                    && Some(kver) != good.as_ref()
+            })
+            .collect();
+
+        if candidates.is_empty() {
+            println!("Nothing to prune");
+            return Ok(());
+        }
+
+        for kver in candidates {
+            let modules = Path::new(Self::MODULES_PATH).join(&kver);
+            let size = dir_size(&modules);
+            if !self.confirm_prompt(&format!(
+                "Remove kernel {kver} and {} ({})?",
+                modules.display(),
+                HumanBytes(size)
+            ))? {
+                continue;
+            }
+
+            let _mounts = self.mount_boot_partitions(&kver)?;
+            self.remove_artifacts(&kver)?;
+            std::fs::remove_dir_all(&modules).map_err(BuilderErr::KernelBuildFail)?;
+            println!("Removed {}", modules.display());
+            self.remove_boot_environment(&kver)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the boot artifacts of a kernel release, also those of the destinations, with their
+    /// boot loader entries and forgets its install. Only versioned paths are removed, unversioned
+    /// ones belong to whatever kernel was installed last.
+    fn remove_artifacts(&self, kver: &str) -> Result<(), BuilderErr> {
+        let templates = [
+            Some(&self.config.kernel_file_path),
+            self.config.initramfs_file_path.as_ref(),
+            self.config.uki_file_path.as_ref(),
+        ];
+        let destinations = self
+            .config
+            .destinations
+            .iter()
+            .flat_map(|dest| [Some(&dest.kernel), dest.initramfs.as_ref()]);
+        let paths = templates
+            .into_iter()
+            .chain(destinations)
+            .flatten()
+            .filter(|template| template::is_versioned(template))
+            .map(|template| self.render_path(template, kver))
+            .chain(self.debug_artifacts(kver).into_iter().map(|(_, path)| path));
+        for path in paths {
+            if path.exists() {
+                std::fs::remove_file(&path).map_err(BuilderErr::KernelBuildFail)?;
+                println!("Removed {}", path.display());
+            }
+        }
+
+        self.remove_bootloader_entries(kver)?;
+
+        let mut state = self.load_state()?;
+        state.installs.retain(|install| install.version != kver);
+        self.save_state(&state)
+    }
+
+    /// Checks that the filesystems of all artifact paths are mounted. Copying into the empty
+    /// mountpoint of an unmounted `/boot` or ESP would silently install to the root filesystem, so
+    /// the user is offered to mount them until the returned guards are dropped.
+    pub(crate) fn mount_boot_partitions(
+        &self,
+        kver: &str,
+    ) -> Result<Vec<mounts::TemporaryMount<'_>>, BuilderErr> {
+        let mut paths = vec![self.kernel_path(kver)];
+        paths.extend(self.initramfs_path(kver));
+        paths.extend(self.uki_path(kver));
+        for dest in &self.config.destinations {
+            paths.push(self.render_path(&dest.kernel, kver));
+            paths.extend(
+                dest.initramfs
+                    .as_ref()
+                    .map(|path| self.render_path(path, kver)),
+            );
+        }
+
+        let mut mounted: Vec<mounts::TemporaryMount<'_>> = vec![];
+        let mut checked: Vec<PathBuf> = vec![];
+        for path in paths {
+            let Some(mount) = mounts::unmounted(&path) else {
+                continue;
+            };
+            if checked.contains(&mount.mountpoint) {
+                continue;
+            }
+            checked.push(mount.mountpoint.clone());
+
+            if !self.confirm_prompt(&format!(
+                "{} is not mounted, mount it for the install?",
+                mount.mountpoint.display()
+            ))? {
+                return Err(BuilderErr::NotMounted(mount.mountpoint));
+            }
+            mounted.push(
+                mounts::TemporaryMount::mount(self, &mount.mountpoint)
+                    .map_err(BuilderErr::KernelBuildFail)?,
+            );
+            println!("Mounted {}", mount.mountpoint.display());
+        }
+
+        Ok(mounted)
+    }
+
+    /// Warns about artifact paths the firmware or boot loader cannot read from
+    pub(crate) fn check_layout(&self, kver: &str) {
+        let layout = layout::BootLayout::detect(self);
+        let mut paths = vec![self.kernel_path(kver)];
+        paths.extend(self.initramfs_path(kver));
+        paths.extend(self.uki_path(kver));
+        for path in paths
+            .iter()
+            .filter(|path| !layout.on_boot_partition(self, path))
+        {
+            self.warn(format!(
+                "{} is not on a boot partition, the boot loader may not find it",
+                path.display()
+            ));
+        }
+
+        // images started by the firmware itself have to be on the ESP
+        let efi_image = (self.config.efi_stub
+            || self.config.bootloader == Some(Bootloader::Efibootmgr))
+        .then(|| self.efi_boot_files(kver).0);
+        if let Some(image) = efi_image.filter(|image| layout.uefi && !layout.on_esp(image)) {
+            self.warn(format!(
+                "{} is not on the EFI system partition, the firmware cannot boot it",
+                image.display()
+            ));
+        }
+    }
+
+    /// Secure Boot signing is enabled by configuring a key and certificate for `sbsign`, or by
+    /// selecting `sbctl` which manages its own keys.
+    pub(crate) fn signing_enabled(&self) -> bool {
+        match self.config.secure_boot_signer {
+            Signer::Sbsign => {
+                self.config.secure_boot_key.is_some() && self.config.secure_boot_cert.is_some()
+            }
+            Signer::Sbctl => true,
+        }
+    }
+
+    /// Signs an EFI image with the configured Secure Boot signer
+    pub(crate) fn sign(&self, image: &Path, output: &Path) -> Result<(), BuilderErr> {
+        match self.config.secure_boot_signer {
+            Signer::Sbsign => {
+                let (Some(key), Some(cert)) =
+                    (&self.config.secure_boot_key, &self.config.secure_boot_cert)
+                else {
+                    return Err(BuilderErr::SigningError("no key configured".into()));
+                };
+                signing::sbsign(self, image, output, key, cert)
+            }
+            Signer::Sbctl => signing::sbctl_sign(self, image, output),
+        }
+        .map_err(BuilderErr::SigningError)
+    }
+
+    /// Checks the Secure Boot signature of an installed image, so a broken signature is caught
+    /// before the firmware refuses to boot it. With `sbctl` the image is registered in its
+    /// database first, so `sbctl sign-all` covers it in the future.
+    pub(crate) fn verify_signature(&self, image: &Path) -> Result<(), BuilderErr> {
+        match self.config.secure_boot_signer {
+            Signer::Sbsign => {
+                let Some(cert) = &self.config.secure_boot_cert else {
+                    return Ok(());
+                };
+                signing::sbverify(self, image, cert)
+            }
+            Signer::Sbctl => signing::sbctl_register(self, image)
+                .and_then(|()| signing::sbctl_verify(self, image)),
+        }
+        .map_err(BuilderErr::SigningError)?;
+        println!("Verified Secure Boot signature of {}", image.display());
+
+        Ok(())
+    }
+
+    /// Copies an installed artifact to all additional destinations, reporting the result for each
+    /// of them. A failing destination does not stop the others from being updated.
+    pub(crate) fn copy_to_destinations(
+        &self,
+        label: &str,
+        kver: &str,
+        source: &Path,
+        target: impl Fn(&Destination) -> Option<&Path>,
+    ) -> Result<(), BuilderErr> {
+        let mut failed = vec![];
+        for template in self.config.destinations.iter().filter_map(target) {
+            let dest = self.render_path(template, kver);
+            let result = self.backup_old(&dest).and_then(|()| {
+                atomic_copy(self, source, &dest).map_err(BuilderErr::KernelBuildFail)
+            });
+            match result {
+                Ok(()) => println!("Installed {label} to {}", dest.display()),
+                Err(e) => {
+                    eprintln!("Failed installing {label} to {}: {e}", dest.display());
+                    failed.push(dest);
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BuilderErr::DestinationsFailed(failed))
+        }
+    }
+
+    /// Keeps an existing boot artifact as `<name>.<old-suffix>` before it gets overwritten, so
+    /// the previous version is only one rename away.
+    pub(crate) fn backup_old(&self, target: &Path) -> Result<(), BuilderErr> {
+        if !self.config.keep_old || !target.exists() {
+            return Ok(());
+        }
+
+        backup(self, target, &self.old_path(target)).map_err(BuilderErr::BackupError)
+    }
+
+    /// Path of the previous version of a boot artifact kept by `keep-old`
+    pub(crate) fn old_path(&self, target: &Path) -> PathBuf {
+        let mut old = target.as_os_str().to_owned();
+        old.push(format!(".{}", self.config.old_suffix));
+        PathBuf::from(old)
+    }
+
+    /// Puts the previous version of a boot artifact kept by `keep-old` back in place, returns
+    /// whether there was one
+    pub(crate) fn restore_old(&self, target: &Path) -> Result<bool, BuilderErr> {
+        let old = self.old_path(target);
+        if !old.exists() {
+            return Ok(false);
+        }
+
+        std::fs::rename(&old, target).map_err(BuilderErr::BackupError)?;
+        println!("Restored {}", target.display());
+
+        Ok(true)
+    }
+}
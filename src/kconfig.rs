@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Parsed kernel `.config` file
+#[derive(Debug, Default)]
+pub struct KernelConfig {
+    options: HashMap<String, String>,
+}
+
+impl KernelConfig {
+    /// Reads and parses a kernel `.config`, commented out options are treated as unset.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let options = content
+            .lines()
+            .filter_map(|line| line.strip_prefix("CONFIG_"))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.trim_matches('"').to_string()))
+            .collect();
+
+        Ok(Self { options })
+    }
+
+    /// Value of an option without the `CONFIG_` prefix
+    pub fn get(&self, option: &str) -> Option<&str> {
+        self.options.get(option).map(String::as_str)
+    }
+
+    /// Option is compiled into the kernel image
+    pub fn is_builtin(&self, option: &str) -> bool {
+        self.get(option) == Some("y")
+    }
+}
+
+/// Sets options in a kernel `.config`, replacing existing or disabled entries and appending the
+/// missing ones. Values have to be quoted by the caller where needed.
+pub fn set_options(path: &Path, options: &[(&str, &str)]) -> std::io::Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    let mut remaining = options.to_vec();
+    let mut lines = content
+        .lines()
+        .map(|line| {
+            let key = line
+                .strip_prefix("# CONFIG_")
+                .and_then(|rest| rest.strip_suffix(" is not set"))
+                .or_else(|| {
+                    line.strip_prefix("CONFIG_")
+                        .and_then(|rest| rest.split_once('=').map(|(key, _)| key))
+                });
+            match key.and_then(|key| remaining.iter().position(|(k, _)| *k == key)) {
+                Some(idx) => {
+                    let (key, value) = remaining.remove(idx);
+                    format!("CONFIG_{key}={value}")
+                }
+                None => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+    lines.extend(
+        remaining
+            .into_iter()
+            .map(|(key, value)| format!("CONFIG_{key}={value}")),
+    );
+
+    std::fs::write(path, lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmp;
+
+    #[test]
+    fn set_options_replaces_enables_and_appends() {
+        let dir = tmp::TempDir::new("kconfig-test").unwrap();
+        let path = dir.join(".config");
+        std::fs::write(
+            &path,
+            "# General setup\nCONFIG_LOCALVERSION=\"\"\n# CONFIG_EFI_STUB is not set\nCONFIG_EFI=y\n",
+        )
+        .unwrap();
+
+        set_options(
+            &path,
+            &[
+                ("EFI_STUB", "y"),
+                ("LOCALVERSION", "\"-rt\""),
+                ("CMDLINE_BOOL", "y"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# General setup\nCONFIG_LOCALVERSION=\"-rt\"\nCONFIG_EFI_STUB=y\nCONFIG_EFI=y\n\
+             CONFIG_CMDLINE_BOOL=y\n"
+        );
+        let config = KernelConfig::load(&path).unwrap();
+        assert!(config.is_builtin("EFI_STUB"));
+        assert_eq!(config.get("LOCALVERSION"), Some("-rt"));
+    }
+}
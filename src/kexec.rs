@@ -0,0 +1,69 @@
+use crate::{reboot, BuilderErr, CommandRunner, Invocation, KernelBuilder};
+
+impl KernelBuilder {
+    /// Loads the installed kernel and initramfs with `kexec -l` to verify the running kernel
+    /// accepts the image, then offers to reboot into it right away through the init system. If
+    /// declined or the reboot fails, the image is unloaded again.
+    pub(crate) fn kexec_smoke_test(&self, kver: &str) -> Result<(), BuilderErr> {
+        self.kexec_load(kver)?;
+        println!("Kernel image was loaded successfully with kexec");
+
+        if !self.assume_yes.get()
+            && self.confirm_prompt("Reboot into the new kernel with kexec now?")?
+        {
+            let rebooted = self.kexec_reboot(kver);
+            if rebooted.is_err() {
+                let _ = self.kexec(&["-u"]);
+            }
+            return rebooted;
+        }
+
+        self.kexec(&["-u"])
+    }
+
+    /// Loads the installed kernel and initramfs and reboots into it with kexec. The init system
+    /// shuts the system down cleanly first, with `systemctl kexec` or `openrc-shutdown --kexec`.
+    /// Other init systems are refused, as jumping into the kernel right away would skip stopping
+    /// the services and unmounting the filesystems.
+    pub(crate) fn kexec_reboot(&self, kver: &str) -> Result<(), BuilderErr> {
+        let shutdown = if reboot::systemd_running() {
+            Invocation::new("systemctl").arg("kexec")
+        } else if reboot::openrc_running() {
+            Invocation::new("openrc-shutdown").args(["--kexec", "now"])
+        } else {
+            return Err(BuilderErr::KexecError(
+                "kexec reboot needs systemd or OpenRC to shut the system down".into(),
+            ));
+        };
+
+        self.kexec_load(kver)?;
+        println!("Rebooting into {kver} with kexec");
+        self.run_attached(&shutdown)
+            .map_err(|e| BuilderErr::KexecError(format!("shutting down for kexec failed: {e}")))
+    }
+
+    fn kexec_load(&self, kver: &str) -> Result<(), BuilderErr> {
+        let mut args = vec![
+            "-l".to_string(),
+            self.kernel_path(kver).to_string_lossy().to_string(),
+        ];
+        if let Some(initramfs) = self
+            .initramfs_path(kver)
+            .filter(|path| !self.initramfs_less() && path.exists())
+        {
+            args.push(format!("--initrd={}", initramfs.display()));
+        }
+        match self.kernel_cmdline()? {
+            Some(cmdline) => args.push(format!("--command-line={cmdline}")),
+            None => args.push("--reuse-cmdline".into()),
+        }
+
+        self.kexec(&args.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    fn kexec(&self, args: &[&str]) -> Result<(), BuilderErr> {
+        self.run(&Invocation::new("kexec").args(args))
+            .map(drop)
+            .map_err(|e| BuilderErr::KexecError(e.to_string()))
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{mounts, Bootloader, CommandRunner, Invocation};
+use std::path::{Path, PathBuf};
+
+/// GPT partition type of an EFI system partition
+const ESP_TYPE: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+/// GPT partition type of an extended boot loader partition
+const XBOOTLDR_TYPE: &str = "bc13c2ff-59e6-4262-a352-b275fd6f7172";
+
+/// Partitions the firmware and boot loader read the kernel from
+#[derive(Debug, Clone, Default)]
+pub struct BootLayout {
+    /// Booted in UEFI mode
+    pub uefi: bool,
+    /// Mountpoint of the EFI system partition
+    pub esp: Option<PathBuf>,
+    /// Mountpoint of a separate `/boot` partition, which may be the ESP or an XBOOTLDR partition
+    pub boot: Option<PathBuf>,
+}
+
+impl BootLayout {
+    /// Detects the layout from the mounted and configured filesystems, identifying the ESP by its
+    /// partition type and falling back to vfat filesystems on the usual mountpoints.
+    pub fn detect(runner: &dyn CommandRunner) -> Self {
+        let mut candidates = mounts::all();
+        candidates.extend(mounts::fstab());
+
+        let esp = candidates
+            .iter()
+            .find(|mount| partition_type(runner, &mount.device).as_deref() == Some(ESP_TYPE))
+            .or_else(|| {
+                ["/efi", "/boot/efi", "/boot"]
+                    .iter()
+                    .find_map(|mountpoint| {
+                        candidates.iter().find(|mount| {
+                            mount.fstype == "vfat" && mount.mountpoint == Path::new(mountpoint)
+                        })
+                    })
+            })
+            .map(|mount| mount.mountpoint.clone());
+        let boot = candidates
+            .iter()
+            .find(|mount| mount.mountpoint == Path::new("/boot"))
+            .map(|mount| mount.mountpoint.clone());
+
+        Self {
+            uefi: Path::new("/sys/firmware/efi").is_dir(),
+            esp,
+            boot,
+        }
+    }
+
+    /// Directory kernels and initramfs images should be installed to
+    pub fn boot_dir(&self) -> PathBuf {
+        self.boot.clone().unwrap_or_else(|| PathBuf::from("/boot"))
+    }
+
+    /// Checks if the path is on the ESP
+    pub fn on_esp(&self, path: &Path) -> bool {
+        self.esp.as_ref().is_some_and(|esp| {
+            path.starts_with(esp) && mounts::find(path).is_none_or(|mount| &mount.mountpoint == esp)
+        })
+    }
+
+    /// Checks if the path is on a partition the boot loader can read, i.e. the ESP, `/boot` or
+    /// an extended boot loader partition. `/boot` on the root filesystem counts as well.
+    pub fn on_boot_partition(&self, runner: &dyn CommandRunner, path: &Path) -> bool {
+        if self.on_esp(path) || path.starts_with(self.boot_dir()) {
+            return true;
+        }
+
+        mounts::find(path).is_some_and(|mount| {
+            partition_type(runner, &mount.device).as_deref() == Some(XBOOTLDR_TYPE)
+        })
+    }
+
+    /// Guesses the installed boot loader from its files
+    pub fn bootloader(&self) -> Option<Bootloader> {
+        let esp = self.esp.clone().unwrap_or_default();
+        if esp.join("loader/loader.conf").exists() {
+            Some(Bootloader::SystemdBoot)
+        } else if esp.join("EFI/refind").is_dir() {
+            Some(Bootloader::Refind)
+        } else if self.boot_dir().join("grub/grub.cfg").exists() {
+            Some(Bootloader::Grub)
+        } else {
+            None
+        }
+    }
+}
+
+/// Resolves `UUID=`, `PARTUUID=` and `LABEL=` device specs of `/etc/fstab`
+fn resolve_device(device: &Path) -> PathBuf {
+    let spec = device.to_string_lossy();
+    [
+        ("UUID=", "/dev/disk/by-uuid"),
+        ("PARTUUID=", "/dev/disk/by-partuuid"),
+        ("LABEL=", "/dev/disk/by-label"),
+    ]
+    .iter()
+    .find_map(|(prefix, dir)| {
+        spec.strip_prefix(prefix)
+            .map(|id| Path::new(dir).join(id.trim_matches('"')))
+    })
+    .unwrap_or_else(|| device.to_path_buf())
+}
+
+/// GPT partition type GUID of a block device in lowercase
+fn partition_type(runner: &dyn CommandRunner, device: &Path) -> Option<String> {
+    let parttype = runner
+        .run(
+            &Invocation::new("lsblk")
+                .args(["--nodeps", "--noheadings", "--output", "PARTTYPE"])
+                .arg(resolve_device(device)),
+        )
+        .ok()?
+        .trim()
+        .to_lowercase();
+    (!parttype.is_empty()).then_some(parttype)
+}
@@ -1,360 +1,109 @@
-use dialoguer::theme::ColorfulTheme;
-use dialoguer::Select;
-use dialoguer::{console::Term, Confirm};
-use indicatif::ProgressBar;
-use serde::Deserialize;
-use std::io::{BufRead, BufReader};
-use std::num::NonZeroUsize;
-use std::{
-    os::unix,
-    path::{Path, PathBuf},
-    process::{Command, Stdio},
-    time::Duration,
-};
-
+mod archive;
+mod binpkg;
+pub use binpkg::Binpkg;
+mod bootloader;
+mod build;
+pub use bootloader::{Bootloader, GrubDefault, RefindVariant};
+pub use signing::Signer;
+mod deploy;
+pub use deploy::Deploy;
+mod discover;
+use discover::VersionEntry;
+mod efi;
 mod error;
+mod eselect;
+mod export;
+mod external;
+pub use external::ModuleBuild;
+mod fetch;
+mod git;
+mod hooks;
 pub use error::BuilderErr;
+pub use hooks::Hooks;
+mod changelog;
 mod cli;
-pub use cli::Args;
-
-#[derive(Debug, Deserialize)]
-pub struct KBConfig {
-    /// Path to the kernel bz image on the boot partition
-    #[serde(rename = "kernel")]
-    pub kernel_file_path: PathBuf,
-    /// Path to the initramfs on the boot partition
-    #[serde(rename = "initramfs")]
-    pub initramfs_file_path: Option<PathBuf>,
-    /// path to the `.config` file that will be symlinked
-    #[serde(rename = "kernel-config")]
-    pub kernel_config_file_path: PathBuf,
-    /// path to the kernel sources
-    #[serde(rename = "kernel-src")]
-    pub kernel_src: PathBuf,
-    #[serde(rename = "keep-last-kernel")]
-    pub keep_last_kernel: bool,
-    #[serde(rename = "last-kernel-suffix")]
-    pub last_kernel_suffix: Option<String>,
-}
-
-#[derive(Clone, Debug)]
-struct VersionEntry {
-    path: PathBuf,
-    version_string: String,
-}
+mod cmdline;
+mod compat;
+mod config;
+pub use config::{
+    init_config, Destination, Flavor, InitramfsCompression, InstallMode, KBConfig, UkiGenerator,
+};
+#[cfg(feature = "dracut")]
+mod initramfs;
+mod install;
+mod kconfig;
+mod kexec;
+mod layout;
+#[cfg(feature = "dracut")]
+mod microcode;
+mod modules;
+mod mounts;
+mod options;
+pub use options::{BuildOptions, KernelBuilderOptions};
+mod patches;
+mod paths;
+mod pattern;
+mod pipeline;
+mod portage;
+pub use portage::PortageHook;
+mod qemu;
+mod reboot;
+mod report;
+pub use report::{BuildReport, StepReport};
+mod releases;
+pub use qemu::BootResult;
+mod rootfs;
+mod runner;
+pub use runner::{CommandRunner, Invocation, Output, RecordingRunner, SystemRunner};
+mod signing;
+mod snapshot;
+mod state;
+mod status;
+pub use state::ArtifactHash;
+mod template;
+mod tmp;
+mod ui;
+pub use ui::{Interactive, NonInteractive, Progress, Prompter, Silent, Spinner};
+mod version;
+pub use cli::{Args, CmdlineAction, KernelInstallAction, Subcommand};
+pub use version::{running_kernel, KernelVersion};
 
 #[derive(Debug)]
 pub struct KernelBuilder {
     config: KBConfig,
-    versions: Vec<VersionEntry>,
+    versions: std::cell::OnceCell<Vec<VersionEntry>>,
+    flavor: Option<String>,
+    verbosity: Verbosity,
+    assume_yes: std::cell::Cell<bool>,
+    /// make jobs, the available CPUs if unset
+    jobs: Option<std::num::NonZeroUsize>,
+    prompter: Box<dyn Prompter>,
+    progress: Box<dyn Progress>,
+    runner: Box<dyn CommandRunner>,
+    /// Steps finished in this run and how long they took, for the hook context and the report
+    timings: std::cell::RefCell<Vec<StepReport>>,
+    /// Warnings shown in this run, for the build report
+    warnings: std::cell::RefCell<Vec<String>>,
+    /// Report of the most recent build, also kept when it failed
+    last_report: std::cell::RefCell<Option<BuildReport>>,
+    /// Day the current build started on, `{date}` of a kernel not installed yet
+    run_date: std::cell::RefCell<Option<String>>,
+}
+
+/// Amount of output shown from external tools
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only errors
+    Quiet,
+    /// Warnings and errors, other output only updates the spinner
+    #[default]
+    Normal,
+    /// Everything
+    Verbose,
 }
 
 impl KernelBuilder {
     pub const LINUX_PATH: &'static str = "/usr/src";
-
-    #[must_use]
-    pub fn new(config: KBConfig) -> Self {
-        let mut builder = Self {
-            config,
-            versions: vec![],
-        };
-        builder.get_available_version();
-
-        builder
-    }
-
-    fn get_available_version(&mut self) {
-        if self.versions.is_empty() {
-            if let Ok(directories) = std::fs::read_dir(&self.config.kernel_src) {
-                self.versions = directories
-                    .filter_map(|dir| dir.ok().map(|d| d.path()))
-                    .filter(|path| path.starts_with(&self.config.kernel_src) && !path.is_symlink())
-                    .filter_map(|path| {
-                        path.strip_prefix(&self.config.kernel_src)
-                            .ok()
-                            .and_then(|p| {
-                                let tmp = p.to_owned();
-                                let version_string = tmp.to_string_lossy();
-                                version_string
-                                    .starts_with("linux-")
-                                    .then_some(VersionEntry {
-                                        path: path.clone(),
-                                        version_string: version_string.to_string(),
-                                    })
-                            })
-                    })
-                    .collect::<Vec<_>>();
-            }
-        }
-    }
-
-    ///
-    /// # Errors
-    ///
-    /// - Error on missing kernel config
-    /// - Failing creating symlinks
-    /// - Failing kernel build
-    ///
-    /// if selected:
-    /// - Failing installing kernel modules
-    /// - Failing generating initramfs
-    pub fn build(&self, cli: &Args) -> Result<(), BuilderErr> {
-        let Some(version_entry) = self.prompt_for_kernel_version() else {
-            return Ok(());
-        };
-
-        let VersionEntry {
-            path,
-            version_string,
-        } = &version_entry;
-
-        // create symlink from /usr/src/.config
-        let link = path.join(".config");
-        if !link.exists() {
-            let dot_config = &self.config.kernel_config_file_path;
-            if !dot_config.exists() || !dot_config.is_file() {
-                return Err(BuilderErr::KernelConfigMissing);
-            }
-
-            unix::fs::symlink(dot_config, link).map_err(BuilderErr::LinkingFileError)?;
-        }
-
-        let linux = PathBuf::from(&self.config.kernel_src).join("linux");
-        let linux_target = linux.read_link().map_err(BuilderErr::LinkingFileError)?;
-
-        if linux_target.to_string_lossy() != *version_string {
-            std::fs::remove_file(&linux).map_err(BuilderErr::LinkingFileError)?;
-            unix::fs::symlink(path, linux).map_err(BuilderErr::LinkingFileError)?;
-        }
-
-        if cli.menuconfig {
-            Self::make_menuconfig(path)?;
-            if !Self::confirm_prompt("Continue build process?")? {
-                return Ok(());
-            }
-        }
-
-        if !cli.no_build {
-            self.build_kernel(path, cli.replace)?;
-        }
-
-        if !cli.no_modules && Self::confirm_prompt("Do you want to install kernel modules?")? {
-            Self::install_kernel_modules(path)?;
-        }
-
-        #[cfg(feature = "dracut")]
-        if !cli.no_initramfs
-            && Self::confirm_prompt("Do you want to generate initramfs with dracut?")?
-        {
-            self.generate_initramfs(&version_entry, cli.replace)?;
-        }
-        Ok(())
-    }
-
-    fn build_kernel(&self, path: &Path, replace: bool) -> Result<(), BuilderErr> {
-        let new_flags = Command::new("make")
-            .arg("listnewconfigs")
-            .current_dir(path)
-            .output()
-            .map_err(BuilderErr::KernelBuildFail)?;
-
-        if !new_flags.stdout.is_empty() {
-            let make_oldconfig = Command::new("make")
-                .arg("oldconfig")
-                .current_dir(path)
-                .stdin(Stdio::inherit()) // Allow interaction with the terminal for input
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::piped())
-                .spawn()
-                .map_err(BuilderErr::KernelBuildFail)?;
-
-            if let Some(stderr) = make_oldconfig.stderr {
-                let reader = BufReader::new(stderr);
-                for line in reader.lines() {
-                    let line = line.expect("Failed to read error line");
-                    eprintln!("{line}");
-                }
-            }
-        }
-
-        let threads: NonZeroUsize =
-            std::thread::available_parallelism().unwrap_or(NonZeroUsize::new(1).unwrap());
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(Duration::from_millis(120));
-        let mut cmd = Command::new("make")
-            .current_dir(path)
-            .args(["-j", &threads.to_string()])
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()
-            .map_err(BuilderErr::KernelBuildFail)?;
-
-        {
-            let stdout = cmd.stdout.as_mut().unwrap();
-            let stdout_reader = BufReader::new(stdout);
-            let stdout_lines = stdout_reader.lines();
-
-            for line in stdout_lines {
-                let line = line
-                    .map_err(BuilderErr::KernelBuildFail)?
-                    .to_ascii_lowercase();
-                pb.set_message(format!("Compiling kernel: {line}"));
-            }
-        }
-
-        cmd.wait().map_err(BuilderErr::KernelBuildFail)?;
-
-        pb.finish_with_message("Finished compiling Kernel");
-
-        if self.config.keep_last_kernel && !replace {
-            let path = self.config.kernel_file_path.clone();
-            let mut filename = path
-                .file_name()
-                .map(|p| p.to_string_lossy().to_string())
-                .expect("could not get filename of kernel file path");
-            let suff = format!(
-                "-{}",
-                self.config
-                    .last_kernel_suffix
-                    .clone()
-                    .unwrap_or(String::from("prev"))
-            );
-            filename.push_str(&suff);
-            let path = path.with_file_name(filename);
-
-            std::fs::copy(self.config.kernel_file_path.clone(), path)
-                .map_err(BuilderErr::KernelBuildFail)?;
-        }
-
-        std::fs::copy(
-            path.join("arch/x86/boot/bzImage"),
-            self.config.kernel_file_path.clone(),
-        )
-        .map_err(BuilderErr::KernelBuildFail)?;
-
-        Ok(())
-    }
-
-    fn make_menuconfig(path: &Path) -> Result<(), BuilderErr> {
-        let mut cmd = Command::new("make")
-            .current_dir(path)
-            .arg("menuconfig")
-            .spawn()
-            .map_err(|_| BuilderErr::MenuconfigError)?;
-
-        cmd.wait().map_err(|_| BuilderErr::MenuconfigError)?;
-
-        Ok(())
-    }
-
-    fn install_kernel_modules(path: &Path) -> Result<(), BuilderErr> {
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(Duration::from_millis(120));
-        pb.set_message("Install kernel modules");
-        Command::new("make")
-            .current_dir(path)
-            .arg("modules_install")
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .map_err(BuilderErr::KernelBuildFail)?
-            .wait()
-            .map_err(BuilderErr::KernelBuildFail)?;
-        pb.finish_with_message("Finished installing modules");
-
-        Ok(())
-    }
-
-    #[cfg(feature = "dracut")]
-    fn generate_initramfs(
-        &self,
-        VersionEntry {
-            path,
-            version_string,
-        }: &VersionEntry,
-        replace: bool,
-    ) -> Result<(), BuilderErr> {
-        let initramfs_file_path = &self
-            .config
-            .initramfs_file_path
-            .clone()
-            .ok_or(BuilderErr::KernelConfigMissingOption("initramfs".into()))?;
-
-        if self.config.keep_last_kernel && !replace {
-            let mut filename = initramfs_file_path
-                .file_stem()
-                .map(|p| p.to_string_lossy().to_string())
-                .expect("could not get filename of initramfs file path");
-            let suff = format!(
-                "-{}.img",
-                self.config
-                    .last_kernel_suffix
-                    .clone()
-                    .unwrap_or(String::from("prev"))
-            );
-            filename.push_str(&suff);
-            let path = initramfs_file_path.with_file_name(filename);
-
-            std::fs::copy(initramfs_file_path, path).map_err(BuilderErr::KernelBuildFail)?;
-        }
-
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(Duration::from_millis(120));
-        let mut cmd = Command::new("dracut")
-            .current_dir(path)
-            .args([
-                "--hostonly",
-                "--kver",
-                version_string.strip_prefix("linux-").unwrap(),
-                "--force",
-                initramfs_file_path.to_string_lossy().as_ref(),
-            ])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()
-            .map_err(BuilderErr::KernelBuildFail)?;
-
-        {
-            let stdout = cmd.stdout.as_mut().unwrap();
-            let stdout_reader = BufReader::new(stdout);
-            let stdout_lines = stdout_reader.lines();
-
-            for line in stdout_lines {
-                pb.set_message(format!(
-                    "Generating initramfs: {}",
-                    line.map_err(BuilderErr::KernelBuildFail)?
-                ));
-            }
-        }
-
-        cmd.wait().map_err(BuilderErr::KernelBuildFail)?;
-        pb.finish_with_message("Finished initramfs");
-
-        Ok(())
-    }
-
-    fn prompt_for_kernel_version(&self) -> Option<VersionEntry> {
-        let versions = self
-            .versions
-            .clone()
-            .into_iter()
-            .map(|v| v.version_string)
-            .collect::<Vec<_>>();
-
-        Select::with_theme(&ColorfulTheme::default())
-            .with_prompt("Pick version to build and install")
-            .items(versions.as_slice())
-            .default(versions.len().saturating_sub(1)) // select the last entry
-            .interact_on_opt(&Term::stderr())
-            .ok()
-            .flatten()
-            .map(|selection| self.versions[selection].clone())
-    }
-
-    fn confirm_prompt(message: &str) -> Result<bool, BuilderErr> {
-        Confirm::new()
-            .with_prompt(message)
-            .interact()
-            .map_err(BuilderErr::PromptError)
-    }
+    pub const CMDLINE_PATH: &'static str = "/etc/kernel/cmdline";
+    pub const MODULES_PATH: &'static str = "/lib/modules";
 }
@@ -1,8 +1,9 @@
 use config::{Config, Environment, File};
-use kernel_builder::{BuilderErr, KBConfig, KernelBuilder};
-use std::path::PathBuf;
+use kernel_builder::{BootResult, BuilderErr, KBConfig, KernelBuilder, Spinner};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use kernel_builder::Args;
+use kernel_builder::{Args, CmdlineAction, KernelInstallAction, Subcommand};
 
 fn main() -> Result<(), BuilderErr> {
     let mut settings_path = if let Ok(xdg_env) = std::env::var("XDG_CONFIG_HOME") {
@@ -11,17 +12,145 @@ fn main() -> Result<(), BuilderErr> {
         PathBuf::from(std::env!("HOME")).join(".config")
     };
     settings_path.push("kernel-builder/config");
+
+    let cli_args = Args::parse_args();
+    // there is no config to load yet
+    if cli_args.subcommand == Some(Subcommand::Init) {
+        return kernel_builder::init_config(
+            &kernel_builder::SystemRunner,
+            &settings_path.with_extension("toml"),
+        );
+    }
+
     let settings = Config::builder()
         .add_source(File::with_name(settings_path.to_string_lossy().as_ref()).required(true))
         .add_source(Environment::with_prefix("KB"))
         .build()?;
 
     let config = settings.try_deserialize::<KBConfig>()?;
-    let kernel_builder = KernelBuilder::new(config);
-
-    let cli_args = Args::parse_args();
-    sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
-    kernel_builder.build(&cli_args)?;
+    let mut builder = KernelBuilder::builder(config)
+        .verbosity(cli_args.verbosity)
+        .assume_yes(cli_args.yes)
+        .progress(Box::new(Spinner::default()));
+    if let Some(flavor) = &cli_args.flavor {
+        builder = builder.flavor(flavor);
+    }
+    let mut kernel_builder = builder.build()?;
+    match cli_args.subcommand {
+        Some(Subcommand::Cmdline(CmdlineAction::Show)) => match kernel_builder.kernel_cmdline()? {
+            Some(cmdline) => println!("{cmdline}"),
+            None => eprintln!("No kernel command line configured"),
+        },
+        Some(Subcommand::Cmdline(CmdlineAction::Edit)) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            kernel_builder.edit_cmdline()?;
+        }
+        #[cfg(feature = "dracut")]
+        Some(Subcommand::Initramfs {
+            rescue,
+            all,
+            ref kver,
+        }) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            if rescue {
+                kernel_builder.generate_rescue_initramfs()?;
+            } else if all {
+                kernel_builder.regenerate_all_initramfs()?;
+            } else {
+                kernel_builder.regenerate_initramfs(kver.as_deref())?;
+            }
+        }
+        Some(Subcommand::TestBoot { ref kver, timeout }) => {
+            if kernel_builder.test_boot(kver.as_deref(), Duration::from_secs(timeout))?
+                != BootResult::Passed
+            {
+                return Err(BuilderErr::BootTestFailed);
+            }
+        }
+        Some(Subcommand::Init) => unreachable!("handled before loading the config"),
+        Some(Subcommand::Auto) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            kernel_builder.set_assume_yes(true);
+            let result = kernel_builder.auto(&cli_args);
+            result.and(write_report(&kernel_builder, cli_args.report.as_deref()))?;
+        }
+        Some(Subcommand::Hook { from_portage }) => {
+            if !from_portage {
+                eprintln!("hook is only meant to be run with --from-portage");
+                return Ok(());
+            }
+            kernel_builder.set_assume_yes(true);
+            kernel_builder.portage_hook(&cli_args)?;
+        }
+        Some(Subcommand::Fetch { ref version }) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            kernel_builder.fetch(version)?;
+        }
+        Some(Subcommand::Prune { keep, sources }) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            if sources {
+                kernel_builder.prune_sources()?;
+            } else {
+                kernel_builder.prune(keep)?;
+            }
+        }
+        Some(Subcommand::Verify) => kernel_builder.verify()?,
+        Some(Subcommand::Export {
+            ref kver,
+            ref output,
+        }) => kernel_builder.export(kver.as_deref(), output.as_deref())?,
+        Some(Subcommand::Binpkg { ref kver }) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            kernel_builder.binpkg(kver.as_deref())?;
+        }
+        Some(Subcommand::Import { ref archive }) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            kernel_builder.import(archive)?;
+        }
+        Some(Subcommand::Status { json }) => kernel_builder.status(json)?,
+        Some(Subcommand::List { json }) => kernel_builder.list(json)?,
+        Some(Subcommand::Alias {
+            ref name,
+            ref target,
+            remove,
+        }) => {
+            if remove || target.is_some() {
+                sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            }
+            kernel_builder.alias(name.as_deref(), target.as_deref(), remove)?;
+        }
+        Some(Subcommand::MarkGood) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            kernel_builder.mark_good()?;
+        }
+        Some(Subcommand::KernelInstall {
+            action,
+            ref kver,
+            ref image,
+            ref initrds,
+        }) => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            match action {
+                KernelInstallAction::Add => {
+                    kernel_builder.plugin_add(kver, image.as_deref(), initrds)?;
+                }
+                KernelInstallAction::Remove => kernel_builder.plugin_remove(kver)?,
+            }
+        }
+        None => {
+            sudo::escalate_if_needed().map_err(|_| BuilderErr::NoPrivileges)?;
+            let result = kernel_builder.build(&cli_args);
+            result.and(write_report(&kernel_builder, cli_args.report.as_deref()))?;
+        }
+    }
 
     Ok(())
 }
+
+/// Writes the report of the last build with `--report`, also when the build failed
+fn write_report(kernel_builder: &KernelBuilder, path: Option<&Path>) -> Result<(), BuilderErr> {
+    match (kernel_builder.last_report(), path) {
+        (Some(report), Some(path)) => report.write(path),
+        _ => Ok(()),
+    }
+}
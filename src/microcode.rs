@@ -0,0 +1,72 @@
+use std::io::Read;
+use std::path::Path;
+
+/// Cpu vendors that ship early microcode updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuVendor {
+    Intel,
+    Amd,
+}
+
+impl CpuVendor {
+    /// Detects the vendor of the running cpu from `/proc/cpuinfo`.
+    pub fn detect() -> Option<Self> {
+        let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+        cpuinfo
+            .lines()
+            .find(|line| line.starts_with("vendor_id"))
+            .and_then(|line| line.split(':').nth(1))
+            .and_then(|vendor| match vendor.trim() {
+                "GenuineIntel" => Some(Self::Intel),
+                "AuthenticAMD" => Some(Self::Amd),
+                _ => None,
+            })
+    }
+
+    /// Package that provides the microcode for this vendor
+    pub fn package(self) -> &'static str {
+        match self {
+            Self::Intel => "sys-firmware/intel-microcode",
+            Self::Amd => "sys-kernel/linux-firmware",
+        }
+    }
+
+    /// Firmware directory dracut picks the microcode blobs from
+    pub fn firmware_dir(self) -> &'static Path {
+        match self {
+            Self::Intel => Path::new("/lib/firmware/intel-ucode"),
+            Self::Amd => Path::new("/lib/firmware/amd-ucode"),
+        }
+    }
+
+    /// Name of the microcode file inside the early cpio archive
+    pub fn cpio_entry(self) -> &'static str {
+        match self {
+            Self::Intel => "kernel/x86/microcode/GenuineIntel.bin",
+            Self::Amd => "kernel/x86/microcode/AuthenticAMD.bin",
+        }
+    }
+
+    /// Checks if microcode blobs for this vendor are installed.
+    pub fn microcode_installed(self) -> bool {
+        std::fs::read_dir(self.firmware_dir())
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false)
+    }
+}
+
+/// Checks if the initramfs starts with an uncompressed early cpio that contains the microcode
+/// for the given vendor. The early cpio is always placed at the start of the image, so only the
+/// first few kilobytes need to be inspected.
+pub fn initramfs_has_microcode(initramfs: &Path, vendor: CpuVendor) -> std::io::Result<bool> {
+    let mut header = Vec::with_capacity(8192);
+    std::fs::File::open(initramfs)?
+        .take(8192)
+        .read_to_end(&mut header)?;
+
+    let needle = vendor.cpio_entry().as_bytes();
+    Ok(
+        header.starts_with(b"070701")
+            && header.windows(needle.len()).any(|window| window == needle),
+    )
+}
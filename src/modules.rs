@@ -0,0 +1,82 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Module names compare equal with dashes and underscores mixed, like the kernel does
+fn normalize(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Modules loaded in the running kernel, as listed by `lsmod`
+pub fn loaded() -> Vec<String> {
+    std::fs::read_to_string("/proc/modules")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(normalize)
+        .collect()
+}
+
+/// Modules a kernel release provides, loadable from its module tree or built in
+pub fn available(modules_dir: &Path) -> HashSet<String> {
+    ["modules.dep", "modules.builtin"]
+        .iter()
+        .filter_map(|index| std::fs::read_to_string(modules_dir.join(index)).ok())
+        .flat_map(|index| {
+            index
+                .lines()
+                .filter_map(|line| {
+                    let path = line.split(':').next()?;
+                    let file = path.rsplit('/').next()?;
+                    file.split(".ko").next().map(normalize)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Loaded modules the kernel release in `modules_dir` does not provide, sorted by name. Empty
+/// if the tree has no index to compare against.
+pub fn missing_loaded(modules_dir: &Path) -> Vec<String> {
+    let available = available(modules_dir);
+    if available.is_empty() {
+        return vec![];
+    }
+
+    let mut missing: Vec<String> = loaded()
+        .into_iter()
+        .filter(|module| !available.contains(module))
+        .collect();
+    missing.sort();
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmp;
+
+    #[test]
+    fn available_reads_loadable_and_builtin_modules() {
+        let dir = tmp::TempDir::new("modules-test").unwrap();
+        std::fs::write(
+            dir.join("modules.dep"),
+            "kernel/drivers/gpu/drm/amd/amdgpu/amdgpu.ko.zst: kernel/drivers/gpu/drm/drm.ko.zst\n\
+             kernel/drivers/net/ethernet/realtek/r8169.ko:\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("modules.builtin"), "kernel/fs/btrfs/btrfs.ko\n").unwrap();
+
+        let modules = available(dir.path());
+
+        let mut names: Vec<&str> = modules.iter().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["amdgpu", "btrfs", "r8169"]);
+        assert!(available(&dir.join("missing")).is_empty());
+    }
+
+    #[test]
+    fn names_compare_with_dashes_and_underscores() {
+        assert_eq!(normalize("snd-hda-intel"), "snd_hda_intel");
+        assert_eq!(normalize("snd_hda_intel"), "snd_hda_intel");
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{CommandRunner, Invocation};
+use std::path::{Path, PathBuf};
+
+/// Entry of `/proc/mounts`
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub device: PathBuf,
+    pub mountpoint: PathBuf,
+    pub fstype: String,
+}
+
+/// All currently mounted filesystems
+pub fn all() -> Vec<Mount> {
+    parse("/proc/mounts")
+}
+
+/// Filesystems configured in `/etc/fstab`
+pub fn fstab() -> Vec<Mount> {
+    parse("/etc/fstab")
+}
+
+/// Both `/proc/mounts` and `/etc/fstab` share the same format
+fn parse(path: &str) -> Vec<Mount> {
+    std::fs::read_to_string(path)
+        .map(|mounts| {
+            mounts
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    Some(Mount {
+                        device: PathBuf::from(fields.next()?),
+                        mountpoint: PathBuf::from(unescape(fields.next()?)),
+                        fstype: fields.next()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Mount that contains `path`, i.e. the one with the longest matching mountpoint
+pub fn find(path: &Path) -> Option<Mount> {
+    all()
+        .into_iter()
+        .filter(|mount| path.starts_with(&mount.mountpoint))
+        .max_by_key(|mount| mount.mountpoint.components().count())
+}
+
+/// Filesystem from `/etc/fstab` that should contain `path` but is not mounted. The root
+/// filesystem is always mounted and never reported.
+pub fn unmounted(path: &Path) -> Option<Mount> {
+    let mounted = all();
+    fstab()
+        .into_iter()
+        .filter(|mount| mount.mountpoint != Path::new("/") && path.starts_with(&mount.mountpoint))
+        .max_by_key(|mount| mount.mountpoint.components().count())
+        .filter(|mount| {
+            !mounted
+                .iter()
+                .any(|active| active.mountpoint == mount.mountpoint)
+        })
+}
+
+/// Filesystem mounted for the duration of an operation, unmounted again when dropped
+#[derive(Debug)]
+pub struct TemporaryMount<'a> {
+    runner: &'a dyn CommandRunner,
+    mountpoint: PathBuf,
+}
+
+impl<'a> TemporaryMount<'a> {
+    /// Mounts a filesystem configured in `/etc/fstab` by its mountpoint
+    pub fn mount(runner: &'a dyn CommandRunner, mountpoint: &Path) -> std::io::Result<Self> {
+        runner
+            .run(&Invocation::new("mount").arg(mountpoint))
+            .map_err(|e| {
+                std::io::Error::other(format!("mounting {} failed: {e}", mountpoint.display()))
+            })?;
+
+        Ok(Self {
+            runner,
+            mountpoint: mountpoint.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for TemporaryMount<'_> {
+    fn drop(&mut self) {
+        match self
+            .runner
+            .output(&Invocation::new("umount").arg(&self.mountpoint))
+        {
+            Ok(output) if output.success => {
+                println!("Unmounted {}", self.mountpoint.display());
+            }
+            _ => eprintln!("Warning: could not unmount {}", self.mountpoint.display()),
+        }
+    }
+}
+
+/// `/proc/mounts` escapes whitespace as octal sequences, e.g. `\040` for a space
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code = chars.by_ref().take(3).collect::<String>();
+            match u8::from_str_radix(&code, 8) {
+                Ok(byte) => result.push(char::from(byte)),
+                Err(_) => {
+                    result.push(c);
+                    result.push_str(&code);
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_octal_sequences() {
+        assert_eq!(unescape("/boot/efi"), "/boot/efi");
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(
+            unescape("/mnt/tab\\011and\\134slash"),
+            "/mnt/tab\tand\\slash"
+        );
+        assert_eq!(unescape("/mnt/not\\x41"), "/mnt/not\\x41");
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{
+    ui, Args, Bootloader, BuilderErr, CommandRunner, Flavor, InstallMode, KBConfig, KernelBuilder,
+    Progress, Prompter, SystemRunner, UkiGenerator, Verbosity,
+};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+/// Options of a [`KernelBuilder`], created with [`KernelBuilder::builder`]. Everything not set
+/// keeps the defaults of [`KernelBuilder::new`].
+#[derive(Debug)]
+pub struct KernelBuilderOptions {
+    config: KBConfig,
+    source_roots: Option<Vec<PathBuf>>,
+    flavor: Option<String>,
+    verbosity: Verbosity,
+    assume_yes: bool,
+    jobs: Option<NonZeroUsize>,
+    prompter: Option<Box<dyn Prompter>>,
+    progress: Option<Box<dyn Progress>>,
+    runner: Option<Box<dyn CommandRunner>>,
+}
+
+impl KernelBuilderOptions {
+    pub(crate) fn new(config: KBConfig) -> Self {
+        Self {
+            config,
+            source_roots: None,
+            flavor: None,
+            verbosity: Verbosity::default(),
+            assume_yes: false,
+            jobs: None,
+            prompter: None,
+            progress: None,
+            runner: None,
+        }
+    }
+
+    /// Directories searched for source trees instead of `kernel-src` and `source-roots` of the
+    /// config, the first one also gets the `linux` symlink.
+    #[must_use]
+    pub fn source_roots(mut self, roots: Vec<PathBuf>) -> Self {
+        self.source_roots = Some(roots);
+        self
+    }
+
+    /// One of the flavors defined in the config
+    #[must_use]
+    pub fn flavor(mut self, flavor: impl Into<String>) -> Self {
+        self.flavor = Some(flavor.into());
+        self
+    }
+
+    #[must_use]
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Answers all questions with yes, see [`KernelBuilder::set_assume_yes`]
+    #[must_use]
+    pub fn assume_yes(mut self, assume_yes: bool) -> Self {
+        self.assume_yes = assume_yes;
+        self
+    }
+
+    /// Number of make jobs, defaults to the available CPUs
+    #[must_use]
+    pub fn jobs(mut self, jobs: NonZeroUsize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Boot loader to update instead of the `bootloader` of the config
+    #[must_use]
+    pub fn bootloader(mut self, bootloader: Bootloader) -> Self {
+        self.config.bootloader = Some(bootloader);
+        self
+    }
+
+    /// How the kernel is installed instead of the `install-mode` of the config
+    #[must_use]
+    pub fn install_mode(mut self, install_mode: InstallMode) -> Self {
+        self.config.install_mode = install_mode;
+        self
+    }
+
+    /// Tool generating the unified kernel image instead of the `uki-generator` of the config
+    #[must_use]
+    pub fn uki_generator(mut self, uki_generator: UkiGenerator) -> Self {
+        self.config.uki_generator = uki_generator;
+        self
+    }
+
+    #[must_use]
+    pub fn prompter(mut self, prompter: Box<dyn Prompter>) -> Self {
+        self.prompter = Some(prompter);
+        self
+    }
+
+    #[must_use]
+    pub fn progress(mut self, progress: Box<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    #[must_use]
+    pub fn runner(mut self, runner: Box<dyn CommandRunner>) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Creates the builder. Source trees are only scanned once they are needed.
+    ///
+    /// # Errors
+    ///
+    /// - Flavor is not defined in the config
+    pub fn build(self) -> Result<KernelBuilder, BuilderErr> {
+        let mut config = self.config;
+        if let Some(mut roots) = self.source_roots {
+            if !roots.is_empty() {
+                config.kernel_src = roots.remove(0);
+                config.source_roots = roots;
+            }
+        }
+
+        let mut builder = KernelBuilder::new(config);
+        builder.set_flavor(self.flavor)?;
+        builder.set_verbosity(self.verbosity);
+        builder.set_assume_yes(self.assume_yes);
+        builder.jobs = self.jobs;
+        if let Some(prompter) = self.prompter {
+            builder.set_prompter(prompter);
+        }
+        if let Some(progress) = self.progress {
+            builder.set_progress(progress);
+        }
+        if let Some(runner) = self.runner {
+            builder.set_runner(runner);
+        }
+
+        Ok(builder)
+    }
+}
+
+/// What a build does, the library counterpart of the build flags of the command line. The
+/// defaults run every step that applies to the config. Unattended runs answer the questions
+/// whose safe answer is no, like building an end-of-life series, with the fields from
+/// `build_eol` on instead of yes.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub no_build: bool,
+    #[cfg(feature = "dracut")]
+    pub no_initramfs: bool,
+    pub no_modules: bool,
+    pub no_uki: bool,
+    /// Opens menuconfig before building, which needs a terminal
+    pub menuconfig: bool,
+    pub replace: bool,
+    pub kexec_reboot: bool,
+    pub reboot: bool,
+    /// Time like `03:00` to schedule a reboot at
+    pub reboot_at: Option<String>,
+    /// Names of steps to leave out
+    pub skip: Vec<String>,
+    pub resume_from: Option<String>,
+    pub dry_run: bool,
+    /// Build a series that reached its end of life
+    pub build_eol: bool,
+    /// Build although installed packages with out-of-tree modules do not support the version
+    pub build_unsupported: bool,
+    /// Run `emerge @module-rebuild` after installing the modules
+    pub module_rebuild: bool,
+    /// Deselect and unmerge source packages superseded by the new kernel
+    pub depclean: bool,
+    /// Leave the completed steps in place when a step fails instead of rolling them back
+    pub no_rollback: bool,
+}
+
+impl From<&Args> for BuildOptions {
+    fn from(cli: &Args) -> Self {
+        Self {
+            no_build: cli.no_build,
+            #[cfg(feature = "dracut")]
+            no_initramfs: cli.no_initramfs,
+            no_modules: cli.no_modules,
+            no_uki: cli.no_uki,
+            menuconfig: cli.menuconfig,
+            replace: cli.replace,
+            kexec_reboot: cli.kexec_reboot,
+            reboot: cli.reboot,
+            reboot_at: cli.reboot_at.clone(),
+            skip: cli.skip.clone(),
+            resume_from: cli.resume_from.clone(),
+            dry_run: cli.dry_run,
+            ..Self::default()
+        }
+    }
+}
+
+impl KernelBuilder {
+    /// Creates a builder with the defaults: terminal prompts, no progress output and the source
+    /// roots of the config, which are scanned once the source trees are needed.
+    #[must_use]
+    pub fn new(config: KBConfig) -> Self {
+        Self {
+            config,
+            versions: std::cell::OnceCell::new(),
+            flavor: None,
+            verbosity: Verbosity::default(),
+            assume_yes: std::cell::Cell::new(false),
+            jobs: None,
+            prompter: Box::new(ui::Interactive),
+            progress: Box::new(ui::Silent),
+            runner: Box::new(SystemRunner),
+            timings: Default::default(),
+            warnings: Default::default(),
+            last_report: Default::default(),
+            run_date: Default::default(),
+        }
+    }
+
+    /// Configures a builder step by step, e.g. for library consumers that drive builds
+    /// without a terminal.
+    #[must_use]
+    pub fn builder(config: KBConfig) -> KernelBuilderOptions {
+        KernelBuilderOptions::new(config)
+    }
+
+    /// Selects one of the flavors defined in the config, `None` uses the defaults.
+    ///
+    /// # Errors
+    ///
+    /// - Flavor is not defined in the config
+    pub fn set_flavor(&mut self, flavor: Option<String>) -> Result<(), BuilderErr> {
+        if let Some(name) = &flavor {
+            if !self.config.flavors.contains_key(name) {
+                return Err(BuilderErr::UnknownFlavor(name.clone()));
+            }
+        }
+        self.flavor = flavor;
+
+        Ok(())
+    }
+
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        self.verbosity = verbosity;
+    }
+
+    /// Answers all questions with yes, for unattended builds. Reboots still need `--reboot`.
+    pub fn set_assume_yes(&mut self, assume_yes: bool) {
+        self.assume_yes.set(assume_yes);
+    }
+
+    /// Reports the progress of long running steps, nothing is shown by default. The command
+    /// line uses [`Spinner`](crate::Spinner). Only the steps and the tool output go through it,
+    /// status messages like installed paths and warnings are still printed to stdout and stderr.
+    pub fn set_progress(&mut self, progress: Box<dyn Progress>) {
+        self.progress = progress;
+    }
+
+    /// Replaces the terminal prompts, e.g. with [`NonInteractive`](crate::NonInteractive) when
+    /// there is no TTY.
+    pub fn set_prompter(&mut self, prompter: Box<dyn Prompter>) {
+        self.prompter = prompter;
+    }
+
+    /// Runs make, dracut and the boot loader tools through `runner`, e.g. a
+    /// [`RecordingRunner`](crate::RecordingRunner) in tests.
+    pub fn set_runner(&mut self, runner: Box<dyn CommandRunner>) {
+        self.runner = runner;
+    }
+
+    pub(crate) fn selected_flavor(&self) -> Option<&Flavor> {
+        self.flavor
+            .as_ref()
+            .and_then(|name| self.config.flavors.get(name))
+    }
+}
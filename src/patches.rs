@@ -0,0 +1,185 @@
+use crate::{CommandRunner, Invocation};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Checksums of the patches applied to a tree, one per line
+const APPLIED_FILE: &str = ".kernel-builder-patches";
+
+/// Splits a patch URL from the checksum pinned with a `#sha256=<hex>` suffix
+fn split_pin(entry: &str) -> (&str, Option<&str>) {
+    match entry.rsplit_once("#sha256=") {
+        Some((url, sha256)) => (url, Some(sha256)),
+        None => (entry, None),
+    }
+}
+
+/// File in `download_dir` caching a patch URL, named after a hash of the whole URL so patches
+/// with the same file name from different places do not collide
+fn cache_path(url: &str, download_dir: &Path) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    let name = url.rsplit('/').next().unwrap_or_default();
+
+    download_dir.join(format!("{:016x}-{name}", hasher.finish()))
+}
+
+/// Downloads a patch once into `download_dir`. Plain http URLs need a pinned checksum, which is
+/// checked on every use.
+fn download(runner: &dyn CommandRunner, entry: &str, download_dir: &Path) -> io::Result<PathBuf> {
+    let (url, sha256) = split_pin(entry);
+    if !url.starts_with("https://") && sha256.is_none() {
+        return Err(io::Error::other(format!(
+            "patch {url} is not fetched over https, pin it with #sha256=<checksum>"
+        )));
+    }
+
+    let target = cache_path(url, download_dir);
+    if !target.exists() {
+        std::fs::create_dir_all(download_dir)?;
+        // a failed download must not end up in the cache
+        let partial = target.with_extension("part");
+        crate::fetch::download(runner, url, &partial)?;
+        std::fs::rename(&partial, &target)?;
+    }
+    if let Some(sha256) = sha256 {
+        let actual = crate::install::sha256(runner, &target)?;
+        if !actual.eq_ignore_ascii_case(sha256) {
+            std::fs::remove_file(&target)?;
+            return Err(io::Error::other(format!(
+                "patch {url} has checksum {actual}, expected {sha256}"
+            )));
+        }
+    }
+
+    Ok(target)
+}
+
+/// Patch files of the configured entries in order. Directories contribute their `.patch` and
+/// `.diff` files sorted by name, URLs are downloaded into `download_dir` first.
+pub fn collect(
+    runner: &dyn CommandRunner,
+    entries: &[String],
+    download_dir: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    let mut patches = vec![];
+    for entry in entries {
+        if entry.starts_with("https://") || entry.starts_with("http://") {
+            patches.push(download(runner, entry, download_dir)?);
+            continue;
+        }
+
+        let path = PathBuf::from(entry);
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(&path)?
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|ext| ext == "patch" || ext == "diff")
+                })
+                .collect();
+            files.sort();
+            patches.extend(files);
+        } else if path.is_file() {
+            patches.push(path);
+        } else {
+            return Err(io::Error::other(format!("patch {entry} not found")));
+        }
+    }
+
+    Ok(patches)
+}
+
+/// Checksums of the patches kernel-builder applied to the tree
+pub fn applied(tree: &Path) -> Vec<String> {
+    std::fs::read_to_string(tree.join(APPLIED_FILE))
+        .map(|content| content.lines().map(ToString::to_string).collect())
+        .unwrap_or_default()
+}
+
+pub fn record(tree: &Path, checksum: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(tree.join(APPLIED_FILE))?
+        .write_all(format!("{checksum}\n").as_bytes())
+}
+
+fn command(tree: &Path, patch: &Path, check: bool, reverse: bool) -> Invocation {
+    let invocation = if crate::git::is_git_tree(tree) {
+        let mut apply = Invocation::new("git").arg("apply");
+        if check {
+            apply = apply.arg("--check");
+        }
+        if reverse {
+            apply = apply.arg("--reverse");
+        }
+        apply.arg(patch)
+    } else {
+        let mut apply = Invocation::new("patch")
+            .args(["-p1", "--batch", "--silent", "--input"])
+            .arg(patch);
+        if check {
+            apply = apply.arg("--dry-run");
+        }
+        // without --forward patch offers to reverse already applied patches
+        apply.arg(if reverse { "--reverse" } else { "--forward" })
+    };
+
+    invocation.current_dir(tree)
+}
+
+/// Checks if the patch was applied already, by other means than kernel-builder
+pub fn is_applied(runner: &dyn CommandRunner, tree: &Path, patch: &Path) -> bool {
+    runner.run(&command(tree, patch, true, true)).is_ok()
+}
+
+/// Applies the patch with `git apply` in git trees and `patch -p1` otherwise, after checking it
+/// applies cleanly so a failing patch leaves the tree untouched.
+pub fn apply(runner: &dyn CommandRunner, tree: &Path, patch: &Path) -> io::Result<()> {
+    runner.run(&command(tree, patch, true, false))?;
+    runner.run(&command(tree, patch, false, false)).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pinned_checksums() {
+        assert_eq!(
+            split_pin("http://example.org/fix.patch#sha256=abc123"),
+            ("http://example.org/fix.patch", Some("abc123"))
+        );
+        assert_eq!(
+            split_pin("https://example.org/fix.patch"),
+            ("https://example.org/fix.patch", None)
+        );
+    }
+
+    #[test]
+    fn caches_patches_by_url() {
+        let dir = Path::new("/var/lib/kernel-builder/patches");
+        let a = cache_path("https://a.example.org/fix.patch", dir);
+        let b = cache_path("https://b.example.org/fix.patch", dir);
+
+        assert_ne!(a, b);
+        assert_eq!(a, cache_path("https://a.example.org/fix.patch", dir));
+        assert!(a.to_string_lossy().ends_with("-fix.patch"));
+    }
+
+    #[test]
+    fn refuses_unpinned_http() {
+        let err = collect(
+            &crate::RecordingRunner::default(),
+            &["http://example.org/fix.patch".to_string()],
+            Path::new("/nonexistent"),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("#sha256="));
+    }
+}
@@ -0,0 +1,65 @@
+use crate::{install, template, KernelBuilder};
+use std::path::{Path, PathBuf};
+
+impl KernelBuilder {
+    /// Renders the placeholders of a configured artifact path for a kernel release
+    pub(crate) fn render_path(&self, template: &Path, kver: &str) -> PathBuf {
+        let mut context = template::TemplateContext::new(kver, self.flavor.as_deref());
+        if template::is_dated(template) {
+            context.date = self.template_date(kver);
+        }
+        let path = context.render(template);
+        // rendered names may contain characters that are invalid on the ESP
+        if install::on_fat(&path) {
+            install::fat_safe(&path)
+        } else {
+            path
+        }
+    }
+
+    /// Date `{date}` renders to for a kernel release. An installed kernel keeps the date recorded
+    /// in the state database, so its paths resolve the same on later days. Otherwise it is the
+    /// day the running build started on, or today.
+    pub(crate) fn template_date(&self, kver: &str) -> String {
+        self.load_state()
+            .ok()
+            .and_then(|state| {
+                state
+                    .installs
+                    .into_iter()
+                    .find(|install| install.version == kver)
+            })
+            .map(|install| install.path_date.unwrap_or(install.date))
+            .or_else(|| self.run_date.borrow().clone())
+            .unwrap_or_else(template::today)
+    }
+
+    /// Path of the installed kernel image for a kernel release
+    #[must_use]
+    pub fn kernel_path(&self, kver: &str) -> PathBuf {
+        self.render_path(&self.config.kernel_file_path, kver)
+    }
+
+    /// Path of the installed initramfs for a kernel release
+    #[must_use]
+    pub fn initramfs_path(&self, kver: &str) -> Option<PathBuf> {
+        self.config
+            .initramfs_file_path
+            .as_ref()
+            .map(|template| self.render_path(template, kver))
+    }
+
+    /// Path of the installed unified kernel image for a kernel release
+    #[must_use]
+    pub fn uki_path(&self, kver: &str) -> Option<PathBuf> {
+        self.config
+            .uki_file_path
+            .as_ref()
+            .map(|template| self.render_path(template, kver))
+    }
+
+    /// Boot without initramfs, either configured explicitly or implied by EFI stub booting
+    pub(crate) fn initramfs_less(&self) -> bool {
+        self.config.skip_initramfs || self.config.efi_stub
+    }
+}
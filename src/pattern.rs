@@ -0,0 +1,52 @@
+/// Matches text against a glob pattern where `*` matches any sequence of characters and `?`
+/// matches a single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` in the pattern and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_literals_and_wildcards() {
+        assert!(glob_match("linux-6.12.8-gentoo", "linux-6.12.8-gentoo"));
+        assert!(glob_match("linux-6.12*", "linux-6.12.8-gentoo"));
+        assert!(glob_match("*-gentoo", "linux-6.12.8-gentoo"));
+        assert!(glob_match("linux-6.?.*", "linux-6.6.30-gentoo"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("linux-*-*-r1", "linux-6.12.8-gentoo-r1"));
+
+        assert!(!glob_match("linux-6.12*", "linux-6.6.30-gentoo"));
+        assert!(!glob_match("linux-6.?.*", "linux-6.12.8-gentoo"));
+        assert!(!glob_match("*-gentoo", "linux-6.12.8-gentoo-r1"));
+        assert!(!glob_match("?", ""));
+    }
+}
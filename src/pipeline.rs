@@ -0,0 +1,771 @@
+use crate::{
+    discover::VersionEntry, external, hooks, install, state, ArtifactHash, Bootloader,
+    BuildOptions, BuilderErr, InstallMode, KernelBuilder, StepReport,
+};
+use std::path::{Path, PathBuf};
+
+/// A kernel release going through the steps of a build
+pub(crate) struct Run<'a> {
+    pub options: &'a BuildOptions,
+    pub version_entry: &'a VersionEntry,
+    pub kver: String,
+    /// Snapshot taken before installing
+    pub snapshot: Option<u32>,
+    /// Outcome of the out-of-tree module builds for the summary
+    pub rebuilt: Vec<external::ModuleBuild>,
+    /// Installed artifacts with their checksums
+    pub artifacts: Vec<ArtifactHash>,
+    /// Boot artifacts that did not exist before the run, removed on rollback
+    pub created: Vec<PathBuf>,
+}
+
+impl<'a> Run<'a> {
+    pub fn new(options: &'a BuildOptions, version_entry: &'a VersionEntry, kver: String) -> Self {
+        Self {
+            options,
+            version_entry,
+            kver,
+            snapshot: None,
+            rebuilt: vec![],
+            artifacts: vec![],
+            created: vec![],
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.version_entry.path
+    }
+
+    /// Whether the kernel is built and installed in this run, not only modules or boot artifacts
+    fn installs(&self) -> bool {
+        !self.options.no_build
+    }
+
+    /// Remembers a boot artifact written by a step as created by the run if `existed` before
+    fn note_created(&mut self, target: PathBuf, existed: bool) {
+        if !existed && target.exists() {
+            self.created.push(target);
+        }
+    }
+}
+
+/// One step of building and installing a kernel. The steps of a run are assembled from the
+/// config and flags, which lets single steps be skipped, a failed run be resumed from a step and
+/// the completed steps be rolled back after a failure.
+pub(crate) trait Step {
+    /// Name used on the command line, e.g. with `--skip`
+    fn name(&self) -> &'static str;
+
+    /// Whether the step applies to the run at all
+    fn check(&self, _builder: &KernelBuilder, _run: &Run) -> bool {
+        true
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr>;
+
+    /// Whether `rollback` can undo anything
+    fn reversible(&self) -> bool {
+        false
+    }
+
+    /// Undoes the step after a later one failed, returns whether there was anything to undo
+    fn rollback(&self, _builder: &KernelBuilder, _run: &Run) -> Result<bool, BuilderErr> {
+        Ok(false)
+    }
+}
+
+struct Snapshot;
+
+impl Step for Snapshot {
+    fn name(&self) -> &'static str {
+        "snapshot"
+    }
+
+    fn check(&self, _builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs()
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        run.snapshot = builder.take_snapshot(&run.kver)?;
+        Ok(())
+    }
+}
+
+struct Build;
+
+impl Step for Build {
+    fn name(&self) -> &'static str {
+        "build"
+    }
+
+    fn check(&self, _builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs()
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        let path = run.path();
+        builder.clean_stale_tree(path)?;
+        builder.run_step_hooks(hooks::HookStep::PreBuild, run.version_entry, &run.kver)?;
+        builder.track_step(&run.kver, state::BuildStep::Build, || {
+            builder.build_kernel(path)
+        })?;
+        // remember the config the objects were built with for the next stale check
+        if let Ok(hash) = install::sha256(builder, &path.join(".config")) {
+            let _ = std::fs::write(path.join(KernelBuilder::CONFIG_HASH_FILE), hash);
+        }
+        builder.run_step_hooks(hooks::HookStep::PostBuild, run.version_entry, &run.kver)
+    }
+}
+
+struct InstallKernel;
+
+impl Step for InstallKernel {
+    fn name(&self) -> &'static str {
+        "install-kernel"
+    }
+
+    fn check(&self, _builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs()
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        builder.run_step_hooks(hooks::HookStep::PreInstall, run.version_entry, &run.kver)?;
+        if builder.config.install_mode == InstallMode::Copy {
+            if builder.config.kernel_hooks {
+                builder.run_kernel_hooks(hooks::PREINST_DIR, &run.kver)?;
+            }
+            let kernel = builder.kernel_path(&run.kver);
+            let existed = kernel.exists();
+            builder.install_kernel(run.path(), &run.kver, run.options.replace)?;
+            run.note_created(kernel, existed);
+        }
+
+        Ok(())
+    }
+
+    fn reversible(&self) -> bool {
+        true
+    }
+
+    fn rollback(&self, builder: &KernelBuilder, run: &Run) -> Result<bool, BuilderErr> {
+        builder.roll_back_artifact(&builder.kernel_path(&run.kver), run)
+    }
+}
+
+struct Modules;
+
+impl Step for Modules {
+    fn name(&self) -> &'static str {
+        "modules"
+    }
+
+    fn check(&self, _builder: &KernelBuilder, run: &Run) -> bool {
+        !run.options.no_modules
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        if !builder.confirm_prompt("Do you want to install kernel modules?")? {
+            return Ok(());
+        }
+
+        let (path, kver) = (run.path(), run.kver.as_str());
+        builder.create_boot_environment(kver)?;
+        builder.track_step(kver, state::BuildStep::Modules, || {
+            builder.install_kernel_modules(path)
+        })?;
+        builder.rebuild_zfs_module()?;
+        let mut rebuilt = if builder.config.module_packages.is_empty() {
+            builder.offer_module_rebuild(run.options)?;
+            vec![]
+        } else {
+            builder.rebuild_module_packages()
+        };
+        rebuilt.extend(builder.build_external_modules(path));
+        builder.sign_external_modules(path, kver)?;
+        rebuilt.extend(builder.check_module_packages(kver));
+        rebuilt.extend(builder.check_critical_modules(kver));
+        builder.compare_loaded_modules(kver);
+        builder.sync_boot_environment(kver)?;
+        run.rebuilt.extend(rebuilt);
+
+        Ok(())
+    }
+}
+
+struct ZfsModule;
+
+impl Step for ZfsModule {
+    fn name(&self) -> &'static str {
+        "zfs-module"
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        builder.check_zfs_module(&run.kver)
+    }
+}
+
+/// Hands the kernel to installkernel or kernel-install, whose hooks may generate an initramfs
+/// and therefore run after the modules are in place
+struct SystemInstall;
+
+impl Step for SystemInstall {
+    fn name(&self) -> &'static str {
+        "system-install"
+    }
+
+    fn check(&self, builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs() && builder.config.install_mode != InstallMode::Copy
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        match builder.config.install_mode {
+            InstallMode::Copy => Ok(()),
+            InstallMode::Installkernel => builder.run_installkernel(run.path(), &run.kver),
+            InstallMode::KernelInstall => {
+                builder.run_kernel_install(run.path(), &run.kver)?;
+                println!("Installed kernel {} with kernel-install", run.kver);
+                Ok(())
+            }
+        }
+    }
+}
+
+struct BuiltinRoot;
+
+impl Step for BuiltinRoot {
+    fn name(&self) -> &'static str {
+        "builtin-root"
+    }
+
+    fn check(&self, builder: &KernelBuilder, _run: &Run) -> bool {
+        builder.initramfs_less()
+    }
+
+    fn execute(&self, _builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        KernelBuilder::verify_builtin_root(run.path())
+    }
+}
+
+#[cfg(feature = "dracut")]
+struct Initramfs;
+
+#[cfg(feature = "dracut")]
+impl Step for Initramfs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn check(&self, builder: &KernelBuilder, run: &Run) -> bool {
+        !builder.initramfs_less() && !run.options.no_initramfs
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        if !builder.confirm_prompt("Do you want to generate initramfs with dracut?")? {
+            return Ok(());
+        }
+
+        let kver = run.kver.as_str();
+        let initramfs = builder.initramfs_path(kver);
+        let existed = initramfs.as_ref().is_some_and(|path| path.exists());
+        builder.track_step(kver, state::BuildStep::Initramfs, || {
+            builder.generate_initramfs(run.version_entry, run.options.replace)
+        })?;
+        builder.run_step_hooks(hooks::HookStep::PostInitramfs, run.version_entry, kver)?;
+        // hooks may have rebuilt modules the image has to contain
+        if builder.initramfs_outdated(kver) {
+            println!("Modules changed after the initramfs was generated, regenerating it");
+            builder.track_step(kver, state::BuildStep::Initramfs, || {
+                builder.generate_initramfs(run.version_entry, true)
+            })?;
+        }
+        if let Some(initramfs) = initramfs {
+            run.note_created(initramfs, existed);
+        }
+
+        Ok(())
+    }
+
+    fn reversible(&self) -> bool {
+        true
+    }
+
+    fn rollback(&self, builder: &KernelBuilder, run: &Run) -> Result<bool, BuilderErr> {
+        match builder.initramfs_path(&run.kver) {
+            Some(initramfs) => builder.roll_back_artifact(&initramfs, run),
+            None => Ok(false),
+        }
+    }
+}
+
+struct Uki;
+
+impl Step for Uki {
+    fn name(&self) -> &'static str {
+        "uki"
+    }
+
+    fn check(&self, builder: &KernelBuilder, run: &Run) -> bool {
+        builder.config.uki_file_path.is_some() && !run.options.no_uki
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        if !builder.confirm_prompt("Do you want to generate a unified kernel image?")? {
+            return Ok(());
+        }
+
+        let uki = builder.uki_path(&run.kver);
+        let existed = uki.as_ref().is_some_and(|path| path.exists());
+        builder.track_step(&run.kver, state::BuildStep::Uki, || {
+            builder.generate_uki(run.version_entry)
+        })?;
+        if let Some(uki) = uki {
+            run.note_created(uki, existed);
+        }
+
+        Ok(())
+    }
+
+    fn reversible(&self) -> bool {
+        true
+    }
+
+    fn rollback(&self, builder: &KernelBuilder, run: &Run) -> Result<bool, BuilderErr> {
+        match builder.uki_path(&run.kver) {
+            Some(uki) => builder.roll_back_artifact(&uki, run),
+            None => Ok(false),
+        }
+    }
+}
+
+struct EfiStubEntry;
+
+impl Step for EfiStubEntry {
+    fn name(&self) -> &'static str {
+        "efi-stub-entry"
+    }
+
+    fn check(&self, builder: &KernelBuilder, _run: &Run) -> bool {
+        builder.config.efi_stub && builder.config.bootloader != Some(Bootloader::Efibootmgr)
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        builder.ensure_efi_stub_entry(&run.kver)
+    }
+}
+
+struct Bootloaders;
+
+impl Step for Bootloaders {
+    fn name(&self) -> &'static str {
+        "bootloader"
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        builder.update_bootloaders(&run.kver)
+    }
+}
+
+struct KernelHooks;
+
+impl Step for KernelHooks {
+    fn name(&self) -> &'static str {
+        "kernel-hooks"
+    }
+
+    fn check(&self, builder: &KernelBuilder, run: &Run) -> bool {
+        // installkernel runs the /etc/kernel hooks itself
+        run.installs()
+            && builder.config.kernel_hooks
+            && builder.config.install_mode == InstallMode::Copy
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        builder.run_kernel_hooks(hooks::POSTINST_DIR, &run.kver)
+    }
+}
+
+struct Record;
+
+impl Step for Record {
+    fn name(&self) -> &'static str {
+        "record"
+    }
+
+    fn check(&self, _builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs()
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        let kver = run.kver.as_str();
+        run.artifacts = builder.track_step(kver, state::BuildStep::Install, || {
+            builder.record_install(kver, run.snapshot)
+        })?;
+        builder.run_step_hooks(hooks::HookStep::PostInstall, run.version_entry, kver)
+    }
+}
+
+struct Deploy;
+
+impl Step for Deploy {
+    fn name(&self) -> &'static str {
+        "deploy"
+    }
+
+    fn check(&self, builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs() && builder.config.deploy.is_some()
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        match &builder.config.deploy {
+            Some(deploy) => builder.deploy(deploy, run.path(), &run.kver),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Depclean;
+
+impl Step for Depclean {
+    fn name(&self) -> &'static str {
+        "depclean"
+    }
+
+    fn check(&self, _builder: &KernelBuilder, run: &Run) -> bool {
+        run.installs()
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        builder.offer_depclean(&builder.superseded_sources(), run.options.depclean)
+    }
+}
+
+struct Kexec;
+
+impl Step for Kexec {
+    fn name(&self) -> &'static str {
+        "kexec"
+    }
+
+    fn check(&self, builder: &KernelBuilder, run: &Run) -> bool {
+        (run.options.kexec_reboot && run.installs()) || builder.config.kexec_test
+    }
+
+    fn execute(&self, builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+        if run.options.kexec_reboot && run.installs() {
+            builder.kexec_reboot(&run.kver)
+        } else {
+            builder.kexec_smoke_test(&run.kver)
+        }
+    }
+}
+
+/// All steps of a build in order, the ones not applying to a run are skipped by their `check`
+pub(crate) fn steps() -> Vec<Box<dyn Step>> {
+    let mut steps: Vec<Box<dyn Step>> = vec![
+        Box::new(Snapshot),
+        Box::new(Build),
+        Box::new(InstallKernel),
+        Box::new(Modules),
+        Box::new(ZfsModule),
+        Box::new(SystemInstall),
+        Box::new(BuiltinRoot),
+    ];
+    #[cfg(feature = "dracut")]
+    steps.push(Box::new(Initramfs));
+    steps.extend([
+        Box::new(Uki) as Box<dyn Step>,
+        Box::new(EfiStubEntry),
+        Box::new(Bootloaders),
+        Box::new(KernelHooks),
+        Box::new(Record),
+        Box::new(Deploy),
+        Box::new(Depclean),
+        Box::new(Kexec),
+    ]);
+
+    steps
+}
+
+impl KernelBuilder {
+    /// Runs the steps in order, leaving out the ones skipped on the command line and the ones
+    /// before the step to resume from. With `--dry-run` the steps are only listed. When a step
+    /// fails, rolling back the completed ones is offered.
+    pub(crate) fn run_steps(
+        &self,
+        steps: &[Box<dyn Step>],
+        run: &mut Run,
+    ) -> Result<(), BuilderErr> {
+        for name in run
+            .options
+            .skip
+            .iter()
+            .chain(run.options.resume_from.as_ref())
+        {
+            if !steps.iter().any(|step| step.name() == name) {
+                return Err(BuilderErr::UnknownStep(name.clone()));
+            }
+        }
+        let start = run
+            .options
+            .resume_from
+            .as_ref()
+            .and_then(|from| steps.iter().position(|step| step.name() == from))
+            .unwrap_or_default();
+
+        let mut completed: Vec<&dyn Step> = vec![];
+        for step in &steps[start..] {
+            if run.options.skip.iter().any(|skip| skip == step.name()) || !step.check(self, run) {
+                continue;
+            }
+            if run.options.dry_run {
+                println!("Would run step {}", step.name());
+                self.timings.borrow_mut().push(StepReport {
+                    name: step.name().to_string(),
+                    seconds: 0.0,
+                    ok: true,
+                });
+                continue;
+            }
+
+            let start = std::time::Instant::now();
+            let result = step.execute(self, run);
+            self.timings.borrow_mut().push(StepReport {
+                name: step.name().to_string(),
+                seconds: start.elapsed().as_secs_f64(),
+                ok: result.is_ok(),
+            });
+            if let Err(err) = result {
+                eprintln!("Step {} failed: {err}", step.name());
+                self.roll_back(&completed, run)?;
+                return Err(err);
+            }
+            completed.push(step.as_ref());
+        }
+
+        Ok(())
+    }
+
+    /// Rolls back the completed steps in reverse order after confirmation. Boot artifacts the
+    /// run created are removed, replaced ones are restored from the copies kept by `keep-old`.
+    fn roll_back(&self, completed: &[&dyn Step], run: &Run) -> Result<(), BuilderErr> {
+        let reversible: Vec<&&dyn Step> =
+            completed.iter().filter(|step| step.reversible()).collect();
+        if reversible.is_empty() {
+            println!("Nothing to roll back");
+            return Ok(());
+        }
+        if !self.config.keep_old {
+            self.warn(
+                "keep-old is disabled, boot artifacts replaced by the run cannot be restored",
+            );
+        }
+        if !self.confirm_or(
+            "Roll back the boot artifacts of the completed steps?",
+            !run.options.no_rollback,
+        )? {
+            return Ok(());
+        }
+
+        let mut rolled_back = false;
+        for step in reversible.into_iter().rev() {
+            match step.rollback(self, run) {
+                Ok(true) => {
+                    println!("Rolled back step {}", step.name());
+                    rolled_back = true;
+                }
+                Ok(false) => {}
+                Err(err) => self.warn(format!("rolling back step {} failed: {err}", step.name())),
+            }
+        }
+        if !rolled_back {
+            println!("Nothing to roll back");
+        }
+
+        Ok(())
+    }
+
+    /// Removes a boot artifact the run created or puts back the previous version kept by
+    /// `keep-old`, returns whether either was possible
+    fn roll_back_artifact(&self, target: &Path, run: &Run) -> Result<bool, BuilderErr> {
+        if run.created.iter().any(|created| created == target) {
+            std::fs::remove_file(target).map_err(BuilderErr::BackupError)?;
+            println!("Removed {}", target.display());
+            return Ok(true);
+        }
+
+        self.restore_old(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tmp::TempDir, KBConfig};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Step that logs what is done to it and optionally writes a boot artifact or fails
+    struct Fake {
+        name: &'static str,
+        fails: bool,
+        artifact: Option<PathBuf>,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Step for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn execute(&self, _builder: &KernelBuilder, run: &mut Run) -> Result<(), BuilderErr> {
+            self.log.borrow_mut().push(self.name.to_string());
+            if self.fails {
+                return Err(BuilderErr::HookFailed(self.name.to_string()));
+            }
+            if let Some(artifact) = &self.artifact {
+                let existed = artifact.exists();
+                std::fs::write(artifact, "new").unwrap();
+                run.note_created(artifact.clone(), existed);
+            }
+
+            Ok(())
+        }
+
+        fn reversible(&self) -> bool {
+            self.artifact.is_some()
+        }
+
+        fn rollback(&self, builder: &KernelBuilder, run: &Run) -> Result<bool, BuilderErr> {
+            self.log
+                .borrow_mut()
+                .push(format!("rollback {}", self.name));
+            match &self.artifact {
+                Some(artifact) => builder.roll_back_artifact(artifact, run),
+                None => Ok(false),
+            }
+        }
+    }
+
+    struct Fixture {
+        dir: TempDir,
+        log: Rc<RefCell<Vec<String>>>,
+        builder: KernelBuilder,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = TempDir::new("pipeline-test").unwrap();
+            let mut config = KBConfig::for_test(dir.path());
+            config.keep_old = true;
+            let builder = KernelBuilder::builder(config)
+                .assume_yes(true)
+                .build()
+                .unwrap();
+
+            Self {
+                dir,
+                log: Rc::default(),
+                builder,
+            }
+        }
+
+        fn step(&self, name: &'static str) -> Box<dyn Step> {
+            self.fake(name, false, None)
+        }
+
+        fn fake(&self, name: &'static str, fails: bool, artifact: Option<&str>) -> Box<dyn Step> {
+            Box::new(Fake {
+                name,
+                fails,
+                artifact: artifact.map(|artifact| self.dir.join(artifact)),
+                log: Rc::clone(&self.log),
+            })
+        }
+
+        fn run(&self, steps: &[Box<dyn Step>], options: &BuildOptions) -> Result<(), BuilderErr> {
+            let entry = VersionEntry {
+                path: self.dir.join("linux-6.12.8-gentoo"),
+                version_string: "linux-6.12.8-gentoo".to_string(),
+            };
+            let mut run = Run::new(options, &entry, "6.12.8-gentoo".to_string());
+            self.builder.run_steps(steps, &mut run)
+        }
+
+        fn log(&self) -> Vec<String> {
+            self.log.borrow().clone()
+        }
+    }
+
+    #[test]
+    fn skips_steps_and_resumes_from_a_step() {
+        let fixture = Fixture::new();
+        let steps = ["a", "b", "c", "d"].map(|name| fixture.step(name));
+        let options = BuildOptions {
+            skip: vec!["c".to_string()],
+            resume_from: Some("b".to_string()),
+            ..BuildOptions::default()
+        };
+
+        fixture.run(&steps, &options).unwrap();
+        assert_eq!(fixture.log(), ["b", "d"]);
+    }
+
+    #[test]
+    fn rejects_unknown_steps() {
+        let fixture = Fixture::new();
+        let steps = [fixture.step("a")];
+        let options = BuildOptions {
+            skip: vec!["missing".to_string()],
+            ..BuildOptions::default()
+        };
+
+        let result = fixture.run(&steps, &options);
+        assert!(matches!(result, Err(BuilderErr::UnknownStep(name)) if name == "missing"));
+        assert!(fixture.log().is_empty());
+    }
+
+    #[test]
+    fn dry_run_executes_nothing() {
+        let fixture = Fixture::new();
+        let steps = [fixture.step("a"), fixture.fake("b", true, None)];
+        let options = BuildOptions {
+            dry_run: true,
+            ..BuildOptions::default()
+        };
+
+        fixture.run(&steps, &options).unwrap();
+        assert!(fixture.log().is_empty());
+    }
+
+    #[test]
+    fn failure_rolls_back_completed_steps() {
+        let fixture = Fixture::new();
+        std::fs::write(fixture.dir.join("vmlinuz"), "new").unwrap();
+        std::fs::write(fixture.dir.join("vmlinuz.old"), "previous").unwrap();
+        let steps = [
+            fixture.fake("kernel", false, Some("vmlinuz")),
+            fixture.fake("initramfs", false, Some("initramfs.img")),
+            fixture.step("modules"),
+            fixture.fake("bootloader", true, None),
+            fixture.step("record"),
+        ];
+
+        let result = fixture.run(&steps, &BuildOptions::default());
+        assert!(matches!(result, Err(BuilderErr::HookFailed(_))));
+        assert_eq!(
+            fixture.log(),
+            [
+                "kernel",
+                "initramfs",
+                "modules",
+                "bootloader",
+                "rollback initramfs",
+                "rollback kernel"
+            ]
+        );
+        // the created initramfs is gone, the replaced kernel is back
+        assert!(!fixture.dir.join("initramfs.img").exists());
+        let kernel = std::fs::read_to_string(fixture.dir.join("vmlinuz")).unwrap();
+        assert_eq!(kernel, "previous");
+    }
+}
@@ -0,0 +1,329 @@
+use crate::version::KernelVersion;
+use crate::{CommandRunner, Invocation};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What the Portage hook does after new kernel sources were emerged
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PortageHook {
+    /// Start `kernel-builder auto` once emerge is done, as transient systemd unit or detached
+    /// process
+    #[default]
+    Schedule,
+    /// Build right away inside the emerge run
+    Launch,
+}
+
+/// Set in the environment of the emerge runs of kernel-builder itself, the hook leaves their
+/// packages to the running build
+pub const EMERGE_MARKER: &str = "KERNEL_BUILDER_EMERGE";
+
+/// Snippet for `/etc/portage/bashrc` that calls the hook after sources were merged
+pub const BASHRC_SNIPPET: &str = r#"# kernel-builder: build new kernel sources after they were emerged
+post_pkg_postinst() {
+    [[ -n ${KERNEL_BUILDER_EMERGE} || ${ROOT:-/} != / ]] && return
+    if [[ ${CATEGORY} == sys-kernel && ${PN} == *-sources ]]; then
+        kernel-builder hook --from-portage
+    elif [[ ${INHERITED} == *linux-mod* ]]; then
+        kernel-builder hook --from-portage
+    fi
+}
+"#;
+
+/// Whether the hook was invoked by an emerge run of kernel-builder itself
+pub fn emerged_by_builder() -> bool {
+    std::env::var_os(EMERGE_MARKER).is_some()
+}
+
+/// Package that invoked the hook as `category/name-version`, if it provides kernel sources
+pub fn emerged_sources() -> Option<String> {
+    let category = std::env::var("CATEGORY").ok()?;
+    let name = std::env::var("PN").ok()?;
+    let version = std::env::var("PVR").unwrap_or_default();
+
+    (category == "sys-kernel" && name.ends_with("-sources"))
+        .then(|| format!("{category}/{name}-{version}"))
+}
+
+/// Whether the package that invoked the hook builds kernel modules with the linux-mod eclasses
+pub fn emerged_module_package() -> bool {
+    std::env::var("INHERITED").is_ok_and(|inherited| {
+        inherited
+            .split_whitespace()
+            .any(|eclass| eclass.starts_with("linux-mod"))
+    })
+}
+
+/// Kernel release a module package was built for, `KV_FULL` of the linux-info eclass
+#[cfg(feature = "dracut")]
+pub fn module_package_kernel() -> Option<String> {
+    std::env::var("KV_FULL").ok().filter(|kv| !kv.is_empty())
+}
+
+/// Waits for the process `$0` to exit unless it is `0`, removes the marker `$1` and runs the rest
+const WAIT_SCRIPT: &str = r#"while [ "$0" -gt 0 ] && kill -0 "$0" 2>/dev/null; do sleep 5; done; rm -f "$1"; shift; exec "$@""#;
+
+/// Runs the rest in a new session detached from the terminal, with output appended to `$1`
+const DETACH_SCRIPT: &str = r#"log="$1"; shift; setsid -f "$@" >>"$log" 2>&1 </dev/null"#;
+
+/// Parent process id from the content of `/proc/<pid>/stat`. The command name before it is in
+/// parentheses and may contain spaces.
+fn parent_pid(stat: &str) -> Option<u32> {
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// Process id of the emerge run this process was started from, found among its ancestors
+fn emerge_pid() -> Option<u32> {
+    let mut pid = std::process::id();
+    while pid > 1 {
+        pid = parent_pid(&std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?)?;
+        let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+        // emerge is a python script, its path is the first or second argument
+        let is_emerge = cmdline
+            .split(|&byte| byte == 0)
+            .take(2)
+            .any(|arg| String::from_utf8_lossy(arg).rsplit('/').next() == Some("emerge"));
+        if is_emerge {
+            return Some(pid);
+        }
+    }
+
+    None
+}
+
+/// Runs kernel-builder with `args` in the background once the emerge run that called the hook
+/// has exited, so it released its locks and merged all packages. `unit` names the transient
+/// systemd service, or without systemd a marker in `/run` that exists until the run starts.
+/// Returns `false` when the same run is still pending, so packages merged by one emerge run
+/// schedule it only once. Output of the detached process goes to `log`.
+pub fn schedule(
+    runner: &dyn CommandRunner,
+    unit: &str,
+    args: &[&str],
+    log: &str,
+) -> io::Result<bool> {
+    let exe = std::env::current_exe()?;
+    let pid = emerge_pid().unwrap_or_default().to_string();
+    if crate::reboot::systemd_running() {
+        let pending = runner
+            .output(
+                &Invocation::new("systemctl")
+                    .args(["--quiet", "is-active"])
+                    .arg(format!("{unit}.service")),
+            )?
+            .success;
+        if pending {
+            return Ok(false);
+        }
+
+        runner.run(
+            &Invocation::new("systemd-run")
+                .args(["--unit", unit, "--collect", "--quiet"])
+                .args(["sh", "-c", WAIT_SCRIPT, &pid, ""])
+                .arg(&exe)
+                .args(args),
+        )?;
+
+        return Ok(true);
+    }
+
+    let marker = Path::new("/run").join(format!("{unit}.pending"));
+    match std::fs::File::create_new(&marker) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    runner.run(
+        &Invocation::new("sh")
+            .args(["-c", DETACH_SCRIPT, "sh", log])
+            .args(["sh", "-c", WAIT_SCRIPT, &pid])
+            .arg(&marker)
+            .arg(&exe)
+            .args(args),
+    )?;
+
+    Ok(true)
+}
+
+/// Installed package that provides a source tree, e.g. `sys-kernel/gentoo-sources-6.6.30-r1`
+/// for `linux-6.6.30-gentoo-r1`
+pub fn sources_package(version: &KernelVersion) -> Option<String> {
+    if version.is_rc() {
+        return None;
+    }
+
+    let name = match version.local() {
+        "" => "vanilla",
+        local => local,
+    };
+    let mut package = match version.patch() {
+        0 => format!("{name}-sources-{}", version.series()),
+        patch => format!("{name}-sources-{}.{patch}", version.series()),
+    };
+    if version.revision() > 0 {
+        package.push_str(&format!("-r{}", version.revision()));
+    }
+
+    Path::new("/var/db/pkg/sys-kernel")
+        .join(&package)
+        .exists()
+        .then(|| format!("sys-kernel/{package}"))
+}
+
+/// Installed kernel source packages like `sys-kernel/gentoo-sources-6.12.8-r1` with the version
+/// of the tree they ship, the reverse of [`sources_package`].
+pub fn installed_sources() -> Vec<(String, KernelVersion)> {
+    let Ok(entries) = std::fs::read_dir("/var/db/pkg/sys-kernel") else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let package = entry.file_name().to_str()?.to_string();
+            let version = sources_version(&package)?;
+            Some((format!("sys-kernel/{package}"), version))
+        })
+        .collect()
+}
+
+/// Version of the tree a source package like `gentoo-sources-6.12.8-r1` ships
+fn sources_version(package: &str) -> Option<KernelVersion> {
+    let (name, version) = package.split_once("-sources-")?;
+    let (numbers, revision) = match version.split_once("-r") {
+        Some((numbers, revision)) => (numbers, format!("-r{revision}")),
+        None => (version, String::new()),
+    };
+    let tree = match name {
+        "vanilla" => format!("{numbers}{revision}"),
+        local => format!("{numbers}-{local}{revision}"),
+    };
+
+    KernelVersion::parse(&tree)
+}
+
+/// Installed packages with kernel modules, i.e. the members of `@module-rebuild`. Portage
+/// defines the set by ownership of files below `/lib/modules`, the database of the former
+/// sys-kernel/module-rebuild tool is consulted as well.
+pub fn module_packages() -> Vec<String> {
+    let mut packages = vec![];
+    let vdb = Path::new("/var/db/pkg");
+    for category in std::fs::read_dir(vdb)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+    {
+        for package in std::fs::read_dir(category.path())
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+        {
+            let owns_modules =
+                std::fs::read_to_string(package.path().join("CONTENTS")).is_ok_and(|contents| {
+                    contents.lines().any(|line| {
+                        line.starts_with("obj /lib/modules/")
+                            || line.starts_with("obj /usr/lib/modules/")
+                    })
+                });
+            if owns_modules {
+                packages.push(format!(
+                    "{}/{}",
+                    category.file_name().to_string_lossy(),
+                    package.file_name().to_string_lossy()
+                ));
+            }
+        }
+    }
+
+    // entries look like `a:1:x11-drivers/nvidia-drivers-550.78`
+    if let Ok(moduledb) = std::fs::read_to_string("/var/lib/module-rebuild/moduledb") {
+        for package in moduledb.lines().filter_map(|line| line.rsplit(':').next()) {
+            if !package.is_empty() && !packages.iter().any(|known| known == package) {
+                packages.push(package.to_string());
+            }
+        }
+    }
+
+    packages.sort();
+    packages
+}
+
+/// Kernel modules installed by a package like `app-emulation/virtualbox-modules-7.0.20`,
+/// for all kernels it was built for
+pub fn package_modules(package: &str) -> Vec<PathBuf> {
+    std::fs::read_to_string(Path::new("/var/db/pkg").join(package).join("CONTENTS"))
+        .map(|contents| {
+            contents
+                .lines()
+                .filter_map(|line| line.strip_prefix("obj "))
+                // `obj <path> <md5> <mtime>`
+                .filter_map(|line| line.rsplitn(3, ' ').nth(2))
+                .filter(|path| path.contains("/lib/modules/") && path.contains(".ko"))
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Runs `emerge` with output going to the terminal
+pub fn emerge(runner: &dyn CommandRunner, args: &[&str]) -> io::Result<()> {
+    runner.run_attached(&Invocation::new("emerge").args(args).env(EMERGE_MARKER, "1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn installed_sources_map_to_their_trees() {
+        let version = |package| sources_version(package).map(|version| version.to_string());
+
+        assert_eq!(
+            version("gentoo-sources-6.12.8-r1").as_deref(),
+            Some("6.12.8-gentoo-r1")
+        );
+        assert_eq!(
+            version("gentoo-sources-6.6.30").as_deref(),
+            Some("6.6.30-gentoo")
+        );
+        assert_eq!(version("vanilla-sources-6.13.2").as_deref(), Some("6.13.2"));
+        assert_eq!(version("git-sources-6.14_rc3"), None);
+        assert_eq!(version("linux-firmware-20250109"), None);
+    }
+
+    #[test]
+    fn parses_the_parent_pid() {
+        assert_eq!(
+            parent_pid("4242 (python3.12) S 4100 4242 4100 0 -1 4194560"),
+            Some(4100)
+        );
+        assert_eq!(parent_pid("17 (tmux: server) S 1 17 17 0"), Some(1));
+        assert_eq!(parent_pid("garbage"), None);
+    }
+
+    #[test]
+    fn wait_script_removes_the_marker_and_runs_the_command() {
+        let dir = crate::tmp::TempDir::new("portage-test").unwrap();
+        let marker = dir.join("unit.pending");
+        std::fs::write(&marker, "").unwrap();
+
+        let output = Command::new("sh")
+            .args(["-c", WAIT_SCRIPT, "0"])
+            .arg(&marker)
+            .args(["echo", "ran"])
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ran\n");
+        assert!(!marker.exists());
+    }
+}
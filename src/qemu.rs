@@ -0,0 +1,140 @@
+use crate::tmp::TempDir;
+use crate::{BuilderErr, CommandRunner, Invocation, KernelBuilder};
+use std::path::Path;
+use std::time::Duration;
+
+/// Serial console lines that show the kernel handed over to userspace
+const SUCCESS_MARKERS: [&str; 2] = [
+    "Run /init as init process",
+    "Run /sbin/init as init process",
+];
+const PANIC_MARKER: &str = "Kernel panic";
+
+/// Runs QEMU with `timeout` and stops it at the first marker on the serial console, `grep`
+/// exiting makes QEMU die of a broken pipe on its next line
+const WATCH_SCRIPT: &str = r#"timeout="$1"; shift
+timeout "$timeout" "$@" 2>/dev/null | grep --max-count=1 --fixed-strings \
+    -e "$KB_SUCCESS_INIT" -e "$KB_SUCCESS_SBIN_INIT" -e "$KB_PANIC""#;
+
+/// Outcome of a boot test
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootResult {
+    /// The kernel started the init process of the initramfs
+    Passed,
+    /// The kernel panicked, contains the panic line
+    Panicked(String),
+    /// Neither init nor a panic was seen in time
+    TimedOut,
+}
+
+/// Boots the kernel in a throwaway QEMU/KVM machine with an empty scratch disk and watches the
+/// serial console until init is started, the kernel panics or the timeout is reached.
+pub fn boot_test(
+    runner: &dyn CommandRunner,
+    kernel: &Path,
+    initramfs: Option<&Path>,
+    cmdline: &str,
+    timeout: Duration,
+) -> std::io::Result<BootResult> {
+    // removed with the private dir when the test is over
+    let scratch = TempDir::new("boot-test")?;
+    let disk = scratch.join("disk.img");
+    std::fs::File::create_new(&disk)?.set_len(64 * 1024 * 1024)?;
+
+    let mut qemu = Invocation::new("sh")
+        .args(["-c", WATCH_SCRIPT, "sh"])
+        .arg(format!("{}", timeout.as_secs_f64()))
+        .env("KB_SUCCESS_INIT", SUCCESS_MARKERS[0])
+        .env("KB_SUCCESS_SBIN_INIT", SUCCESS_MARKERS[1])
+        .env("KB_PANIC", PANIC_MARKER)
+        .arg("qemu-system-x86_64")
+        .args([
+            "-m",
+            "1024",
+            "-display",
+            "none",
+            "-serial",
+            "stdio",
+            "-no-reboot",
+        ]);
+    if Path::new("/dev/kvm").exists() {
+        qemu = qemu.args(["-enable-kvm", "-cpu", "host"]);
+    }
+    qemu = qemu.arg("-kernel").arg(kernel);
+    if let Some(initramfs) = initramfs {
+        qemu = qemu.arg("-initrd").arg(initramfs);
+    }
+    qemu = qemu
+        .arg("-append")
+        .arg(format!("{cmdline} console=ttyS0 panic=-1"))
+        .arg("-drive")
+        .arg(format!("file={},format=raw,if=virtio", disk.display()));
+
+    // grep prints the first marker line, nothing if qemu exited (e.g. `panic=-1` rebooted with
+    // `-no-reboot`) or the time is up
+    let output = runner.output(&qemu)?;
+    let result = match output.stdout.lines().next() {
+        Some(line) if line.contains(PANIC_MARKER) => BootResult::Panicked(line.to_string()),
+        Some(_) => BootResult::Passed,
+        None => BootResult::TimedOut,
+    };
+
+    Ok(result)
+}
+
+impl KernelBuilder {
+    /// Boots the installed kernel and initramfs in a throwaway QEMU/KVM machine and reports whether
+    /// it reached the init process of the initramfs within the timeout. Without a kernel release
+    /// the one of the tree `/usr/src/linux` points to is used.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to determine the kernel release
+    /// - Failing to read the kernel command line
+    /// - Failing to start QEMU
+    pub fn test_boot(
+        &self,
+        kver: Option<&str>,
+        timeout: Duration,
+    ) -> Result<BootResult, BuilderErr> {
+        let kver = kver
+            .map(|kver| self.resolve_kver(kver))
+            .or_else(|| self.linked_kernel())
+            .ok_or(BuilderErr::RunningKernelUnknown)?;
+        let cmdline = self.kernel_cmdline()?.unwrap_or_default();
+        let kernel_file_path = self.kernel_path(&kver);
+        let initramfs = self
+            .initramfs_path(&kver)
+            .filter(|path| !self.initramfs_less() && path.exists());
+
+        self.progress
+            .on_step_start(&format!("Booting {} in QEMU", kernel_file_path.display()));
+        let result = boot_test(
+            self,
+            &kernel_file_path,
+            initramfs.as_deref(),
+            &cmdline,
+            timeout,
+        )
+        .map_err(BuilderErr::BootTestError)?;
+
+        match &result {
+            BootResult::Passed => self
+                .progress
+                .on_step_end(true, "Boot test passed, init was started"),
+            BootResult::Panicked(line) => {
+                self.progress
+                    .on_step_end(false, &format!("Boot test failed: {line}"));
+            }
+            BootResult::TimedOut => self.progress.on_step_end(
+                false,
+                &format!(
+                    "Boot test failed: init was not reached within {}s",
+                    timeout.as_secs()
+                ),
+            ),
+        }
+
+        Ok(result)
+    }
+}
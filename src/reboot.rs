@@ -0,0 +1,91 @@
+use crate::{BuilderErr, CommandRunner, Invocation, KernelBuilder};
+use std::io::{self, IsTerminal};
+use std::path::Path;
+
+/// systemd is the running init system
+pub fn systemd_running() -> bool {
+    Path::new("/run/systemd/system").exists()
+}
+
+/// OpenRC is the running init system
+pub fn openrc_running() -> bool {
+    Path::new("/run/openrc").exists()
+}
+
+/// Checks a time of day in the `HH:MM` format
+pub fn parse_time(time: &str) -> Result<String, String> {
+    let valid = time.split_once(':').is_some_and(|(hours, minutes)| {
+        hours.len() == 2
+            && minutes.len() == 2
+            && hours.parse::<u8>().is_ok_and(|hours| hours < 24)
+            && minutes.parse::<u8>().is_ok_and(|minutes| minutes < 60)
+    });
+    if !valid {
+        return Err(format!("invalid time `{time}`, expected HH:MM"));
+    }
+
+    Ok(time.to_string())
+}
+
+/// Reboots the system right away
+pub fn now(runner: &dyn CommandRunner) -> io::Result<()> {
+    if systemd_running() {
+        runner.run_attached(&Invocation::new("systemctl").arg("reboot"))
+    } else {
+        runner.run_attached(&Invocation::new("shutdown").args(["-r", "now"]))
+    }
+}
+
+/// Schedules a reboot at the next occurrence of the time of day `at`, with a transient timer
+/// under systemd and with `shutdown` otherwise.
+pub fn schedule(runner: &dyn CommandRunner, at: &str) -> io::Result<()> {
+    if systemd_running() {
+        runner.run_attached(
+            &Invocation::new("systemd-run")
+                .args(["--unit", "kernel-builder-reboot"])
+                .arg(format!("--on-calendar=*-*-* {at}:00"))
+                .args(["systemctl", "reboot"]),
+        )
+    } else {
+        runner.run_attached(&Invocation::new("shutdown").args(["-r", at]))
+    }
+}
+
+impl KernelBuilder {
+    /// Reboots into the installed kernel as requested on the command line, or asks when running
+    /// interactively.
+    pub(crate) fn offer_reboot(
+        &self,
+        kver: &str,
+        reboot_now: bool,
+        at: Option<&str>,
+    ) -> Result<(), BuilderErr> {
+        if let Some(at) = at {
+            schedule(self, at).map_err(BuilderErr::RebootError)?;
+            println!("Scheduled reboot into {kver} at {at}");
+        } else if reboot_now
+            || (std::io::stdin().is_terminal()
+                && !self.assume_yes.get()
+                && self.confirm_prompt(&format!("Reboot into {kver} now?"))?)
+        {
+            now(self).map_err(BuilderErr::RebootError)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!(parse_time("03:00").as_deref(), Ok("03:00"));
+        assert_eq!(parse_time("23:59").as_deref(), Ok("23:59"));
+
+        for invalid in ["3:00", "24:00", "12:60", "0300", "now", "12:5a", ""] {
+            assert!(parse_time(invalid).is_err(), "{invalid} accepted");
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::version::KernelVersion;
+use crate::{CommandRunner, Invocation};
+use serde::Deserialize;
+use std::io;
+
+const RELEASES_URL: &str = "https://www.kernel.org/releases.json";
+
+#[derive(Debug, Deserialize)]
+struct LatestStable {
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    /// `mainline`, `stable`, `longterm` or `linux-next`
+    moniker: String,
+    version: String,
+    iseol: bool,
+}
+
+/// Current releases as published by kernel.org
+#[derive(Debug, Deserialize)]
+pub struct Releases {
+    latest_stable: LatestStable,
+    releases: Vec<Release>,
+}
+
+impl Releases {
+    /// Downloads the release information with curl
+    pub fn fetch(runner: &dyn CommandRunner) -> io::Result<Self> {
+        let json = runner.run(
+            &Invocation::new("curl")
+                .args([
+                    "--fail",
+                    "--location",
+                    "--silent",
+                    "--show-error",
+                    "--max-time",
+                    "10",
+                ])
+                .arg(RELEASES_URL),
+        )?;
+
+        serde_json::from_str(&json).map_err(io::Error::other)
+    }
+
+    /// Checks if the series of a tree like `linux-6.1.90-gentoo` has reached its end of life.
+    /// kernel.org drops series some time after their last release, so a series that is not
+    /// listed and older than the oldest listed longterm series is end of life as well.
+    pub fn is_eol(&self, name: &str) -> bool {
+        let mut listed = self
+            .releases
+            .iter()
+            .filter(|release| crate::version::matches(name, series(&release.version)))
+            .peekable();
+        if listed.peek().is_some() {
+            return listed.any(|release| release.iseol);
+        }
+
+        let Some(version) = KernelVersion::parse(name) else {
+            return false;
+        };
+        self.releases
+            .iter()
+            .filter(|release| release.moniker == "longterm")
+            .filter_map(|release| KernelVersion::parse(&release.version))
+            .map(|lts| (lts.major(), lts.minor()))
+            .min()
+            .is_some_and(|oldest| (version.major(), version.minor()) < oldest)
+    }
+
+    /// Annotation of a tree for the version picker, `None` if there is nothing to tell
+    pub fn annotation(&self, name: &str) -> Option<&'static str> {
+        let latest_lts = self
+            .releases
+            .iter()
+            .find(|release| release.moniker == "longterm" && !release.iseol);
+
+        if self.is_eol(name) {
+            Some("EOL")
+        } else if crate::version::matches(name, &self.latest_stable.version) {
+            Some("latest stable")
+        } else if latest_lts.is_some_and(|lts| crate::version::matches(name, &lts.version)) {
+            Some("latest LTS")
+        } else {
+            None
+        }
+    }
+}
+
+/// Series of a release version like `6.1.90`, i.e. `6.1`
+fn series(version: &str) -> &str {
+    version
+        .match_indices('.')
+        .nth(1)
+        .map_or(version, |(index, _)| &version[..index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlisted_series_older_than_longterm_are_eol() {
+        let releases: Releases = serde_json::from_str(
+            r#"{
+                "latest_stable": {"version": "6.12.8"},
+                "releases": [
+                    {"moniker": "mainline", "version": "6.13-rc3", "iseol": false},
+                    {"moniker": "stable", "version": "6.12.8", "iseol": false},
+                    {"moniker": "stable", "version": "6.11.11", "iseol": true},
+                    {"moniker": "longterm", "version": "6.6.68", "iseol": false},
+                    {"moniker": "longterm", "version": "5.4.288", "iseol": false}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(releases.is_eol("linux-6.11.11-gentoo"));
+        assert!(!releases.is_eol("linux-6.12.8-gentoo"));
+        assert!(!releases.is_eol("linux-5.4.288-gentoo"));
+        // dropped from the list
+        assert!(releases.is_eol("linux-4.19.325-gentoo"));
+        // newer than the oldest longterm but not listed, e.g. a stable series in between
+        assert!(!releases.is_eol("linux-6.7.12"));
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{external::ModuleBuild, state::ArtifactHash, BuilderErr};
+use serde::Serialize;
+use std::path::Path;
+
+/// Outcome of building and installing a kernel, printed as JSON with `--report` and returned
+/// to library consumers. A failed build has the steps up to the failure and the error.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuildReport {
+    /// Name of the source tree like `linux-6.12.8-gentoo`
+    pub version: String,
+    /// Kernel release, e.g. `6.12.8-gentoo`
+    pub kver: String,
+    pub flavor: Option<String>,
+    /// Nothing was built or installed, `steps` are the ones that would run
+    pub dry_run: bool,
+    /// Steps run in order
+    pub steps: Vec<StepReport>,
+    /// Installed boot artifacts with their checksums, empty if nothing was installed
+    pub artifacts: Vec<ArtifactHash>,
+    /// Out-of-tree modules rebuilt against the kernel
+    pub modules: Vec<ModuleBuild>,
+    /// Warnings shown during the run
+    pub warnings: Vec<String>,
+    /// Error that stopped the build, `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// Step of the pipeline that ran and how long it took
+#[derive(Debug, Clone, Serialize)]
+pub struct StepReport {
+    pub name: String,
+    pub seconds: f64,
+    pub ok: bool,
+}
+
+impl BuildReport {
+    /// Writes the report as JSON
+    ///
+    /// # Errors
+    ///
+    /// - Failing to write the file
+    pub fn write(&self, path: &Path) -> Result<(), BuilderErr> {
+        let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other);
+        json.and_then(|json| std::fs::write(path, json))
+            .map_err(BuilderErr::ReportError)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::mounts;
+use std::path::{Path, PathBuf};
+
+/// Storage stack the running system boots its root filesystem from
+#[derive(Debug, Clone)]
+pub struct RootStack {
+    pub device: PathBuf,
+    pub fstype: String,
+    pub encrypted: bool,
+}
+
+impl RootStack {
+    /// Detects the root filesystem from `/proc/mounts` and checks if it lives on dm-crypt.
+    pub fn detect() -> Option<Self> {
+        let mount = mounts::all()
+            .into_iter()
+            .find(|mount| mount.mountpoint == Path::new("/") && mount.fstype != "rootfs")?;
+        let (device, fstype) = (mount.device, mount.fstype);
+
+        let encrypted = device
+            .canonicalize()
+            .ok()
+            .and_then(|dev| {
+                dev.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .is_some_and(|name| is_crypt_device(&name));
+
+        Some(Self {
+            device,
+            fstype,
+            encrypted,
+        })
+    }
+
+    /// Kernel config option that provides the root filesystem driver
+    pub fn fs_kconfig(&self) -> Option<&'static str> {
+        Some(match self.fstype.as_str() {
+            "ext2" | "ext3" | "ext4" => "EXT4_FS",
+            "btrfs" => "BTRFS_FS",
+            "xfs" => "XFS_FS",
+            "f2fs" => "F2FS_FS",
+            "jfs" => "JFS_FS",
+            "nilfs2" => "NILFS2_FS",
+            "bcachefs" => "BCACHEFS_FS",
+            _ => return None,
+        })
+    }
+
+    /// Kernel modules of the drivers the root block device depends on, e.g. `nvme` or `ahci`.
+    pub fn driver_modules(&self) -> Vec<String> {
+        let Some(name) = self.device.canonicalize().ok().and_then(|dev| {
+            dev.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        }) else {
+            return vec![];
+        };
+        let Ok(mut sys) = Path::new("/sys/class/block").join(name).canonicalize() else {
+            return vec![];
+        };
+
+        let mut modules = vec![];
+        loop {
+            if let Some(module) = sys
+                .join("driver/module")
+                .read_link()
+                .ok()
+                .and_then(|module| module.file_name().map(|m| m.to_string_lossy().to_string()))
+            {
+                if !modules.contains(&module) {
+                    modules.push(module);
+                }
+            }
+            if !sys.pop() || sys == Path::new("/sys/devices") {
+                break;
+            }
+        }
+
+        modules
+    }
+
+    /// Kernel module name of the root filesystem driver
+    pub fn fs_module(&self) -> &str {
+        match self.fstype.as_str() {
+            "ext2" | "ext3" => "ext4",
+            fstype => fstype,
+        }
+    }
+}
+
+/// Walks the device mapper stack of a block device (e.g. LVM on LUKS) looking for a dm-crypt
+/// target.
+fn is_crypt_device(name: &str) -> bool {
+    let sys = Path::new("/sys/block").join(name);
+    let is_crypt =
+        std::fs::read_to_string(sys.join("dm/uuid")).is_ok_and(|uuid| uuid.starts_with("CRYPT-"));
+
+    is_crypt
+        || std::fs::read_dir(sys.join("slaves")).is_ok_and(|slaves| {
+            slaves
+                .filter_map(Result::ok)
+                .any(|slave| is_crypt_device(&slave.file_name().to_string_lossy()))
+        })
+}
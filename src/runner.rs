@@ -0,0 +1,341 @@
+use crate::{KernelBuilder, Verbosity};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+/// External program to run with its arguments, working directory and environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(OsString, OsString)>,
+}
+
+impl Invocation {
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            args: vec![],
+            cwd: None,
+            env: vec![],
+        }
+    }
+
+    #[must_use]
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    #[must_use]
+    pub fn args<I: IntoIterator<Item = impl Into<OsString>>>(mut self, args: I) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    #[must_use]
+    pub fn current_dir(mut self, cwd: &Path) -> Self {
+        self.cwd = Some(cwd.to_path_buf());
+        self
+    }
+
+    #[must_use]
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).envs(self.env.iter().cloned());
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+
+        command
+    }
+}
+
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(cwd) = &self.cwd {
+            write!(f, "(cd {}) ", cwd.display())?;
+        }
+        for (key, value) in &self.env {
+            write!(f, "{}={} ", key.to_string_lossy(), value.to_string_lossy())?;
+        }
+        write!(f, "{}", self.program.to_string_lossy())?;
+        for arg in &self.args {
+            write!(f, " {}", arg.to_string_lossy())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Captured result of a finished program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+    pub success: bool,
+    /// Exit code, `None` if killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs the external programs of a build like make, dracut or grub-mkconfig. The builder uses
+/// [`SystemRunner`], tests plug in [`RecordingRunner`] to see what would be run.
+pub trait CommandRunner: fmt::Debug {
+    /// Runs the program to completion and captures its output.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start the program
+    fn output(&self, invocation: &Invocation) -> io::Result<Output>;
+
+    /// Runs the program and passes stdout and stderr merged line by line to `on_line` while it
+    /// runs. Returns whether it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start the program
+    fn stream(&self, invocation: &Invocation, on_line: &mut dyn FnMut(&str)) -> io::Result<bool>;
+
+    /// Runs the program attached to the terminal, for interactive tools like menuconfig.
+    /// Returns whether it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start the program
+    fn interactive(&self, invocation: &Invocation) -> io::Result<bool>;
+
+    /// Runs the program to completion and returns its stdout.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start the program
+    /// - Program failed, the error holds its stderr
+    fn run(&self, invocation: &Invocation) -> io::Result<String> {
+        let output = self.output(invocation)?;
+        if !output.success {
+            let stderr = output.stderr.trim();
+            return Err(io::Error::other(if stderr.is_empty() {
+                format!("{} failed", invocation.program.to_string_lossy())
+            } else {
+                stderr.to_string()
+            }));
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Runs the program attached to the terminal.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to start the program
+    /// - Program failed
+    fn run_attached(&self, invocation: &Invocation) -> io::Result<()> {
+        if !self.interactive(invocation)? {
+            return Err(io::Error::other(format!("`{invocation}` failed")));
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the programs on this system
+#[derive(Debug, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, invocation: &Invocation) -> io::Result<Output> {
+        let output = invocation.command().stdin(Stdio::null()).output()?;
+
+        Ok(Output {
+            success: output.status.success(),
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+
+    fn stream(&self, invocation: &Invocation, on_line: &mut dyn FnMut(&str)) -> io::Result<bool> {
+        let mut child = invocation
+            .command()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // merge both streams in the order the lines arrive
+        let (tx, rx) = std::sync::mpsc::channel();
+        for reader in [
+            child
+                .stdout
+                .take()
+                .map(|out| Box::new(out) as Box<dyn io::Read + Send>),
+            child
+                .stderr
+                .take()
+                .map(|err| Box::new(err) as Box<dyn io::Read + Send>),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        for line in rx {
+            on_line(&line);
+        }
+
+        Ok(child.wait()?.success())
+    }
+
+    fn interactive(&self, invocation: &Invocation) -> io::Result<bool> {
+        Ok(invocation.command().status()?.success())
+    }
+}
+
+/// Records the invocations instead of running anything, every program succeeds without output.
+/// Clones share the record, so a clone kept outside the builder sees its invocations.
+#[derive(Debug, Default, Clone)]
+pub struct RecordingRunner {
+    invocations: Rc<RefCell<Vec<Invocation>>>,
+}
+
+impl RecordingRunner {
+    /// Invocations in the order they were made
+    #[must_use]
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.borrow().clone()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn output(&self, invocation: &Invocation) -> io::Result<Output> {
+        self.invocations.borrow_mut().push(invocation.clone());
+        Ok(Output {
+            success: true,
+            code: Some(0),
+            ..Output::default()
+        })
+    }
+
+    fn stream(&self, invocation: &Invocation, _on_line: &mut dyn FnMut(&str)) -> io::Result<bool> {
+        self.invocations.borrow_mut().push(invocation.clone());
+        Ok(true)
+    }
+
+    fn interactive(&self, invocation: &Invocation) -> io::Result<bool> {
+        self.invocations.borrow_mut().push(invocation.clone());
+        Ok(true)
+    }
+}
+
+impl KernelBuilder {
+    /// Runs a program with the configured runner and captures its output
+    pub(crate) fn run_output(&self, invocation: &Invocation) -> io::Result<Output> {
+        self.log_invocation(invocation);
+        self.runner.output(invocation)
+    }
+
+    /// Runs a program with the configured runner, passing its output line by line to `on_line`
+    pub(crate) fn run_streamed(
+        &self,
+        invocation: &Invocation,
+        on_line: &mut dyn FnMut(&str),
+    ) -> io::Result<bool> {
+        self.log_invocation(invocation);
+        self.runner.stream(invocation, on_line)
+    }
+
+    /// Runs an interactive program with the configured runner
+    pub(crate) fn run_interactive(&self, invocation: &Invocation) -> io::Result<bool> {
+        self.log_invocation(invocation);
+        self.runner.interactive(invocation)
+    }
+
+    /// Shows the commands run with `--verbose`
+    fn log_invocation(&self, invocation: &Invocation) {
+        if self.verbosity == Verbosity::Verbose {
+            self.progress.on_output_line(&format!("$ {invocation}"));
+        }
+    }
+}
+
+/// Runs with the configured runner and shows the commands with `--verbose`, so the builder can be
+/// passed to the helpers that take a runner.
+impl CommandRunner for KernelBuilder {
+    fn output(&self, invocation: &Invocation) -> io::Result<Output> {
+        self.run_output(invocation)
+    }
+
+    fn stream(&self, invocation: &Invocation, on_line: &mut dyn FnMut(&str)) -> io::Result<bool> {
+        self.run_streamed(invocation, on_line)
+    }
+
+    fn interactive(&self, invocation: &Invocation) -> io::Result<bool> {
+        self.run_interactive(invocation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_runner_shares_invocations_with_clones() {
+        let recorder = RecordingRunner::default();
+        let runner: Box<dyn CommandRunner> = Box::new(recorder.clone());
+        let make = Invocation::new("make")
+            .current_dir(Path::new("/usr/src/linux"))
+            .args(["-j", "4"]);
+
+        assert!(runner.stream(&make, &mut |_| {}).unwrap());
+        assert!(runner.output(&Invocation::new("dracut")).unwrap().success);
+        assert_eq!(recorder.invocations(), [make, Invocation::new("dracut")]);
+    }
+
+    #[test]
+    fn invocation_display() {
+        let invocation = Invocation::new("make")
+            .current_dir(Path::new("/usr/src/linux"))
+            .env("LOCALVERSION", "-lts")
+            .arg("modules_install");
+
+        assert_eq!(
+            invocation.to_string(),
+            "(cd /usr/src/linux) LOCALVERSION=-lts make modules_install"
+        );
+    }
+
+    #[test]
+    fn system_runner_streams_stdout_and_stderr() {
+        let mut lines = vec![];
+        let success = SystemRunner
+            .stream(
+                &Invocation::new("sh").args(["-c", "echo out; echo err >&2; exit 3"]),
+                &mut |line| lines.push(line.to_string()),
+            )
+            .unwrap();
+
+        lines.sort();
+        assert!(!success);
+        assert_eq!(lines, ["err", "out"]);
+    }
+}
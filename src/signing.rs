@@ -0,0 +1,163 @@
+use crate::{CommandRunner, Invocation};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Tool used to sign boot images for Secure Boot
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Signer {
+    /// `sbsign` with the configured key and certificate
+    #[default]
+    Sbsign,
+    /// `sbctl` with the keys it manages
+    Sbctl,
+}
+
+fn run(runner: &dyn CommandRunner, invocation: &Invocation, tool: &str) -> Result<(), String> {
+    let output = runner
+        .output(invocation)
+        .map_err(|e| format!("could not run {tool}: {e}"))?;
+    if !output.success {
+        return Err(format!("{tool} failed: {}", output.stderr.trim()));
+    }
+
+    Ok(())
+}
+
+/// Signs an EFI image for Secure Boot with `sbsign`, writing the signed image to `output`
+pub fn sbsign(
+    runner: &dyn CommandRunner,
+    image: &Path,
+    output: &Path,
+    key: &Path,
+    cert: &Path,
+) -> Result<(), String> {
+    run(
+        runner,
+        &Invocation::new("sbsign")
+            .arg("--key")
+            .arg(key)
+            .arg("--cert")
+            .arg(cert)
+            .arg("--output")
+            .arg(output)
+            .arg(image),
+        "sbsign",
+    )
+}
+
+/// Verifies the Secure Boot signature of an EFI image against a certificate with `sbverify`
+pub fn sbverify(runner: &dyn CommandRunner, image: &Path, cert: &Path) -> Result<(), String> {
+    run(
+        runner,
+        &Invocation::new("sbverify")
+            .arg("--cert")
+            .arg(cert)
+            .arg(image),
+        "sbverify",
+    )
+}
+
+/// Signs an EFI image with the keys managed by `sbctl`, writing the signed image to `output`
+pub fn sbctl_sign(runner: &dyn CommandRunner, image: &Path, output: &Path) -> Result<(), String> {
+    run(
+        runner,
+        &Invocation::new("sbctl")
+            .arg("sign")
+            .arg("--output")
+            .arg(output)
+            .arg(image),
+        "sbctl",
+    )
+}
+
+/// Adds an installed image to the database of `sbctl`, so `sbctl sign-all` re-signs it after
+/// key rotations.
+pub fn sbctl_register(runner: &dyn CommandRunner, image: &Path) -> Result<(), String> {
+    run(
+        runner,
+        &Invocation::new("sbctl")
+            .arg("sign")
+            .arg("--save")
+            .arg(image),
+        "sbctl",
+    )
+}
+
+/// Checks with `sbctl verify` that an image registered in its database is signed
+pub fn sbctl_verify(runner: &dyn CommandRunner, image: &Path) -> Result<(), String> {
+    let output = runner
+        .output(&Invocation::new("sbctl").arg("verify"))
+        .map_err(|e| format!("could not run sbctl: {e}"))?;
+    let report = output.stdout;
+    let image = image.to_string_lossy();
+    let signed = report
+        .lines()
+        .find(|line| line.contains(image.as_ref()))
+        .is_some_and(|line| line.contains("is signed"));
+    if !signed {
+        return Err(format!("sbctl does not report {image} as signed"));
+    }
+
+    Ok(())
+}
+
+/// Marker the kernel expects at the end of a signed module
+const MODULE_SIGNATURE_MARKER: &[u8] = b"~Module signature appended~\n";
+
+/// Kernel modules below `dir` whose path relative to it matches one of the glob patterns
+pub fn find_modules(dir: &Path, patterns: &[String]) -> Vec<PathBuf> {
+    fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                walk(&path, files);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    let mut files = vec![];
+    walk(dir, &mut files);
+    files.retain(|file| {
+        file.strip_prefix(dir).is_ok_and(|relative| {
+            let relative = relative.to_string_lossy();
+            relative.contains(".ko")
+                && patterns
+                    .iter()
+                    .any(|pattern| crate::pattern::glob_match(pattern, &relative))
+        })
+    });
+    files.sort();
+    files
+}
+
+/// Checks if a module already carries an appended signature
+pub fn module_signed(module: &Path) -> std::io::Result<bool> {
+    let content = std::fs::read(module)?;
+    Ok(content.ends_with(MODULE_SIGNATURE_MARKER))
+}
+
+/// Signs a kernel module with `scripts/sign-file` of the kernel tree
+pub fn sign_module(
+    runner: &dyn CommandRunner,
+    tree: &Path,
+    hash: &str,
+    key: &Path,
+    cert: &Path,
+    module: &Path,
+) -> Result<(), String> {
+    run(
+        runner,
+        &Invocation::new(tree.join("scripts/sign-file"))
+            .arg(hash)
+            .arg(key)
+            .arg(cert)
+            .arg(module),
+        "sign-file",
+    )
+}
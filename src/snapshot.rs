@@ -0,0 +1,134 @@
+use std::io;
+use std::path::Path;
+
+use crate::{mounts, CommandRunner, Invocation};
+
+/// Snapper configuration of the root filesystem
+const SNAPPER_ROOT_CONFIG: &str = "/etc/snapper/configs/root";
+
+/// Root filesystem is btrfs and snapper is configured for it
+pub fn snapper_available() -> bool {
+    mounts::find(Path::new("/")).is_some_and(|mount| mount.fstype == "btrfs")
+        && Path::new(SNAPPER_ROOT_CONFIG).exists()
+}
+
+/// Creates a snapper snapshot of the root filesystem and returns its number
+pub fn snapper_create(runner: &dyn CommandRunner, description: &str) -> io::Result<u32> {
+    runner
+        .run(
+            &Invocation::new("snapper")
+                .args(["--config", "root", "create", "--print-number"])
+                .args(["--cleanup-algorithm", "number"])
+                .args(["--description", description]),
+        )?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::other("snapper did not print a snapshot number"))
+}
+
+fn zfs(runner: &dyn CommandRunner, args: &[&str]) -> io::Result<String> {
+    runner.run(&Invocation::new("zfs").args(args))
+}
+
+/// Dataset mounted as root filesystem on ZFS-on-root systems, e.g. `rpool/ROOT/gentoo`
+pub fn zfs_root() -> Option<String> {
+    mounts::find(Path::new("/"))
+        .filter(|mount| mount.fstype == "zfs")
+        .map(|mount| mount.device.to_string_lossy().to_string())
+}
+
+/// Boot environment next to the root dataset, e.g. `rpool/ROOT/<name>`
+pub fn boot_environment(root: &str, name: &str) -> String {
+    match root.rsplit_once('/') {
+        Some((parent, _)) => format!("{parent}/{name}"),
+        None => format!("{root}/{name}"),
+    }
+}
+
+pub fn zfs_exists(runner: &dyn CommandRunner, dataset: &str) -> bool {
+    zfs(runner, &["list", "-H", "-o", "name", dataset]).is_ok()
+}
+
+/// Clones the root dataset into a new boot environment, which is not mounted automatically but
+/// can be booted with `root=ZFS=<dataset>`.
+pub fn create_boot_environment(
+    runner: &dyn CommandRunner,
+    root: &str,
+    dataset: &str,
+) -> io::Result<()> {
+    let name = dataset.rsplit('/').next().unwrap_or(dataset);
+    let snapshot = format!("{root}@{name}");
+    zfs(runner, &["snapshot", &snapshot])?;
+    zfs(
+        runner,
+        &[
+            "clone",
+            "-o",
+            "canmount=noauto",
+            "-o",
+            "mountpoint=/",
+            &snapshot,
+            dataset,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Destroys a boot environment and the snapshot of the root dataset it was cloned from
+pub fn destroy_boot_environment(runner: &dyn CommandRunner, dataset: &str) -> io::Result<()> {
+    let origin = zfs(runner, &["get", "-H", "-o", "value", "origin", dataset])?;
+    zfs(runner, &["destroy", dataset])?;
+    match origin.trim() {
+        "-" | "" => Ok(()),
+        snapshot => zfs(runner, &["destroy", snapshot]).map(drop),
+    }
+}
+
+/// Mounts a boot environment, which has `mountpoint=/`, at `target`
+pub fn mount_boot_environment(
+    runner: &dyn CommandRunner,
+    dataset: &str,
+    target: &Path,
+) -> io::Result<()> {
+    runner
+        .run(
+            &Invocation::new("mount")
+                .args(["-t", "zfs", "-o", "zfsutil", dataset])
+                .arg(target),
+        )
+        .map(drop)
+        .map_err(|e| io::Error::other(format!("could not mount {dataset}: {e}")))
+}
+
+pub fn unmount(runner: &dyn CommandRunner, target: &Path) -> io::Result<()> {
+    runner
+        .run(&Invocation::new("umount").arg(target))
+        .map(drop)
+        .map_err(|e| io::Error::other(format!("could not unmount {}: {e}", target.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RecordingRunner;
+
+    #[test]
+    fn clones_the_root_dataset_into_the_boot_environment() {
+        let recorder = RecordingRunner::default();
+        create_boot_environment(&recorder, "rpool/ROOT/gentoo", "rpool/ROOT/kb-6.12.8").unwrap();
+
+        let commands: Vec<String> = recorder
+            .invocations()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                "zfs snapshot rpool/ROOT/gentoo@kb-6.12.8",
+                "zfs clone -o canmount=noauto -o mountpoint=/ rpool/ROOT/gentoo@kb-6.12.8 rpool/ROOT/kb-6.12.8",
+            ]
+        );
+    }
+}
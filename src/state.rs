@@ -0,0 +1,354 @@
+use crate::{BuilderErr, KernelBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state.toml";
+
+/// Default directory of the state database and backups
+pub fn default_state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/kernel-builder")
+}
+
+/// Checksum of an installed file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactHash {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+/// Installed kernel as reported by `status`
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelStatus {
+    pub version: String,
+    pub date: String,
+    pub boot_state: &'static str,
+    pub running: bool,
+    pub last_known_good: bool,
+}
+
+/// Step of building and installing a kernel release
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildStep {
+    Build,
+    Modules,
+    Initramfs,
+    Uki,
+    Install,
+}
+
+/// How far a kernel release got, releases without record were never built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildStatus {
+    Built,
+    ModulesInstalled,
+    Installed,
+    Failed,
+}
+
+/// Outcome of the last build of a kernel release
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub version: String,
+    pub status: BuildStatus,
+    /// Step that failed, if `status` is `failed`
+    #[serde(default)]
+    pub failed_at: Option<BuildStep>,
+    /// UTC time of the last change as `YYYY-MM-DD HH:MM:SS`
+    pub time: String,
+}
+
+impl std::fmt::Display for BuildStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Build => "build",
+            Self::Modules => "modules",
+            Self::Initramfs => "initramfs",
+            Self::Uki => "uki",
+            Self::Install => "install",
+        })
+    }
+}
+
+impl std::fmt::Display for BuildRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.status, self.failed_at) {
+            (BuildStatus::Built, _) => write!(f, "built {}", self.time),
+            (BuildStatus::ModulesInstalled, _) => write!(f, "modules installed {}", self.time),
+            (BuildStatus::Installed, _) => write!(f, "installed {}", self.time),
+            (BuildStatus::Failed, Some(step)) => write!(f, "failed at {step} {}", self.time),
+            (BuildStatus::Failed, None) => write!(f, "failed {}", self.time),
+        }
+    }
+}
+
+/// Source tree as shown by `list`
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    pub path: PathBuf,
+    /// Kernel release the tree builds
+    pub release: String,
+    /// Last build of the release, `None` if it was never built
+    pub build: Option<BuildRecord>,
+}
+
+/// Output of `status`, serialized for `status --json`
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub running: Option<String>,
+    /// Release installed most recently
+    pub installed: Option<String>,
+    /// The most recently installed kernel is not the running one
+    pub reboot_required: bool,
+    pub last_known_good: Option<String>,
+    /// Source trees that appeared since the last build
+    pub new_sources: Vec<String>,
+    pub kernels: Vec<KernelStatus>,
+    pub builds: Vec<BuildRecord>,
+}
+
+/// Kernel installed by kernel-builder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallRecord {
+    pub version: String,
+    pub kernel: PathBuf,
+    pub initramfs: Option<PathBuf>,
+    #[serde(default)]
+    pub uki: Option<PathBuf>,
+    /// Date of the install as `YYYY-MM-DD`
+    pub date: String,
+    /// Checksums of all installed artifacts including copies to destinations
+    #[serde(default)]
+    pub hashes: Vec<ArtifactHash>,
+    /// Date of the first successful boot as `YYYY-MM-DD`
+    #[serde(default)]
+    pub booted: Option<String>,
+    /// Number of the snapper snapshot taken before the install
+    #[serde(default)]
+    pub snapshot: Option<u32>,
+    /// Date `{date}` rendered to in the artifact paths, which stays the same when the kernel is
+    /// reinstalled on a later day. `date` for records without it.
+    #[serde(default, rename = "path-date")]
+    pub path_date: Option<String>,
+}
+
+/// Copy of a kernel taken before it got overwritten
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupRecord {
+    pub version: String,
+    /// Path the kernel was installed to
+    pub kernel: PathBuf,
+    pub initramfs: Option<PathBuf>,
+    /// Copy of the kernel in the backup directory
+    pub kernel_backup: PathBuf,
+    pub initramfs_backup: Option<PathBuf>,
+    /// Date of the backup as `YYYY-MM-DD`
+    pub date: String,
+}
+
+/// Persistent record of installed kernels and backups, stored as TOML in the state directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub installs: Vec<InstallRecord>,
+    #[serde(default)]
+    pub backups: Vec<BackupRecord>,
+    /// Release of the kernel that booted successfully most recently
+    #[serde(default)]
+    pub last_known_good: Option<String>,
+    /// Source trees present at the last build, `None` before the first build
+    #[serde(default)]
+    pub known_sources: Option<Vec<String>>,
+    #[serde(default)]
+    pub builds: Vec<BuildRecord>,
+    /// Aliases defined with `alias`, taking precedence over the ones in the config
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl State {
+    /// Loads the state database, a missing database is empty.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        match std::fs::read_to_string(dir.join(STATE_FILE)) {
+            Ok(content) => toml::from_str(&content).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the state database, replacing the previous one atomically.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let content = toml::to_string(self).map_err(io::Error::other)?;
+        let path = dir.join(STATE_FILE);
+        let tmp = dir.join(format!(".{STATE_FILE}.tmp"));
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Most recent install to the given kernel path
+    pub fn last_install_at(&self, kernel: &Path) -> Option<&InstallRecord> {
+        self.installs
+            .iter()
+            .rev()
+            .find(|install| install.kernel == kernel)
+    }
+
+    /// Last build of a kernel release
+    pub fn build(&self, version: &str) -> Option<&BuildRecord> {
+        self.builds.iter().find(|build| build.version == version)
+    }
+
+    /// Records the outcome of a build step. Successful steps that do not advance the status, like
+    /// generating the initramfs, leave the record as is.
+    pub fn record_build(&mut self, version: &str, step: BuildStep, success: bool) {
+        let status = match (success, step) {
+            (false, _) => BuildStatus::Failed,
+            (true, BuildStep::Build) => BuildStatus::Built,
+            (true, BuildStep::Modules) => BuildStatus::ModulesInstalled,
+            (true, BuildStep::Install) => BuildStatus::Installed,
+            (true, BuildStep::Initramfs | BuildStep::Uki) => return,
+        };
+
+        self.builds.retain(|build| build.version != version);
+        self.builds.push(BuildRecord {
+            version: version.to_string(),
+            status,
+            failed_at: (!success).then_some(step),
+            time: crate::template::timestamp(),
+        });
+    }
+
+    /// Records an install, replacing earlier records of kernels at the same path as they have
+    /// been overwritten.
+    pub fn record_install(&mut self, record: InstallRecord) {
+        self.installs
+            .retain(|install| install.kernel != record.kernel);
+        self.installs.push(record);
+    }
+}
+
+impl KernelBuilder {
+    pub(crate) fn load_state(&self) -> Result<State, BuilderErr> {
+        State::load(&self.config.state_dir).map_err(BuilderErr::StateError)
+    }
+
+    pub(crate) fn save_state(&self, state: &State) -> Result<(), BuilderErr> {
+        state
+            .save(&self.config.state_dir)
+            .map_err(BuilderErr::StateError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tmp;
+
+    fn install(version: &str, kernel: &str) -> InstallRecord {
+        InstallRecord {
+            version: version.to_string(),
+            kernel: PathBuf::from(kernel),
+            initramfs: Some(PathBuf::from(format!("/boot/initramfs-{version}.img"))),
+            uki: None,
+            date: "2025-01-02".to_string(),
+            hashes: vec![ArtifactHash {
+                path: PathBuf::from(kernel),
+                sha256: "ab".repeat(32),
+            }],
+            booted: None,
+            snapshot: Some(42),
+            path_date: Some("2025-01-01".to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_state_file() {
+        let dir = tmp::TempDir::new("state-test").unwrap();
+        let mut state = State::load(dir.path()).unwrap();
+        assert!(state.installs.is_empty() && state.known_sources.is_none());
+
+        state.record_install(install("6.12.8-gentoo", "/boot/vmlinuz-6.12.8-gentoo"));
+        state.record_build("6.12.8-gentoo", BuildStep::Initramfs, false);
+        state.last_known_good = Some("6.6.30-gentoo".to_string());
+        state.known_sources = Some(vec!["linux-6.12.8-gentoo".to_string()]);
+        state
+            .aliases
+            .insert("lts".to_string(), "linux-6.6.30-gentoo".to_string());
+        state.save(dir.path()).unwrap();
+
+        let loaded = State::load(dir.path()).unwrap();
+        assert_eq!(
+            toml::to_string(&loaded).unwrap(),
+            toml::to_string(&state).unwrap()
+        );
+        let install = loaded
+            .last_install_at(Path::new("/boot/vmlinuz-6.12.8-gentoo"))
+            .unwrap();
+        assert_eq!(install.snapshot, Some(42));
+        assert_eq!(install.path_date.as_deref(), Some("2025-01-01"));
+        let build = loaded.build("6.12.8-gentoo").unwrap();
+        assert_eq!(
+            (build.status, build.failed_at),
+            (BuildStatus::Failed, Some(BuildStep::Initramfs))
+        );
+    }
+
+    #[test]
+    fn records_without_newer_fields_still_load() {
+        let dir = tmp::TempDir::new("state-test").unwrap();
+        std::fs::write(
+            dir.join(STATE_FILE),
+            "[[installs]]\n\
+             version = \"6.6.30-gentoo\"\n\
+             kernel = \"/boot/vmlinuz\"\n\
+             date = \"2024-05-01\"\n",
+        )
+        .unwrap();
+
+        let state = State::load(dir.path()).unwrap();
+
+        let install = &state.installs[0];
+        assert_eq!(install.initramfs, None);
+        assert!(install.hashes.is_empty() && install.path_date.is_none());
+    }
+
+    #[test]
+    fn record_build_keeps_the_status_on_steps_that_do_not_advance_it() {
+        let mut state = State::default();
+
+        state.record_build("6.12.8-gentoo", BuildStep::Build, true);
+        state.record_build("6.12.8-gentoo", BuildStep::Initramfs, true);
+        assert_eq!(
+            state.build("6.12.8-gentoo").unwrap().status,
+            BuildStatus::Built
+        );
+
+        state.record_build("6.12.8-gentoo", BuildStep::Modules, true);
+        assert_eq!(state.builds.len(), 1);
+        assert_eq!(
+            state.build("6.12.8-gentoo").unwrap().status,
+            BuildStatus::ModulesInstalled
+        );
+    }
+
+    #[test]
+    fn record_install_replaces_installs_at_the_same_path() {
+        let mut state = State::default();
+
+        state.record_install(install("6.6.30-gentoo", "/boot/vmlinuz"));
+        state.record_install(install("6.12.8-gentoo", "/boot/vmlinuz-6.12.8-gentoo"));
+        state.record_install(install("6.12.9-gentoo", "/boot/vmlinuz"));
+
+        let versions: Vec<&str> = state
+            .installs
+            .iter()
+            .map(|install| install.version.as_str())
+            .collect();
+        assert_eq!(versions, ["6.12.8-gentoo", "6.12.9-gentoo"]);
+    }
+}
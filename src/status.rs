@@ -0,0 +1,225 @@
+use crate::{
+    bootloader, install, running_kernel, state, template, Bootloader, BuilderErr, KernelBuilder,
+};
+
+impl KernelBuilder {
+    /// Compares the installed artifacts with the checksums recorded at install time, to detect
+    /// bit rot or files overwritten by other tools.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to read the state database
+    /// - Missing or modified artifacts
+    pub fn verify(&self) -> Result<(), BuilderErr> {
+        let state = self.load_state()?;
+        if state.installs.is_empty() {
+            println!("No installs recorded");
+            return Ok(());
+        }
+
+        let mut failed = 0;
+        for install in &state.installs {
+            println!("{} (installed {})", install.version, install.date);
+            for artifact in &install.hashes {
+                let status = match install::sha256(self, &artifact.path) {
+                    Ok(sha256) if sha256 == artifact.sha256 => "ok",
+                    Ok(_) => "modified",
+                    Err(_) if !artifact.path.exists() => "missing",
+                    Err(_) => "unreadable",
+                };
+                if status != "ok" {
+                    failed += 1;
+                }
+                println!("  {status:<10} {}", artifact.path.display());
+            }
+        }
+
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(BuilderErr::VerifyFailed(failed))
+        }
+    }
+
+    /// Records the running kernel as booted successfully. Meant to be run late during boot, e.g.
+    /// from a `local.d` script or a unit ordered after `boot-complete.target`. With systemd-boot
+    /// the boot counter is removed from the loader entry, so it is not considered bad later.
+    ///
+    /// # Errors
+    ///
+    /// - Unknown running kernel
+    /// - Failing to update the state database
+    /// - Failing to rename the loader entry
+    pub fn mark_good(&self) -> Result<(), BuilderErr> {
+        let running = running_kernel().ok_or(BuilderErr::RunningKernelUnknown)?;
+        let mut state = self.load_state()?;
+        for install in state
+            .installs
+            .iter_mut()
+            .filter(|install| install.version == running)
+        {
+            install.booted.get_or_insert_with(template::today);
+        }
+        state.last_known_good = Some(running.clone());
+        self.save_state(&state)?;
+        if let Some(machine_id) = (self.config.bootloader == Some(Bootloader::SystemdBoot))
+            .then(bootloader::machine_id)
+            .flatten()
+        {
+            let name = format!("{machine_id}-{running}");
+            if bootloader::bless_loader_entry(&self.config.loader_root, &name)
+                .map_err(|e| BuilderErr::BootloaderError(e.to_string()))?
+            {
+                println!("Removed the boot counter from loader entry {name}");
+            }
+        }
+        println!("Marked {running} as booted successfully");
+
+        Ok(())
+    }
+
+    /// Boot state of an installed kernel, either recorded by `mark-good` or taken from the boot
+    /// counter of its systemd-boot entry.
+    fn boot_state(&self, install: &state::InstallRecord) -> &'static str {
+        if install.booted.is_some() {
+            return "booted successfully";
+        }
+
+        let counted = (self.config.bootloader == Some(Bootloader::SystemdBoot))
+            .then(bootloader::machine_id)
+            .flatten()
+            .and_then(|machine_id| {
+                bootloader::boot_count(
+                    &self.config.loader_root,
+                    &format!("{machine_id}-{}", install.version),
+                )
+            });
+        match counted {
+            Some(bootloader::BootCount::Good) if self.config.boot_counting.is_some() => {
+                "booted successfully"
+            }
+            Some(bootloader::BootCount::Pending(_)) => "boot pending",
+            Some(bootloader::BootCount::Bad) => "failed to boot",
+            _ => "not booted yet",
+        }
+    }
+
+    /// Prints the installed kernels with their boot state and whether a reboot is required to
+    /// run the most recently installed kernel, as JSON for monitoring and MOTD scripts if `json`
+    /// is set.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to read the state database
+    pub fn status(&self, json: bool) -> Result<(), BuilderErr> {
+        let state = self.load_state()?;
+        let running = running_kernel();
+        let installed = state.installs.last().map(|install| install.version.clone());
+        let status = state::Status {
+            new_sources: self.new_sources(&state),
+            reboot_required: installed.is_some() && installed != running,
+            kernels: state
+                .installs
+                .iter()
+                .map(|install| state::KernelStatus {
+                    version: install.version.clone(),
+                    date: install.date.clone(),
+                    boot_state: self.boot_state(install),
+                    running: running.as_ref() == Some(&install.version),
+                    last_known_good: state.last_known_good.as_ref() == Some(&install.version),
+                })
+                .collect(),
+            builds: state.builds.clone(),
+            running,
+            installed,
+            last_known_good: state.last_known_good,
+        };
+
+        if json {
+            let output = serde_json::to_string_pretty(&status)
+                .map_err(|e| BuilderErr::StateError(e.into()))?;
+            println!("{output}");
+            return Ok(());
+        }
+
+        println!(
+            "Running kernel:  {}",
+            status.running.as_deref().unwrap_or("unknown")
+        );
+        println!(
+            "Last known good: {}",
+            status.last_known_good.as_deref().unwrap_or("none")
+        );
+        if status.reboot_required {
+            println!(
+                "Reboot required: {} is installed",
+                status.installed.as_deref().unwrap_or_default()
+            );
+        }
+        if !status.new_sources.is_empty() {
+            println!("New since last build: {}", status.new_sources.join(", "));
+        }
+
+        for kernel in &status.kernels {
+            let mut markers = vec![kernel.boot_state];
+            if kernel.running {
+                markers.push("running");
+            }
+            if kernel.last_known_good {
+                markers.push("last known good");
+            }
+            println!(
+                "{:<24} installed {}  {}",
+                kernel.version,
+                kernel.date,
+                markers.join(", ")
+            );
+        }
+        if !status.builds.is_empty() {
+            println!("Builds:");
+            for build in &status.builds {
+                println!("{:<24} {build}", build.version);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the source trees with the kernel release they build and how far its last build got.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to read the state database
+    pub fn list(&self, json: bool) -> Result<(), BuilderErr> {
+        let state = self.load_state()?;
+        let sources: Vec<state::SourceStatus> = self
+            .available_versions()
+            .iter()
+            .map(|entry| {
+                let release = self.kernel_release(&entry.path, &entry.version_string);
+                state::SourceStatus {
+                    name: entry.version_string.clone(),
+                    path: entry.path.clone(),
+                    build: state.build(&release).cloned(),
+                    release,
+                }
+            })
+            .collect();
+
+        if json {
+            let output = serde_json::to_string_pretty(&sources)
+                .map_err(|e| BuilderErr::StateError(e.into()))?;
+            println!("{output}");
+            return Ok(());
+        }
+
+        for source in &sources {
+            match &source.build {
+                Some(build) => println!("{:<32} {build}", source.name),
+                None => println!("{:<32} never built", source.name),
+            }
+        }
+
+        Ok(())
+    }
+}
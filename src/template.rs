@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Values available as placeholders in artifact paths, e.g. `/boot/vmlinuz-{version}`
+#[derive(Debug, Clone)]
+pub struct TemplateContext {
+    /// Kernel release, e.g. `6.12.8-gentoo`
+    pub version: String,
+    /// Selected flavor, `default` if none was selected
+    pub flavor: String,
+    /// Target architecture, e.g. `x86_64`
+    pub arch: String,
+    /// Current date as `YYYY-MM-DD`
+    pub date: String,
+}
+
+impl TemplateContext {
+    pub fn new(version: &str, flavor: Option<&str>) -> Self {
+        Self {
+            version: version.to_string(),
+            flavor: flavor.unwrap_or("default").to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            date: today(),
+        }
+    }
+
+    /// Replaces all placeholders in the path
+    pub fn render(&self, path: &Path) -> PathBuf {
+        let rendered = path
+            .to_string_lossy()
+            .replace("{version}", &self.version)
+            .replace("{flavor}", &self.flavor)
+            .replace("{arch}", &self.arch)
+            .replace("{date}", &self.date);
+
+        PathBuf::from(rendered)
+    }
+}
+
+/// Checks if the path contains a placeholder that depends on the kernel version
+pub fn is_versioned(path: &Path) -> bool {
+    path.to_string_lossy().contains("{version}")
+}
+
+/// Checks if the path contains the `{date}` placeholder
+pub fn is_dated(path: &Path) -> bool {
+    path.to_string_lossy().contains("{date}")
+}
+
+/// Current UTC date as `YYYY-MM-DD`
+pub fn today() -> String {
+    date(SystemTime::now())
+}
+
+/// Current UTC time as `YYYY-MM-DD HH:MM:SS`
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        today(),
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// UTC date of a point in time as `YYYY-MM-DD`
+pub fn date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / 86_400)
+        .unwrap_or_default();
+    let (year, month, day) = civil_from_days(i64::try_from(days).unwrap_or_default());
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Converts days since the unix epoch into a gregorian date, see
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = u32::try_from(doy - (153 * mp + 2) / 5 + 1).unwrap_or(1);
+    let month = u32::try_from(if mp < 10 { mp + 3 } else { mp - 9 }).unwrap_or(1);
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_all_placeholders() {
+        let context = TemplateContext {
+            version: "6.12.8-gentoo".to_string(),
+            flavor: "lts".to_string(),
+            arch: "x86_64".to_string(),
+            date: "2025-01-02".to_string(),
+        };
+
+        assert_eq!(
+            context.render(Path::new("/boot/{arch}/vmlinuz-{version}-{flavor}-{date}")),
+            Path::new("/boot/x86_64/vmlinuz-6.12.8-gentoo-lts-2025-01-02")
+        );
+        assert_eq!(
+            context.render(Path::new("/boot/vmlinuz")),
+            Path::new("/boot/vmlinuz")
+        );
+        assert!(is_versioned(Path::new("/boot/vmlinuz-{version}")));
+        assert!(!is_dated(Path::new("/boot/vmlinuz-{version}")));
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        // leap days
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_089), (2025, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+}
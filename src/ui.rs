@@ -0,0 +1,496 @@
+use crate::{
+    changelog, discover::VersionEntry, external, git, install, portage, releases, running_kernel,
+    state, version, BuilderErr, KernelBuilder, KernelVersion,
+};
+use dialoguer::console::Term;
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::{Confirm, Editor, Select};
+use indicatif::{HumanBytes, ProgressBar};
+use std::cell::RefCell;
+use std::path::Path;
+use std::time::Duration;
+
+impl KernelBuilder {
+    /// Asks for confirmation when the selected tree is an older release than the running kernel,
+    /// unattended runs do not downgrade. Returns whether to continue with the build.
+    pub(crate) fn confirm_downgrade(
+        &self,
+        version_entry: &VersionEntry,
+    ) -> Result<bool, BuilderErr> {
+        let Some(running) = running_kernel() else {
+            return Ok(true);
+        };
+        let (Some(selected), Some(current)) = (
+            KernelVersion::parse(&version_entry.version_string),
+            KernelVersion::parse(&running),
+        ) else {
+            return Ok(true);
+        };
+        if selected.cmp_release(&current).is_ge() {
+            return Ok(true);
+        }
+
+        self.confirm_or(
+            &format!("You are about to downgrade from {running} to {selected}, continue?"),
+            false,
+        )
+    }
+
+    /// Shows the changes between the newest installed kernel of the same series and the selected
+    /// tree, from the git history for git trees and from the kernel.org ChangeLogs otherwise.
+    /// Returns whether to continue with the build.
+    pub(crate) fn show_changelog(
+        &self,
+        state: &state::State,
+        version_entry: &VersionEntry,
+    ) -> Result<bool, BuilderErr> {
+        let Some(selected) = version::KernelVersion::parse(&version_entry.version_string) else {
+            return Ok(true);
+        };
+        let Some(installed) = state
+            .installs
+            .iter()
+            .filter_map(|install| version::KernelVersion::parse(&install.version))
+            .filter(|installed| installed.series() == selected.series() && !installed.is_rc())
+            .max()
+        else {
+            println!(
+                "No installed kernel of series {}, no changelog to show",
+                selected.series()
+            );
+            return Ok(true);
+        };
+
+        let series = selected.series();
+        if git::is_git_tree(&version_entry.path) {
+            let tag = match installed.patch() {
+                0 => format!("v{series}"),
+                patch => format!("v{series}.{patch}"),
+            };
+            match changelog::shortlog(self, &version_entry.path, &tag) {
+                Ok(shortlog) => println!("{shortlog}"),
+                Err(err) => self.warn(format!("no shortlog since {tag}: {err}")),
+            }
+        } else {
+            if selected.is_rc() || selected.patch() <= installed.patch() {
+                return Ok(true);
+            }
+            for patch in installed.patch() + 1..=selected.patch() {
+                let release = format!("{series}.{patch}");
+                match changelog::stable_changelog(self, &release) {
+                    Ok(log) => {
+                        println!("Changes in {release}:");
+                        for subject in changelog::subjects(&log) {
+                            println!("  {subject}");
+                        }
+                    }
+                    Err(err) => self.warn(format!("no ChangeLog for {release}: {err}")),
+                }
+            }
+        }
+
+        self.confirm_prompt(&format!("Continue with {}?", version_entry.version_string))
+    }
+
+    /// Source tree of a version given on the command line like `6.12.8`, the newest tree of that
+    /// version if there are several flavors. A missing version can be installed by emerging the
+    /// matching sys-kernel/gentoo-sources.
+    pub(crate) fn select_source(&self, version: &str) -> Result<Option<VersionEntry>, BuilderErr> {
+        let version = self
+            .aliases()
+            .remove(version)
+            .unwrap_or(version.to_string());
+        let version = version.strip_prefix("linux-").unwrap_or(&version);
+        let find = |versions: &[VersionEntry]| Self::find_source(versions, version).cloned();
+        if let Some(entry) = find(self.available_versions()) {
+            return Ok(Some(entry));
+        }
+
+        let atom = format!("=sys-kernel/gentoo-sources-{version}");
+        if !self.confirm_prompt(&format!("linux-{version} is not available, emerge {atom}?"))? {
+            return Ok(None);
+        }
+        portage::emerge(self, &["--noreplace", &atom])
+            .map_err(|e| BuilderErr::EmergeFailed(e.to_string()))?;
+
+        find(&self.discover_versions())
+            .map(Some)
+            .ok_or_else(|| BuilderErr::EmergeFailed(format!("no source tree for {version}")))
+    }
+
+    pub(crate) fn print_summary(&self, kver: &str, rebuilt: &[external::ModuleBuild]) {
+        if rebuilt.is_empty() {
+            return;
+        }
+
+        println!("Summary for {kver}:");
+        for result in rebuilt {
+            match &result.error {
+                None => println!("  {:<40} rebuilt", result.name),
+                Some(error) => println!("  {:<40} failed: {error}", result.name),
+            }
+        }
+        if rebuilt.iter().any(|result| result.error.is_some()) {
+            self.warn(format!(
+                "some modules are missing for {kver}, rebuild them before rebooting"
+            ));
+        }
+    }
+
+    #[cfg(feature = "dracut")]
+    pub(crate) fn prompt_for_installed_kernel(
+        &self,
+        preselect: Option<&str>,
+    ) -> Result<Option<String>, BuilderErr> {
+        let kernels = Self::installed_kernels();
+        if kernels.is_empty() {
+            return Err(BuilderErr::ModulesMissing("any release".into()));
+        }
+        let default = preselect
+            .or(running_kernel().as_deref())
+            .and_then(|kver| kernels.iter().position(|k| k == kver))
+            .unwrap_or(kernels.len() - 1);
+
+        Ok(self
+            .prompter
+            .select_version(
+                "Pick kernel to regenerate the initramfs for",
+                &kernels,
+                default,
+            )?
+            .map(|selection| kernels[selection].clone()))
+    }
+
+    /// Prints the size of a generated artifact and how it changed compared to the previous one.
+    pub(crate) fn report_size(
+        label: &str,
+        path: &Path,
+        previous_size: Option<u64>,
+    ) -> Result<(), BuilderErr> {
+        let size = std::fs::metadata(path)
+            .map_err(BuilderErr::KernelBuildFail)?
+            .len();
+        match previous_size {
+            Some(previous) if previous != size => {
+                let (sign, diff) = if size > previous {
+                    ('+', size - previous)
+                } else {
+                    ('-', previous - size)
+                };
+                println!(
+                    "{label} size: {} ({sign}{} compared to previous image)",
+                    HumanBytes(size),
+                    HumanBytes(diff)
+                );
+            }
+            _ => println!("{label} size: {}", HumanBytes(size)),
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn prompt_for_kernel_version(
+        &self,
+        new_sources: &[String],
+        state: &state::State,
+        releases: Option<&releases::Releases>,
+    ) -> Option<VersionEntry> {
+        let running = running_kernel();
+        let linked = self.linked_kernel();
+        self.progress.on_step_start("Inspecting source trees...");
+        let mut aliases: Vec<(String, String)> = self.aliases().into_iter().collect();
+        aliases.sort();
+        let series: Vec<String> = self
+            .available_versions()
+            .iter()
+            .map(|v| {
+                version::KernelVersion::parse(&v.version_string)
+                    .map(|kver| kver.series())
+                    .unwrap_or_default()
+            })
+            .collect();
+        let width = series.iter().map(String::len).max().unwrap_or_default();
+        let versions = self
+            .available_versions()
+            .iter()
+            .enumerate()
+            .map(|(index, v)| {
+                // `6.13-rc3` runs as `6.13.0-rc3`, so compare parsed versions
+                let kver = version::KernelVersion::parse(&v.version_string);
+                let is = |release: Option<&String>| {
+                    kver.is_some()
+                        && release.and_then(|release| version::KernelVersion::parse(release))
+                            == kver
+                };
+                // group the trees by series, only the first tree of a series shows it
+                let label = if index > 0 && series[index - 1] == series[index] {
+                    ""
+                } else {
+                    &series[index]
+                };
+                let mut item = format!("{label:<width$}  {}", v.version_string);
+                if !self.config.source_roots.is_empty() {
+                    if let Some(root) = v.path.parent() {
+                        item.push_str(&format!(" [{}]", root.display()));
+                    }
+                }
+                if git::is_git_tree(&v.path) {
+                    item.push_str(" (git)");
+                }
+                let names: Vec<&str> = aliases
+                    .iter()
+                    .filter(|(_, target)| {
+                        Self::find_source(
+                            self.available_versions(),
+                            target.strip_prefix("linux-").unwrap_or(target),
+                        ) == Some(v)
+                    })
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                if !names.is_empty() {
+                    item.push_str(&format!(" ({})", names.join(", ")));
+                }
+                if new_sources.contains(&v.version_string) {
+                    item.push_str(" (new)");
+                }
+                if is(running.as_ref()) {
+                    item.push_str(" (running)");
+                }
+                if is(linked.as_ref()) {
+                    item.push_str(" (eselected)");
+                }
+                if self
+                    .config
+                    .pin_version
+                    .as_ref()
+                    .is_some_and(|pin| version::matches(&v.version_string, pin))
+                {
+                    item.push_str(" (pinned)");
+                }
+                if let Some(annotation) =
+                    releases.and_then(|releases| releases.annotation(&v.version_string))
+                {
+                    item.push_str(&format!(" ({annotation})"));
+                }
+
+                let mut details = Vec::new();
+                if v.path.join(".config").exists() {
+                    details.push(".config".to_string());
+                }
+                if let Some(build) = state.builds.iter().find(|build| is(Some(&build.version))) {
+                    details.push(build.to_string());
+                } else if let Some(install) = state
+                    .installs
+                    .iter()
+                    .rev()
+                    .find(|install| is(Some(&install.version)))
+                {
+                    details.push(format!("installed {}", install.date));
+                }
+                if self.config.picker_sizes {
+                    details.push(HumanBytes(install::dir_size(&v.path)).to_string());
+                }
+                if !details.is_empty() {
+                    item.push_str(&format!(" - {}", details.join(", ")));
+                }
+                item
+            })
+            .collect::<Vec<_>>();
+        self.progress.on_step_end(true, "");
+
+        // versions are sorted newest first
+        self.prompter
+            .select_version("Pick version to build and install", &versions, 0)
+            .ok()
+            .flatten()
+            .map(|selection| self.available_versions()[selection].clone())
+    }
+
+    /// Asks a yes/no question whose safe answer is no. Unattended runs with `--yes` or
+    /// [`KernelBuilder::build_version`] take `unattended` as answer instead of yes.
+    pub(crate) fn confirm_or(&self, message: &str, unattended: bool) -> Result<bool, BuilderErr> {
+        if self.assume_yes.get() {
+            return Ok(unattended);
+        }
+
+        self.prompter.confirm(message)
+    }
+
+    /// Asks a yes/no question, answered with yes right away with `--yes`
+    pub(crate) fn confirm_prompt(&self, message: &str) -> Result<bool, BuilderErr> {
+        if self.assume_yes.get() {
+            return Ok(true);
+        }
+
+        self.prompter.confirm(message)
+    }
+
+    /// Shows a warning and keeps it for the report of the run
+    pub(crate) fn warn(&self, message: impl Into<String>) {
+        let message = message.into();
+        eprintln!("Warning: {message}");
+        self.warnings.borrow_mut().push(message);
+    }
+}
+
+/// Asks the user to pick from a list, answer yes/no questions and edit text. The builder uses
+/// the terminal by default, embedders without a TTY like daemons, tests or GUIs plug in their
+/// own implementation or [`NonInteractive`].
+pub trait Prompter: std::fmt::Debug {
+    /// Picks one of `items`, preselecting `default`. `None` if the user cancelled.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to interact with the user
+    fn select_version(
+        &self,
+        prompt: &str,
+        items: &[String],
+        default: usize,
+    ) -> Result<Option<usize>, BuilderErr>;
+
+    /// Asks a yes/no question.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to interact with the user
+    fn confirm(&self, prompt: &str) -> Result<bool, BuilderErr>;
+
+    /// Lets the user edit `text`. `None` if it was left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// - Failing to interact with the user
+    fn edit(&self, text: &str) -> Result<Option<String>, BuilderErr>;
+}
+
+/// Prompts on the terminal with dialoguer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Interactive;
+
+impl Prompter for Interactive {
+    fn select_version(
+        &self,
+        prompt: &str,
+        items: &[String],
+        default: usize,
+    ) -> Result<Option<usize>, BuilderErr> {
+        Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .items(items)
+            .default(default)
+            .interact_on_opt(&Term::stderr())
+            .map_err(BuilderErr::PromptError)
+    }
+
+    fn confirm(&self, prompt: &str) -> Result<bool, BuilderErr> {
+        Confirm::new()
+            .with_prompt(prompt)
+            .interact()
+            .map_err(BuilderErr::PromptError)
+    }
+
+    fn edit(&self, text: &str) -> Result<Option<String>, BuilderErr> {
+        Editor::new().edit(text).map_err(BuilderErr::PromptError)
+    }
+}
+
+/// Answers without a terminal: every selection takes the preselected item, every question gets
+/// the same answer and texts stay unchanged
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NonInteractive {
+    /// Answer to all yes/no questions
+    pub confirm: bool,
+}
+
+impl Prompter for NonInteractive {
+    fn select_version(
+        &self,
+        _prompt: &str,
+        items: &[String],
+        default: usize,
+    ) -> Result<Option<usize>, BuilderErr> {
+        Ok((default < items.len()).then_some(default))
+    }
+
+    fn confirm(&self, _prompt: &str) -> Result<bool, BuilderErr> {
+        Ok(self.confirm)
+    }
+
+    fn edit(&self, _text: &str) -> Result<Option<String>, BuilderErr> {
+        Ok(None)
+    }
+}
+
+/// Receives the progress of long running steps like compiling the kernel or generating the
+/// initramfs. Steps do not nest, a step ends before the next one starts. The builder prints its
+/// other messages itself, so an implementation does not capture everything shown on the terminal.
+pub trait Progress: std::fmt::Debug {
+    /// A step starts, described by `message`
+    fn on_step_start(&self, message: &str);
+
+    /// The running step got further, e.g. to the next file compiled
+    fn on_progress(&self, message: &str);
+
+    /// The running step finished or failed. An empty `message` means there is nothing left to
+    /// show about it.
+    fn on_step_end(&self, ok: bool, message: &str);
+
+    /// Output of an external tool worth showing as is, like warnings of dracut
+    fn on_output_line(&self, line: &str);
+}
+
+/// Shows no progress, for library use. Status messages of the builder are printed regardless.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Progress for Silent {
+    fn on_step_start(&self, _message: &str) {}
+
+    fn on_progress(&self, _message: &str) {}
+
+    fn on_step_end(&self, _ok: bool, _message: &str) {}
+
+    fn on_output_line(&self, _line: &str) {}
+}
+
+/// Spinner on the terminal showing the running step and its latest progress
+#[derive(Debug, Default)]
+pub struct Spinner {
+    bar: RefCell<Option<ProgressBar>>,
+}
+
+impl Progress for Spinner {
+    fn on_step_start(&self, message: &str) {
+        let bar = ProgressBar::new_spinner();
+        bar.enable_steady_tick(Duration::from_millis(120));
+        bar.set_message(message.to_string());
+        if let Some(previous) = self.bar.replace(Some(bar)) {
+            previous.finish_and_clear();
+        }
+    }
+
+    fn on_progress(&self, message: &str) {
+        if let Some(bar) = self.bar.borrow().as_ref() {
+            bar.set_message(message.to_string());
+        }
+    }
+
+    fn on_step_end(&self, ok: bool, message: &str) {
+        let Some(bar) = self.bar.take() else {
+            return;
+        };
+        match (ok, message.is_empty()) {
+            (_, true) => bar.finish_and_clear(),
+            (true, false) => bar.finish_with_message(message.to_string()),
+            (false, false) => bar.abandon_with_message(message.to_string()),
+        }
+    }
+
+    fn on_output_line(&self, line: &str) {
+        match self.bar.borrow().as_ref() {
+            Some(bar) => bar.println(line),
+            None => eprintln!("{line}"),
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// Version of a kernel source tree parsed from its directory name like `linux-6.12.8-gentoo-r1`
+/// or `linux-6.13-rc3`, or from a kernel release like `6.13.0-rc3`. Versions are ordered by
+/// release, release candidates before the release, then by revision and local version.
+#[derive(Debug, Clone)]
+pub struct KernelVersion {
+    major: u32,
+    minor: u32,
+    patch: u32,
+    /// Whether the patch level was written out like in `6.13.0-rc3`, only kept for display
+    explicit_patch: bool,
+    /// Release candidate, `-rcN`
+    rc: Option<u32>,
+    /// Gentoo revision of the sources package, `-rN`
+    revision: u32,
+    /// Local version like `gentoo`, empty for vanilla trees
+    local: String,
+}
+
+impl KernelVersion {
+    /// Parses a tree name with or without the `linux-` prefix, `None` if it does not start with a
+    /// `major.minor` version.
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.strip_prefix("linux-").unwrap_or(name);
+        let (numbers, rest) = name.split_once('-').unwrap_or((name, ""));
+        let mut numbers = numbers.split('.').map(str::parse::<u32>);
+        let major = numbers.next()?.ok()?;
+        let minor = numbers.next()?.ok()?;
+        let patch = numbers.next().transpose().ok()?;
+
+        let mut local: Vec<&str> = rest.split('-').filter(|part| !part.is_empty()).collect();
+        let rc = local
+            .iter()
+            .position(|part| {
+                part.strip_prefix("rc")
+                    .is_some_and(|n| n.parse::<u32>().is_ok())
+            })
+            .map(|index| local.remove(index)[2..].parse().unwrap_or_default());
+        let revision = local
+            .last()
+            .and_then(|part| part.strip_prefix('r'))
+            .and_then(|revision| revision.parse().ok());
+        if revision.is_some() {
+            local.pop();
+        }
+
+        Some(Self {
+            major,
+            minor,
+            patch: patch.unwrap_or_default(),
+            explicit_patch: patch.is_some(),
+            rc,
+            revision: revision.unwrap_or_default(),
+            local: local.join("-"),
+        })
+    }
+
+    #[must_use]
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    #[must_use]
+    pub fn minor(&self) -> u32 {
+        self.minor
+    }
+
+    /// Series of the version as `major.minor`, e.g. `6.6`
+    #[must_use]
+    pub fn series(&self) -> String {
+        format!("{}.{}", self.major, self.minor)
+    }
+
+    /// Stable release within the series, `0` for the initial release
+    #[must_use]
+    pub fn patch(&self) -> u32 {
+        self.patch
+    }
+
+    /// Number of the release candidate, `None` for releases
+    #[must_use]
+    pub fn rc(&self) -> Option<u32> {
+        self.rc
+    }
+
+    #[must_use]
+    pub fn is_rc(&self) -> bool {
+        self.rc.is_some()
+    }
+
+    /// Compares only the upstream release, ignoring revision and local version, e.g. to compare
+    /// a tree with the running kernel
+    #[must_use]
+    pub fn cmp_release(&self, other: &Self) -> Ordering {
+        // release candidates come before the release
+        let release = |version: &Self| {
+            (
+                version.major,
+                version.minor,
+                version.patch,
+                version.rc.is_none(),
+                version.rc.unwrap_or_default(),
+            )
+        };
+        release(self).cmp(&release(other))
+    }
+
+    /// Gentoo revision of the sources package, `0` without revision
+    #[must_use]
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    /// Local version like `gentoo`, empty for vanilla trees
+    #[must_use]
+    pub fn local(&self) -> &str {
+        &self.local
+    }
+}
+
+/// Formats the version as it was parsed without `linux-` prefix, e.g. `6.12.8-gentoo-r1`,
+/// `6.13-rc3` or the kernel release `6.13.0-rc3`
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch > 0 || self.explicit_patch {
+            write!(f, ".{}", self.patch)?;
+        }
+        if let Some(rc) = self.rc {
+            write!(f, "-rc{rc}")?;
+        }
+        if !self.local.is_empty() {
+            write!(f, "-{}", self.local)?;
+        }
+        if self.revision > 0 {
+            write!(f, "-r{}", self.revision)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks if a tree name belongs to a version or series like `6.6`, `6.6.30` or
+/// `6.6.30-gentoo-r1`, with or without the `linux-` prefix
+pub fn matches(name: &str, spec: &str) -> bool {
+    let name = name.strip_prefix("linux-").unwrap_or(name);
+    let spec = spec.strip_prefix("linux-").unwrap_or(spec);
+
+    name.strip_prefix(spec)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '-']))
+}
+
+impl Ord for KernelVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_release(other)
+            .then_with(|| (self.revision, &self.local).cmp(&(other.revision, &other.local)))
+    }
+}
+
+/// `6.12` and `6.12.0` are the same version
+impl PartialEq for KernelVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for KernelVersion {}
+
+impl Hash for KernelVersion {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (
+            self.major,
+            self.minor,
+            self.patch,
+            self.rc,
+            self.revision,
+            &self.local,
+        )
+            .hash(state);
+    }
+}
+
+impl PartialOrd for KernelVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Release of the currently running kernel, same as `uname -r`
+#[must_use]
+pub fn running_kernel() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(name: &str) -> KernelVersion {
+        KernelVersion::parse(name).unwrap()
+    }
+
+    #[test]
+    fn parses_tree_names_and_releases() {
+        let tree = version("linux-6.12.8-gentoo-r1");
+        assert_eq!((tree.major(), tree.minor(), tree.patch()), (6, 12, 8));
+        assert_eq!(tree.revision(), 1);
+        assert_eq!(tree.local(), "gentoo");
+        assert_eq!(tree.rc(), None);
+
+        let rc = version("6.13.0-rc3");
+        assert_eq!(
+            (rc.series().as_str(), rc.patch(), rc.rc()),
+            ("6.13", 0, Some(3))
+        );
+        assert_eq!(rc.local(), "");
+
+        assert_eq!(version("linux-6.6").patch(), 0);
+        assert!(KernelVersion::parse("linux-next").is_none());
+        assert!(KernelVersion::parse("6").is_none());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for name in [
+            "6.13.0-rc3",
+            "6.13-rc3",
+            "6.12.0",
+            "6.12",
+            "6.12.8-gentoo-r1",
+            "6.6.30-zen",
+        ] {
+            assert_eq!(version(name).to_string(), name);
+        }
+        assert_eq!(version("linux-6.1.90-gentoo").to_string(), "6.1.90-gentoo");
+    }
+
+    #[test]
+    fn orders_release_candidates_before_releases() {
+        let mut versions = [
+            "6.13-rc3",
+            "6.12.8-gentoo-r1",
+            "6.13",
+            "6.12.8-gentoo",
+            "6.13-rc10",
+            "6.9.12",
+        ]
+        .map(version);
+        versions.sort();
+
+        assert_eq!(
+            versions.map(|version| version.to_string()),
+            [
+                "6.9.12",
+                "6.12.8-gentoo",
+                "6.12.8-gentoo-r1",
+                "6.13-rc3",
+                "6.13-rc10",
+                "6.13"
+            ]
+        );
+        assert_eq!(version("6.12"), version("6.12.0"));
+        assert_eq!(
+            version("6.12.8-gentoo").cmp_release(&version("6.12.8")),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn matches_versions_and_series() {
+        assert!(matches("linux-6.6.30-gentoo", "6.6"));
+        assert!(matches("linux-6.6.30-gentoo", "6.6.30"));
+        assert!(matches("6.6.30-gentoo-r1", "linux-6.6.30-gentoo"));
+        assert!(matches("linux-6.6", "6.6"));
+
+        assert!(!matches("linux-6.6.30-gentoo", "6.6.3"));
+        assert!(!matches("linux-6.12.8-gentoo", "6.1"));
+        assert!(!matches("linux-6.6.30-gentoo", "6.12"));
+    }
+}